@@ -0,0 +1,133 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use color_eyre::eyre::{Result, WrapErr as _, bail, eyre};
+
+use crate::{
+    git_remote::PushSpec,
+    github::PullRequestState,
+    message::MessageSection,
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct AdoptOptions {
+    /// Pull Request number or URL
+    pull_request: String,
+}
+
+pub async fn adopt(
+    opts: AdoptOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let number = config
+        .parse_pull_request_field(&opts.pull_request)
+        .ok_or_else(|| {
+            eyre!(
+                "'{}' is neither the number nor the URL of a Pull Request in \
+                 {}/{}",
+                opts.pull_request,
+                config.owner,
+                config.repo
+            )
+        })?;
+
+    let mut prepared_commits = gh.get_prepared_commits()?;
+
+    let len = prepared_commits.len();
+    if len == 0 {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    }
+    let slice = &mut prepared_commits[len - 1..];
+    let commit = &mut slice[0];
+
+    write_commit_title(commit)?;
+
+    match commit.pull_request_number {
+        Some(existing) if existing == number => {
+            output("✅", "This commit already belongs to this Pull Request")?;
+            return Ok(());
+        }
+        Some(existing) => bail!(
+            "This commit already belongs to Pull Request #{}. Remove the \
+             'Pull Request' section from the commit message first.",
+            existing
+        ),
+        None => (),
+    }
+
+    let pull_request = gh.clone().get_pull_request(number).await?;
+
+    if pull_request.state != PullRequestState::Open {
+        bail!("Pull Request #{} is not open", number);
+    }
+    if pull_request.head_repository.is_fork() {
+        bail!(
+            "Pull Request #{} comes from a fork, which spr cannot push to",
+            number
+        );
+    }
+    if pull_request.head.is_master_branch() {
+        bail!(
+            "Pull Request #{} is from the {} branch itself",
+            number,
+            config.master_ref.branch_name()
+        );
+    }
+    // spr treats a base branch other than master as one it created for the
+    // Pull Request, and would push to it
+    if !pull_request.base.is_master_branch()
+        && !pull_request
+            .base
+            .branch_name()
+            .starts_with(&config.branch_prefix)
+    {
+        bail!(
+            "Pull Request #{} targets {} - spr can only update Pull Requests \
+             that target {}. Change the Pull Request's base branch first.",
+            number,
+            pull_request.base.branch_name(),
+            config.master_ref.branch_name()
+        );
+    }
+
+    // Pushing the current head of the Pull Request branch back is a no-op
+    // that tells us whether we will be able to push updates to it
+    gh.remote()
+        .push_to_remote(&[PushSpec {
+            oid: Some(pull_request.head_oid),
+            remote_ref: pull_request.head.on_github(),
+        }])
+        .wrap_err_with(|| {
+            format!(
+                "Cannot push to {}, the branch of Pull Request #{}",
+                pull_request.head.branch_name(),
+                number
+            )
+        })?;
+
+    commit
+        .message
+        .insert(MessageSection::PullRequest, config.pull_request_url(number));
+    commit.pull_request_number = Some(number);
+
+    output(
+        "🔗",
+        &format!(
+            "Linked to Pull Request #{}: {}",
+            number,
+            config.pull_request_url(number)
+        ),
+    )?;
+
+    git.rewrite_commit_messages(config, slice, None)?;
+
+    Ok(())
+}
@@ -9,7 +9,7 @@ use color_eyre::eyre::{Result, eyre};
 
 use crate::{
     git::PreparedCommit,
-    message::validate_commit_message,
+    message::{keep_local_sections, validate_commit_message},
     output::{output, write_commit_title},
 };
 
@@ -60,13 +60,23 @@ pub async fn amend(
         write_commit_title(commit)?;
         let pull_request = pull_requests.pop().flatten();
         if let Some(pull_request) = pull_request {
-            let pull_request = pull_request.await??;
+            let mut pull_request = pull_request.await??;
+            keep_local_sections(
+                &mut pull_request.sections,
+                &commit.message,
+                &config.custom_sections,
+            );
             commit.message = pull_request.sections;
+            git.set_synced_sections(
+                config,
+                pull_request.number,
+                &commit.message,
+            )?;
         }
         failure = validate_commit_message(&commit.message, config).is_err()
             || failure;
     }
-    git.rewrite_commit_messages(slice, None)?;
+    git.rewrite_commit_messages(config, slice, None)?;
 
     if failure {
         Err(eyre!("amend failed"))
@@ -5,14 +5,19 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::HashSet;
+
 use color_eyre::eyre::{Result, bail};
+use git2::Oid;
 
 use crate::{
     git::PreparedCommit,
     git_remote::PushSpec,
-    github::{PullRequestState, PullRequestUpdate},
+    github::{
+        OpenPullRequest, PullRequest, PullRequestState, PullRequestUpdate,
+    },
     message::MessageSection,
-    output::{output, write_commit_title},
+    output::{confirm, output, write_commit_title},
 };
 
 #[derive(Debug, clap::Parser)]
@@ -26,7 +31,7 @@ pub async fn close(
     opts: CloseOptions,
     git: &crate::git::Git,
     gh: &mut crate::github::GitHub,
-    _config: &crate::config::Config,
+    config: &crate::config::Config,
 ) -> Result<()> {
     let mut result = Ok(());
 
@@ -54,17 +59,18 @@ pub async fn close(
         // This makes it easier to run the code to update the local commit message
         // with all the changes that the implementation makes at the end, even if
         // the implementation encounters an error or exits early.
-        result = close_impl(gh, prepared_commit).await;
+        result = close_impl(git, gh, prepared_commit).await;
     }
 
     // This updates the commit message in the local Git repository (if it was
     // changed by the implementation)
-    git.rewrite_commit_messages(prepared_commits.as_mut_slice(), None)?;
+    git.rewrite_commit_messages(config, prepared_commits.as_mut_slice(), None)?;
 
     result
 }
 
 async fn close_impl(
+    git: &crate::git::Git,
     gh: &mut crate::github::GitHub,
     prepared_commit: &mut PreparedCommit,
 ) -> Result<()> {
@@ -85,11 +91,23 @@ async fn close_impl(
 
     output("📖", "Getting started...")?;
 
-    let base_is_master = pull_request.base.is_master_branch();
+    close_pull_request(git, gh, &pull_request).await?;
+
+    // Remove sections from commit that are not relevant after closing.
+    prepared_commit.message.remove(&MessageSection::PullRequest);
+    prepared_commit.message.remove(&MessageSection::ReviewedBy);
+
+    delete_pull_request_branches(gh, &pull_request)
+}
 
+async fn close_pull_request(
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    pull_request: &PullRequest,
+) -> Result<()> {
     let result = gh
         .update_pull_request(
-            pull_request_number,
+            pull_request.number,
             PullRequestUpdate {
                 state: Some(PullRequestState::Closed),
                 ..Default::default()
@@ -107,24 +125,114 @@ async fn close_impl(
     };
 
     output("📕", "Closed!")?;
+    git.remove_synced_sections(pull_request.number)?;
 
-    // Remove sections from commit that are not relevant after closing.
-    prepared_commit.message.remove(&MessageSection::PullRequest);
-    prepared_commit.message.remove(&MessageSection::ReviewedBy);
+    Ok(())
+}
 
-    let mut push_specs = vec![PushSpec {
-        oid: None,
-        remote_ref: pull_request.head.on_github(),
-    }];
+/// Delete the branch and (if there is one) the base branch of a closed Pull
+/// Request on GitHub
+fn delete_pull_request_branches(
+    gh: &crate::github::GitHub,
+    pull_request: &PullRequest,
+) -> Result<()> {
+    let mut push_specs = Vec::new();
 
-    if !base_is_master {
+    // The head branch of a Pull Request from a fork is not ours to delete
+    if !pull_request.head_repository.is_fork() {
+        push_specs.push(PushSpec {
+            oid: None,
+            remote_ref: pull_request.head.on_github(),
+        });
+    }
+
+    if !pull_request.base.is_master_branch() {
         push_specs.push(PushSpec {
             oid: None,
             remote_ref: pull_request.base.on_github(),
         });
     }
 
-    gh.remote().push_to_remote(&push_specs)?;
+    if !push_specs.is_empty() {
+        gh.remote().push_to_remote(&push_specs)?;
+    }
+
+    Ok(())
+}
+
+/// Look for open Pull Requests by the current user on branches under the
+/// branch prefix that no local commit refers to (anymore), e.g. because their
+/// commit was dropped in an interactive rebase, and offer to close them. The
+/// given commits are the ones of the current branch, but commits on other
+/// local branches are taken into account, too.
+pub async fn close_orphaned_pull_requests(
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+    prepared_commits: &[PreparedCommit],
+    master_oid: Oid,
+    open_pull_requests: &[OpenPullRequest],
+    close_by_default: bool,
+) -> Result<()> {
+    let referenced: HashSet<u64> = prepared_commits
+        .iter()
+        .filter_map(|commit| commit.pull_request_number)
+        .chain(git.get_local_pull_request_numbers(config, master_oid)?)
+        .collect();
+
+    let candidates: Vec<_> = open_pull_requests
+        .iter()
+        .filter(|pr| {
+            pr.head_ref_name.starts_with(&config.branch_prefix)
+                && !referenced.contains(&pr.number)
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    // Others may use the same branch prefix, and their Pull Requests are none
+    // of our business
+    let viewer = gh.get_viewer_login().await?;
+    let orphans: Vec<_> = candidates
+        .into_iter()
+        .filter(|pr| pr.author == viewer)
+        .collect();
+    if orphans.is_empty() {
+        return Ok(());
+    }
+
+    for orphan in &orphans {
+        output(
+            "👻",
+            &format!(
+                "No local commit refers to Pull Request #{}: {}",
+                orphan.number, orphan.title
+            ),
+        )?;
+    }
+
+    if !confirm(
+        format!(
+            "Close {} and delete their branches?",
+            if orphans.len() == 1 {
+                "this Pull Request".to_string()
+            } else {
+                format!("these {} Pull Requests", orphans.len())
+            }
+        ),
+        close_by_default,
+    )
+    .await?
+    {
+        return Ok(());
+    }
+
+    for orphan in orphans {
+        let pull_request = gh.clone().get_pull_request(orphan.number).await?;
+        close_pull_request(git, gh, &pull_request).await?;
+        delete_pull_request_branches(gh, &pull_request)?;
+    }
 
     Ok(())
 }
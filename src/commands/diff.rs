@@ -5,26 +5,33 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::zip;
 
 use color_eyre::eyre::{Error, Result, WrapErr as _, bail, eyre};
 
 use crate::{
+    commands::{
+        close::close_orphaned_pull_requests, restore_stashed_changes,
+        stash_uncommitted_changes,
+    },
     git::PreparedCommit,
     git_remote::PushSpec,
     github::{
-        GitHub, PullRequest, PullRequestRequestReviewers, PullRequestState,
-        PullRequestUpdate,
+        GitHub, OpenPullRequest, PullRequest, PullRequestRequestReviewers,
+        PullRequestState, PullRequestUpdate,
+    },
+    message::{
+        MessageSection, MetadataFormat, change_id_from_github_body,
+        set_change_id_marker, validate_commit_message,
     },
-    message::{MessageSection, validate_commit_message},
-    output::{output, write_commit_title},
+    output::{confirm, output, write_commit_title},
     utils::{parse_name_list, remove_all_parens, slugify},
 };
 use git2::Oid;
 use indoc::{formatdoc, indoc};
 
-#[derive(Debug, clap::Parser)]
+#[derive(Debug, Default, clap::Parser)]
 pub struct DiffOptions {
     /// Create/update pull requests for the whole branch, not just the HEAD commit
     #[clap(long, short = 'a')]
@@ -53,6 +60,36 @@ pub struct DiffOptions {
     /// on any intermediate changes between the master branch and this commit.
     #[clap(long)]
     cherry_pick: bool,
+
+    /// With --all, close your Pull Requests on branches under the branch
+    /// prefix that no local commit refers to. You are still asked to confirm
+    /// when running interactively, but closing them becomes the default.
+    #[clap(long, requires = "all")]
+    close_orphans: bool,
+
+    /// Link commits without a Pull Request to an open Pull Request with the
+    /// same content on the branch spr would have created for them, without
+    /// asking
+    #[clap(long)]
+    relink: bool,
+
+    /// Stash uncommitted changes before, and re-apply them after (default
+    /// taken from git config spr.autoStash)
+    #[clap(long)]
+    autostash: bool,
+}
+
+impl DiffOptions {
+    /// Options for updating the Pull Requests of all commits in the branch,
+    /// using the given message for the new commits on the Pull Request
+    /// branches
+    pub fn update_all(message: String) -> Self {
+        Self {
+            all: true,
+            message: Some(message),
+            ..Default::default()
+        }
+    }
 }
 
 fn get_oids(refs: &str, repo: &git2::Repository) -> Result<HashSet<Oid>> {
@@ -84,9 +121,22 @@ pub async fn diff(
     gh: &mut crate::github::GitHub,
     config: &crate::config::Config,
 ) -> Result<()> {
-    // Abort right here if the local Git repository is not clean
-    git.check_no_uncommitted_changes()?;
+    // Abort right here if the local Git repository is not clean, unless we
+    // are to stash the changes
+    let stash =
+        stash_uncommitted_changes(git, opts.autostash || config.auto_stash)?;
+    let result = diff_commits(opts, git, gh, config).await;
+
+    // An error restoring the changes must not hide the one that stopped us
+    result.and(restore_stashed_changes(git, stash))
+}
 
+async fn diff_commits(
+    opts: DiffOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
     let mut result = Ok(());
 
     // Look up the commits on the local branch
@@ -123,6 +173,40 @@ pub async fn diff(
         }
     };
 
+    // Finding the Pull Requests of commits that do not refer to one, and
+    // those of no commit at all, takes a look at all open Pull Requests
+    let open_pull_requests = if config.change_id
+        || opts.all
+        || prepared_commits
+            .iter()
+            .any(|pc| pc.pull_request_number.is_none())
+    {
+        gh.get_open_pull_requests().await?
+    } else {
+        Vec::new()
+    };
+
+    if config.change_id {
+        check_change_ids(config, &mut prepared_commits, &open_pull_requests)?;
+    }
+
+    relink_pull_requests(
+        git,
+        gh,
+        config,
+        &open_pull_requests,
+        prepared_commits.iter_mut().filter(|pc| {
+            pc.pull_request_number.is_none()
+                && revs_to_pr
+                    .as_ref()
+                    .map(|revs| revs.contains(&pc.oid))
+                    .unwrap_or(true)
+        }),
+        master_base_oid,
+        opts.relink,
+    )
+    .await?;
+
     #[allow(clippy::needless_collect)]
     let pull_request_tasks: Vec<_> = prepared_commits
         .iter()
@@ -150,7 +234,7 @@ pub async fn diff(
     let mut message_on_prompt = "".to_string();
 
     for (prepared_commit, pull_request_task) in
-        zip(prepared_commits.iter_mut(), pull_request_tasks.into_iter())
+        zip(prepared_commits.iter_mut(), pull_request_tasks)
     {
         if result.is_err() {
             break;
@@ -193,12 +277,282 @@ pub async fn diff(
     }
 
     // This updates the commit message in the local Git repository (if it was
-    // changed by the implementation)
-    git.rewrite_commit_messages(prepared_commits.as_mut_slice(), None)?;
+    // changed by the implementation). The implementation only changes
+    // metadata, so if that is kept in notes, the commits stay as they are.
+    if config.metadata_format == MetadataFormat::Notes {
+        for prepared_commit in prepared_commits.iter() {
+            git.update_metadata_note(
+                config,
+                prepared_commit.oid,
+                &prepared_commit.message,
+            )?;
+        }
+    } else {
+        git.rewrite_commit_messages(
+            config,
+            prepared_commits.as_mut_slice(),
+            None,
+        )?;
+    }
+
+    // Having looked at the whole branch, we can tell which Pull Requests have
+    // lost their commits
+    if opts.all && result.is_ok() {
+        close_orphaned_pull_requests(
+            git,
+            gh,
+            config,
+            &prepared_commits,
+            master_base_oid,
+            &open_pull_requests,
+            opts.close_orphans,
+        )
+        .await?;
+    }
 
     result
 }
 
+/// Make use of the Change-Ids of the commits: find the Pull Requests of
+/// commits that lost their Pull Request section, and point out duplicate Pull
+/// Requests and ones of commits that were squashed into others.
+fn check_change_ids(
+    config: &crate::config::Config,
+    prepared_commits: &mut [PreparedCommit],
+    open_pull_requests: &[OpenPullRequest],
+) -> Result<()> {
+    // A commit that was squashed together with others has several Change-Ids.
+    // We keep the first one, and the others' Pull Requests are now obsolete.
+    let mut squashed = Vec::<(String, String)>::new();
+    let mut seen = HashMap::<String, String>::new();
+
+    for commit in prepared_commits.iter_mut() {
+        let Some(text) = commit.message.get(&MessageSection::ChangeId) else {
+            continue;
+        };
+        let mut change_ids = text.split_whitespace().map(String::from);
+        let Some(change_id) = change_ids.next() else {
+            commit.message.remove(&MessageSection::ChangeId);
+            continue;
+        };
+        squashed.extend(change_ids.map(|id| (commit.short_id.clone(), id)));
+
+        if let Some(other) =
+            seen.insert(change_id.clone(), commit.short_id.clone())
+        {
+            bail!(
+                "Commits {} and {} have the same Change-Id {}. Remove it from \
+                 one of them, and spr diff will give that one a new one.",
+                other,
+                commit.short_id,
+                change_id
+            );
+        }
+        commit.message.insert(MessageSection::ChangeId, change_id);
+    }
+
+    // The open Pull Requests by their Change-Ids
+    let mut pull_requests = BTreeMap::<String, Vec<u64>>::new();
+    for pr in open_pull_requests {
+        if let Some(change_id) = change_id_from_github_body(&pr.body) {
+            pull_requests.entry(change_id).or_default().push(pr.number);
+        }
+    }
+
+    for commit in prepared_commits.iter_mut() {
+        let Some(change_id) = commit.message.get(&MessageSection::ChangeId)
+        else {
+            continue;
+        };
+        let numbers: Vec<u64> =
+            pull_requests.get(change_id).cloned().unwrap_or_default();
+
+        if commit.pull_request_number.is_none()
+            && let Some(&number) = numbers.first()
+        {
+            output(
+                "🔗",
+                &format!(
+                    "Commit {} belongs to Pull Request #{}, which has the \
+                     same Change-Id",
+                    commit.short_id, number
+                ),
+            )?;
+            commit.pull_request_number = Some(number);
+            commit.message.insert(
+                MessageSection::PullRequest,
+                config.pull_request_url(number),
+            );
+        }
+
+        for number in numbers
+            .into_iter()
+            .filter(|&number| Some(number) != commit.pull_request_number)
+        {
+            output(
+                "⚠️",
+                &format!(
+                    "Pull Request #{} has the same Change-Id as commit {}, so \
+                     it is probably a duplicate and can be closed",
+                    number, commit.short_id
+                ),
+            )?;
+        }
+    }
+
+    for (short_id, change_id) in &squashed {
+        for number in pull_requests.get(change_id).into_iter().flatten() {
+            output(
+                "⚠️",
+                &format!(
+                    "Commit {} was squashed together with the commit of Pull \
+                     Request #{}, which can probably be closed",
+                    short_id, number
+                ),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the Pull Requests of commits that do not refer to one. That happens
+/// when spr created a Pull Request but did not get to write its number into
+/// the commit message. Such a Pull Request is open, its branch has the name
+/// spr gives the branch of the commit, no local commit refers to it, and its
+/// head has the same tree as the commit.
+async fn relink_pull_requests<'a>(
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+    open_pull_requests: &[OpenPullRequest],
+    commits: impl Iterator<Item = &'a mut PreparedCommit>,
+    master_base_oid: Oid,
+    without_asking: bool,
+) -> Result<()> {
+    let commits: Vec<_> = commits
+        .map(|commit| {
+            let title = commit
+                .message
+                .get(&MessageSection::Title)
+                .map(|t| &t[..])
+                .unwrap_or("");
+            (slugify(title), commit)
+        })
+        .collect();
+    if commits.is_empty() {
+        return Ok(());
+    }
+
+    let referenced =
+        git.get_local_pull_request_numbers(config, master_base_oid)?;
+    let candidates: Vec<_> = open_pull_requests
+        .iter()
+        .filter(|pr| {
+            !referenced.contains(&pr.number)
+                && commits.iter().any(|(slug, _)| {
+                    is_generated_branch_name(
+                        &pr.head_ref_name,
+                        &config.branch_prefix,
+                        slug,
+                    )
+                })
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let branch_names: Vec<&str> = candidates
+        .iter()
+        .map(|pr| pr.head_ref_name.as_str())
+        .collect();
+    let heads = gh.remote().fetch_from_remote(&branch_names, &[])?;
+    let mut candidates: Vec<_> = zip(candidates, heads)
+        .filter_map(|(pr, head)| Some((pr, head?)))
+        .map(|(pr, head)| Ok((git.get_tree_oid_for_commit(head)?, pr)))
+        .collect::<Result<_>>()?;
+
+    for (slug, commit) in commits {
+        let tree = commit.tree_oid;
+        let Some(index) = candidates.iter().position(|(t, pr)| {
+            *t == tree
+                && is_generated_branch_name(
+                    &pr.head_ref_name,
+                    &config.branch_prefix,
+                    &slug,
+                )
+        }) else {
+            continue;
+        };
+        let (_, pr) = &candidates[index];
+
+        output(
+            "🔍",
+            &format!(
+                "Commit {} is not linked to a Pull Request, but Pull Request \
+                 #{} has the same content: {}",
+                commit.short_id, pr.number, pr.title
+            ),
+        )?;
+        // Nobody answering is no reason to link the commit
+        if !without_asking
+            && !confirm(
+                format!(
+                    "Link commit {} to Pull Request #{} instead of creating a \
+                     new one?",
+                    commit.short_id, pr.number
+                ),
+                false,
+            )
+            .await?
+        {
+            continue;
+        }
+
+        let (_, pr) = candidates.remove(index);
+        output("🔗", &format!("Linked to Pull Request #{}", pr.number))?;
+        commit.pull_request_number = Some(pr.number);
+        commit.message.insert(
+            MessageSection::PullRequest,
+            config.pull_request_url(pr.number),
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether spr may have named a new branch for a commit with the given title
+/// slug like this (see `GitRemote::find_unused_branch_name`)
+fn is_generated_branch_name(
+    name: &str,
+    branch_prefix: &str,
+    slug: &str,
+) -> bool {
+    let Some(rest) = name
+        .strip_prefix(branch_prefix)
+        .and_then(|rest| rest.strip_prefix(slug))
+    else {
+        return false;
+    };
+
+    rest.is_empty()
+        || rest.strip_prefix('-').is_some_and(|suffix| {
+            !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit())
+        })
+}
+
+/// A new Change-Id, in the form Gerrit uses: "I" followed by 40 hexadecimal
+/// digits
+fn new_change_id(oid: Oid) -> Result<String> {
+    let seed = format!("{} {:?}", oid, std::time::SystemTime::now());
+
+    Ok(format!(
+        "I{}",
+        Oid::hash_object(git2::ObjectType::Blob, seed.as_bytes())?
+    ))
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn diff_impl(
     opts: &DiffOptions,
@@ -208,49 +562,54 @@ async fn diff_impl(
     config: &crate::config::Config,
     local_commit: &mut PreparedCommit,
     master_base_oid: Oid,
-    pull_request: Option<PullRequest>,
+    mut pull_request: Option<PullRequest>,
 ) -> Result<()> {
     // Parsed commit message of the local commit
     let message = &mut local_commit.message;
 
+    if config.change_id && !message.contains_key(&MessageSection::ChangeId) {
+        message
+            .insert(MessageSection::ChangeId, new_change_id(local_commit.oid)?);
+    }
+
     // Check if the local commit is based directly on the master branch.
     let directly_based_on_master = local_commit.parent_oid == master_base_oid;
 
     // Determine the trees the Pull Request branch and the base branch should
     // have when we're done here.
-    let (new_head_tree, new_base_tree) = if !opts.cherry_pick
-        || directly_based_on_master
-    {
-        // Unless the user tells us to --cherry-pick, these should be the trees
-        // of the current commit and its parent.
-        // If the current commit is directly based on master (i.e.
-        // directly_based_on_master is true), then we can do this here even when
-        // the user tells us to --cherry-pick, because we would cherry pick the
-        // current commit onto its parent, which gives us the same tree as the
-        // current commit has, and the master base is the same as this commit's
-        // parent.
-        let head_tree = git.get_tree_oid_for_commit(local_commit.oid)?;
-        let base_tree = git.get_tree_oid_for_commit(local_commit.parent_oid)?;
-
-        (head_tree, base_tree)
-    } else {
-        // Cherry-pick the current commit onto master
-        let index = git.cherrypick(local_commit.oid, master_base_oid)?;
+    let (new_head_tree, new_base_tree) =
+        if !opts.cherry_pick || directly_based_on_master {
+            // Unless the user tells us to --cherry-pick, these should be the trees
+            // of the current commit and its parent.
+            // If the current commit is directly based on master (i.e.
+            // directly_based_on_master is true), then we can do this here even when
+            // the user tells us to --cherry-pick, because we would cherry pick the
+            // current commit onto its parent, which gives us the same tree as the
+            // current commit has, and the master base is the same as this commit's
+            // parent.
+            (local_commit.tree_oid, local_commit.parent_tree_oid)
+        } else {
+            // Cherry-pick the current commit onto master
+            let index = git.cherrypick_trees(
+                local_commit.parent_tree_oid,
+                local_commit.tree_oid,
+                master_base_oid,
+            )?;
 
-        if index.has_conflicts() {
-            bail!(
-                "This commit cannot be cherry-picked on {master}.",
-                master = config.master_ref.branch_name(),
-            );
-        }
+            if index.has_conflicts() {
+                bail!(
+                    "This commit cannot be cherry-picked on {master}.",
+                    master = config.master_ref.branch_name(),
+                );
+            }
 
-        // This is the tree we are getting from cherrypicking the local commit
-        // on master.
-        let cherry_pick_tree = git.write_index(index)?;
-        let master_tree = git.get_tree_oid_for_commit(master_base_oid)?;
+            // This is the tree we are getting from cherrypicking the local commit
+            // on master.
+            let cherry_pick_tree = git.write_index(index)?;
+            let master_tree = git.get_tree_oid_for_commit(master_base_oid)?;
 
-        (cherry_pick_tree, master_tree)
-    };
+            (cherry_pick_tree, master_tree)
+        };
 
     if let Some(number) = local_commit.pull_request_number {
         output(
@@ -267,20 +626,58 @@ async fn diff_impl(
         validate_commit_message(message, config)?;
     }
 
-    if let Some(ref pull_request) = pull_request {
+    if let Some(ref mut pull_request) = pull_request {
         if pull_request.state == PullRequestState::Closed {
             return Err(Error::msg(formatdoc!(
                 "Pull request is closed. If you want to open a new one, \
                  remove the 'Pull Request' section from the commit message."
             )));
         }
+        if pull_request.head_repository.is_fork() {
+            bail!(
+                "Pull Request #{} comes from a fork, which spr cannot push \
+                 to. Remove the 'Pull Request' section from the commit \
+                 message to open a new one.",
+                pull_request.number
+            );
+        }
+
+        // Record the commit's Change-Id in the Pull Request, if it is not
+        // there yet
+        if let Some(change_id) = message.get(&MessageSection::ChangeId)
+            && pull_request.sections.get(&MessageSection::ChangeId)
+                != Some(change_id)
+        {
+            let body = set_change_id_marker(
+                pull_request.body.as_deref().unwrap_or_default(),
+                change_id,
+            );
+            gh.update_pull_request(
+                pull_request.number,
+                PullRequestUpdate {
+                    body: Some(body.clone()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+            pull_request.body = Some(body);
+            pull_request
+                .sections
+                .insert(MessageSection::ChangeId, change_id.clone());
+        }
 
         if !opts.update_message {
             let mut pull_request_updates: PullRequestUpdate =
                 Default::default();
-            pull_request_updates.update_message(pull_request, message);
+            pull_request_updates.update_message(
+                pull_request,
+                message,
+                &config.custom_sections,
+            );
 
-            if !pull_request_updates.is_empty() {
+            if pull_request_updates.is_empty() {
+                git.set_synced_sections(config, pull_request.number, message)?;
+            } else {
                 output(
                     "⚠️",
                     indoc!(
@@ -288,8 +685,9 @@ async fn diff_impl(
                          local commit's message.
                          Use `spr diff --update-message` to overwrite the \
                          title and message on GitHub with the local message, \
-                         or `spr amend` to go the other way (rewrite the local \
-                         commit message with what is on GitHub)."
+                         `spr amend` to go the other way (rewrite the local \
+                         commit message with what is on GitHub), or `spr \
+                         sync` to merge the changes made on either side."
                     ),
                 )?;
             }
@@ -308,10 +706,7 @@ async fn diff_impl(
         for reviewer in reviewers {
             // Teams are indicated with a leading #
             if let Some(slug) = reviewer.strip_prefix('#') {
-                if let Ok(team) =
-                    GitHub::get_github_team((&config.owner).into(), slug.into())
-                        .await
-                {
+                if let Ok(team) = gh.get_github_team(slug.into()).await {
                     requested_reviewers
                         .team_reviewers
                         .push(team.slug.to_string());
@@ -418,7 +813,11 @@ async fn diff_impl(
 
                 let mut pull_request_updates: PullRequestUpdate =
                     Default::default();
-                pull_request_updates.update_message(pull_request, message);
+                pull_request_updates.update_message(
+                    pull_request,
+                    message,
+                    &config.custom_sections,
+                );
 
                 if !pull_request_updates.is_empty() {
                     // ...and there are actual changes to the message
@@ -429,6 +828,7 @@ async fn diff_impl(
                     .await?;
                     output("✍", "Updated commit message on GitHub")?;
                 }
+                git.set_synced_sections(config, pull_request.number, message)?;
             }
 
             return Ok(());
@@ -437,7 +837,7 @@ async fn diff_impl(
 
     // Check if there is a base branch on GitHub already. That's the case when
     // there is an existing Pull Request, and its base is not the master branch.
-    let base_branch = if let Some(ref pr) = pull_request {
+    let mut base_branch = if let Some(ref pr) = pull_request {
         if pr.base.is_master_branch() {
             None
         } else {
@@ -447,6 +847,16 @@ async fn diff_impl(
         None
     };
 
+    // If the local commit is directly based on master now (e.g. because the
+    // commit it was based on got landed), the base branch is not needed any
+    // more. The Pull Request goes back to targeting master, and the base
+    // branch gets deleted.
+    let obsolete_base_branch = if directly_based_on_master {
+        base_branch.take()
+    } else {
+        None
+    };
+
     // We are going to construct `pr_base_parent: Option<Oid>`.
     // The value will be the commit we have to merge into the new Pull Request
     // commit to reflect changes in the parent of the local commit (by rebasing
@@ -631,7 +1041,11 @@ async fn diff_impl(
         let mut pull_request_updates: PullRequestUpdate = Default::default();
 
         if opts.update_message {
-            pull_request_updates.update_message(&pull_request, message);
+            pull_request_updates.update_message(
+                &pull_request,
+                message,
+                &config.custom_sections,
+            );
         }
 
         if let Some(base_branch) = base_branch {
@@ -664,12 +1078,40 @@ async fn diff_impl(
             gh.remote()
                 .push_to_remote(push_specs.as_slice())
                 .context("git push failed".to_string())?;
+
+            if obsolete_base_branch.is_some() {
+                pull_request_updates.base =
+                    Some(config.master_ref.branch_name().to_string());
+            }
         }
 
         if !pull_request_updates.is_empty() {
             gh.update_pull_request(pull_request.number, pull_request_updates)
                 .await?;
         }
+
+        // Only now that the Pull Request does not target it any more can the
+        // obsolete base branch go (deleting it earlier would close the Pull
+        // Request)
+        if let Some(obsolete_base_branch) = obsolete_base_branch {
+            gh.remote()
+                .push_to_remote(&[PushSpec {
+                    oid: None,
+                    remote_ref: obsolete_base_branch.on_github(),
+                }])
+                .context("git push failed".to_string())?;
+            output(
+                "🧹",
+                &format!(
+                    "Pull Request now targets {}, deleted base branch {}",
+                    config.master_ref.branch_name(),
+                    obsolete_base_branch.branch_name()
+                ),
+            )?;
+        }
+        if opts.update_message {
+            git.set_synced_sections(config, pull_request.number, message)?;
+        }
     } else {
         // We are creating a new Pull Request.
 
@@ -687,10 +1129,20 @@ async fn diff_impl(
             .push_to_remote(push_specs.as_slice())
             .context("git push failed".to_string())?;
 
+        let template = if config.use_pull_request_template {
+            git.get_pull_request_template(
+                local_commit.oid,
+                config.pull_request_template.as_deref(),
+            )?
+        } else {
+            None
+        };
+
         // Then call GitHub to create the Pull Request.
         let pull_request_number = gh
             .create_pull_request(
                 message,
+                template.as_deref(),
                 base_branch
                     .as_ref()
                     .unwrap_or(&config.master_ref)
@@ -712,6 +1164,7 @@ async fn diff_impl(
         )?;
 
         message.insert(MessageSection::PullRequest, pull_request_url);
+        git.set_synced_sections(config, pull_request_number, message)?;
 
         let result = gh
             .request_reviewers(pull_request_number, requested_reviewers)
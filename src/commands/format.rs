@@ -8,7 +8,7 @@
 use color_eyre::eyre::{Result, eyre};
 
 use crate::{
-    message::validate_commit_message,
+    message::{MetadataFormat, validate_commit_message},
     output::{output, write_commit_title},
 };
 
@@ -17,6 +17,11 @@ pub struct FormatOptions {
     /// format all commits in branch, not just HEAD
     #[clap(long, short = 'a')]
     all: bool,
+
+    /// Write the metadata managed by spr (Reviewers, Reviewed By, Pull
+    /// Request) in this format, instead of the configured one
+    #[clap(long, value_enum)]
+    metadata_format: Option<MetadataFormat>,
 }
 
 pub async fn format(
@@ -47,7 +52,13 @@ pub async fn format(
         failure = validate_commit_message(&commit.message, config).is_err()
             || failure;
     }
-    git.rewrite_commit_messages(slice, None)?;
+
+    // A metadata format given on the command line overrides the configured one
+    let config = &crate::config::Config {
+        metadata_format: opts.metadata_format.unwrap_or(config.metadata_format),
+        ..config.clone()
+    };
+    git.rewrite_commit_messages(config, slice, None)?;
 
     if failure {
         Err(eyre!("format failed"))
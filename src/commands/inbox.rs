@@ -0,0 +1,154 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use color_eyre::eyre::Result;
+
+use crate::{
+    commands::patch::{PatchOptions, patch},
+    github::ReviewRequest,
+    output::output,
+    utils::format_duration,
+};
+
+pub async fn inbox(
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let review_requests = gh.get_review_requests().await?;
+    if review_requests.is_empty() {
+        output("📭", "No Pull Requests are waiting for your review")?;
+        return Ok(());
+    }
+
+    let now = chrono::Utc::now();
+    let term = console::Term::stdout();
+    for review_request in &review_requests {
+        term.write_line(&describe(review_request, now))?;
+    }
+
+    if !console::user_attended() {
+        return Ok(());
+    }
+
+    // Quick actions for one of the Pull Requests
+    let items: Vec<String> = review_requests
+        .iter()
+        .map(|pr| format!("#{} {}", pr.number, pr.title))
+        .collect();
+    let choice = tokio::task::spawn_blocking(move || {
+        dialoguer::Select::new()
+            .with_prompt("Pick a Pull Request (Esc to quit)")
+            .items(&items)
+            .default(0)
+            .interact_opt()
+    })
+    .await??;
+    let Some(review_request) = choice.map(|index| &review_requests[index])
+    else {
+        return Ok(());
+    };
+
+    let action = tokio::task::spawn_blocking(|| {
+        dialoguer::Select::new()
+            .with_prompt("What to do (Esc to quit)")
+            .items(["Check it out with spr patch", "Open it in the browser"])
+            .default(0)
+            .interact_opt()
+    })
+    .await??;
+    match action {
+        Some(0) => {
+            patch(
+                PatchOptions::for_pull_request(review_request.number),
+                git,
+                gh,
+                config,
+            )
+            .await?
+        }
+        Some(_) => open::that_detached(&review_request.url)?,
+        None => (),
+    }
+
+    Ok(())
+}
+
+/// One line describing a review request: who is waiting how long, and what
+/// changed since the user's last review
+fn describe(
+    review_request: &ReviewRequest,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let waiting = review_request
+        .requested_at
+        .map(|time| {
+            format!(", waiting {}", format_duration((now - time).num_seconds()))
+        })
+        .unwrap_or_default();
+    let status = match review_request.reviewed_oid {
+        None => console::style("New"),
+        Some(oid) if oid != review_request.head_oid => {
+            console::style("Updated since your review").yellow()
+        }
+        Some(_) => console::style("Reviewed, no changes since").dim(),
+    };
+
+    format!(
+        "{} #{} {} (by {}{}) {}",
+        status,
+        review_request.number,
+        console::style(&review_request.title).bold(),
+        review_request.author,
+        waiting,
+        console::style(&review_request.url).dim(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        console::set_colors_enabled(false);
+        let head_oid = git2::Oid::from_str("1234").unwrap();
+        let mut review_request = ReviewRequest {
+            number: 12,
+            title: "Add a".to_string(),
+            url: "https://github.com/acme/codez/pull/12".to_string(),
+            author: "alice".to_string(),
+            requested_at: Some(chrono::DateTime::UNIX_EPOCH),
+            head_oid,
+            reviewed_oid: None,
+        };
+
+        assert_eq!(
+            describe(
+                &review_request,
+                chrono::DateTime::UNIX_EPOCH + chrono::TimeDelta::days(3)
+            ),
+            "New #12 Add a (by alice, waiting 3d) \
+             https://github.com/acme/codez/pull/12"
+        );
+
+        review_request.requested_at = None;
+        review_request.reviewed_oid = Some(head_oid);
+        assert_eq!(
+            describe(&review_request, chrono::DateTime::UNIX_EPOCH),
+            "Reviewed, no changes since #12 Add a (by alice) \
+             https://github.com/acme/codez/pull/12"
+        );
+
+        review_request.reviewed_oid = Some(git2::Oid::zero());
+        assert!(
+            describe(&review_request, chrono::DateTime::UNIX_EPOCH)
+                .starts_with("Updated since your review #12")
+        );
+    }
+}
@@ -5,14 +5,18 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use color_eyre::eyre::{Error, Report, Result, WrapErr as _, bail, eyre};
+use color_eyre::eyre::{Error, Result, WrapErr as _, bail, eyre};
 use indoc::formatdoc;
 use std::time::Duration;
 
 use crate::{
+    commands::{
+        diff::{DiffOptions, diff},
+        restore_stashed_changes, stash_uncommitted_changes,
+    },
     git_remote::PushSpec,
     github::{PullRequestState, PullRequestUpdate, ReviewStatus},
-    message::build_github_body_for_merging,
+    message::{build_github_body_for_merging, keep_local_sections},
     output::{output, write_commit_title},
 };
 
@@ -22,6 +26,23 @@ pub struct LandOptions {
     /// --cherry-pick
     #[clap(long)]
     cherry_pick: bool,
+
+    /// Which commit in the branch to land, instead of the HEAD commit. This
+    /// can be any revspec naming a single commit, such as HEAD~2. The rest of
+    /// the branch is rebased onto the landed commit.
+    #[clap(long, short = 'r')]
+    refs: Option<String>,
+
+    /// After landing, update the Pull Requests of the remaining commits in
+    /// the branch (like `spr diff --all`), so that those which no longer need
+    /// a base branch target master directly
+    #[clap(long)]
+    update_stack: bool,
+
+    /// Stash uncommitted changes before, and re-apply them after (default
+    /// taken from git config spr.autoStash)
+    #[clap(long)]
+    autostash: bool,
 }
 
 pub async fn land(
@@ -30,10 +51,46 @@ pub async fn land(
     gh: &mut crate::github::GitHub,
     config: &crate::config::Config,
 ) -> Result<()> {
-    git.check_no_uncommitted_changes()?;
+    let stash =
+        stash_uncommitted_changes(git, opts.autostash || config.auto_stash)?;
+    let result = land_commit(opts, git, gh, config).await;
+
+    // An error restoring the changes must not hide the one that stopped us
+    result.and(restore_stashed_changes(git, stash))
+}
+
+async fn land_commit(
+    opts: LandOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
     let mut prepared_commits = gh.get_prepared_commits()?;
 
-    let based_on_unlanded_commits = prepared_commits.len() > 1;
+    if prepared_commits.is_empty() {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    }
+
+    // The position of the commit to land in the branch
+    let index = match opts.refs.as_deref() {
+        Some(refs) => {
+            let oid = git.repo().revparse_single(refs)?.peel_to_commit()?.id();
+            prepared_commits
+                .iter()
+                .position(|pc| pc.oid == oid)
+                .ok_or_else(|| {
+                    eyre!(
+                        "{} is not one of the commits between {} and HEAD",
+                        refs,
+                        config.master_ref.branch_name()
+                    )
+                })?
+        }
+        None => prepared_commits.len() - 1,
+    };
+
+    let based_on_unlanded_commits = index > 0;
 
     if based_on_unlanded_commits && !opts.cherry_pick {
         return Err(Error::msg(formatdoc!(
@@ -45,13 +102,7 @@ pub async fn land(
         )));
     }
 
-    let prepared_commit = match prepared_commits.last_mut() {
-        Some(c) => c,
-        None => {
-            output("👋", "Branch is empty - nothing to do. Good bye!")?;
-            return Ok(());
-        }
-    };
+    let prepared_commit = &mut prepared_commits[index];
 
     write_commit_title(prepared_commit)?;
 
@@ -64,11 +115,23 @@ pub async fn land(
         };
 
     // Load Pull Request information
-    let pull_request = gh.clone().get_pull_request(pull_request_number).await?;
+    let mut pull_request =
+        gh.clone().get_pull_request(pull_request_number).await?;
+
+    // Sections that are only kept in the local commit message still belong
+    // into the commit that lands on master.
+    keep_local_sections(
+        &mut pull_request.sections,
+        &prepared_commit.message,
+        &config.custom_sections,
+    );
 
     if pull_request.state != PullRequestState::Open {
         bail!("This Pull Request is already closed!");
     }
+    if pull_request.head_repository.is_fork() {
+        bail!("This Pull Request comes from a fork, which spr cannot land.");
+    }
 
     if config.require_approval
         && pull_request.review_status != Some(ReviewStatus::Approved)
@@ -83,7 +146,11 @@ pub async fn land(
         gh.remote().fetch_branch(config.master_ref.branch_name())?;
 
     let base_is_master = pull_request.base.is_master_branch();
-    let index = git.cherrypick(prepared_commit.oid, current_master)?;
+    let index = git.cherrypick_trees(
+        prepared_commit.parent_tree_oid,
+        prepared_commit.tree_oid,
+        current_master,
+    )?;
 
     if index.has_conflicts() {
         return Err(Error::msg(formatdoc!(
@@ -273,32 +340,23 @@ pub async fn land(
             // used a base branch with this Pull Request or not. We have made sure the
             // target of the Pull Request is set to the master branch. So let GitHub do
             // the merge now!
-            octocrab::instance()
-                .pulls(&config.owner, &config.repo)
-                .merge(pull_request_number)
-                .method(octocrab::params::pulls::MergeMethod::Squash)
-                .title(pull_request.title)
-                .message(build_github_body_for_merging(&pull_request.sections))
-                .sha(format!("{}", pr_head_oid))
-                .send()
-                .await
-                .map_err(Report::new)
-                .and_then(|merge| {
-                    if merge.merged {
-                        Ok(merge)
-                    } else {
-                        Err(eyre!(
-                            "GitHub Pull Request merge failed: {}",
-                            merge.message.unwrap_or_default()
-                        ))
-                    }
-                })
+            gh.merge_pull_request(
+                pull_request_number,
+                pull_request.title,
+                build_github_body_for_merging(
+                    &pull_request.sections,
+                    &config.custom_sections,
+                    config.metadata_format,
+                ),
+                pr_head_oid,
+            )
+            .await
         }
         Err(err) => Err(err),
     };
 
-    let merge = match result {
-        Ok(merge) => merge,
+    let merge_commit = match result {
+        Ok(merge_commit) => merge_commit,
         Err(mut error) => {
             output("❌", "GitHub Pull Request merge failed")?;
 
@@ -326,10 +384,11 @@ pub async fn land(
     };
 
     output("🛬", "Landed!")?;
+    git.remove_synced_sections(pull_request_number)?;
 
     // Rebase us on top of the now-landed commit
-    if let Some(sha) = merge.sha {
-        let new_parent_oid = git2::Oid::from_str(&sha)?;
+    let mut rebase_result = Ok(());
+    if let Some(new_parent_oid) = merge_commit {
         // Try this up to three times, because fetching the very moment after
         // the merge might still not find the new commit.
         for i in 0..3 {
@@ -344,11 +403,10 @@ pub async fn land(
                     .context("git fetch failed".to_string());
             }
         }
-        git.rebase_commits(&mut prepared_commits[..], new_parent_oid)
-            .context(
-                "The automatic rebase failed - please rebase manually!"
-                    .to_string(),
-            )?;
+        // If this stops because of conflicts, the error tells the user how
+        // to go on. The Pull Request branches can be deleted either way.
+        rebase_result =
+            git.rebase_commits(&mut prepared_commits[..], new_parent_oid);
     }
 
     let mut push_specs = vec![PushSpec {
@@ -365,5 +423,23 @@ pub async fn land(
 
     gh.remote().push_to_remote(&push_specs)?;
 
+    rebase_result?;
+
+    // The landed commit was dropped from the branch by the rebase, so there
+    // is only something to update if there were other commits
+    if opts.update_stack && prepared_commits.len() > 1 {
+        output("🔁", "Updating the Pull Requests of the remaining commits")?;
+        diff(
+            DiffOptions::update_all(format!(
+                "rebase after landing #{}",
+                pull_request_number
+            )),
+            git,
+            gh,
+            config,
+        )
+        .await?;
+    }
+
     Ok(())
 }
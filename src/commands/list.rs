@@ -5,69 +5,26 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use color_eyre::eyre::{Result, WrapErr as _, eyre};
-use graphql_client::{GraphQLQuery, Response};
+use color_eyre::eyre::Result;
 
-#[allow(clippy::upper_case_acronyms)]
-type URI = String;
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "src/gql/schema.docs.graphql",
-    query_path = "src/gql/open_reviews.graphql",
-    response_derives = "Debug"
-)]
-pub struct SearchQuery;
+use crate::github::ReviewStatus;
 
-pub async fn list(config: &crate::config::Config) -> Result<()> {
-    let variables = search_query::Variables {
-        query: format!(
-            "repo:{}/{} is:open is:pr author:@me archived:false",
-            config.owner, config.repo
-        ),
-    };
-    let request_body = SearchQuery::build_query(variables);
-    let response_body: Response<search_query::ResponseData> =
-        octocrab::instance()
-            .post("/graphql", Some(&request_body))
-            .await
-            .wrap_err("Searching for open PRs".to_string())?;
-
-    print_pr_info(response_body).ok_or_else(|| eyre!("unexpected error"))
-}
-
-fn print_pr_info(
-    response_body: Response<search_query::ResponseData>,
-) -> Option<()> {
+pub async fn list(gh: &crate::github::GitHub) -> Result<()> {
     let term = console::Term::stdout();
-    for pr in response_body.data?.search.nodes? {
-        let pr = match pr {
-            Some(crate::commands::list::search_query::SearchQuerySearchNodes::PullRequest(pr)) => pr,
-            _ => continue,
-        };
-        let dummy: String;
-        let decision = match pr.review_decision {
-            Some(search_query::PullRequestReviewDecision::APPROVED) => {
-                console::style("Accepted").green()
-            }
-            Some(
-                search_query::PullRequestReviewDecision::CHANGES_REQUESTED,
-            ) => console::style("Changes Requested").red(),
-            None
-            | Some(search_query::PullRequestReviewDecision::REVIEW_REQUIRED) => {
-                console::style("Pending")
-            }
-            Some(search_query::PullRequestReviewDecision::Other(d)) => {
-                dummy = d;
-                console::style(dummy.as_str())
+    for pr in gh.get_open_pull_requests_by_viewer().await? {
+        let decision = match pr.review_status {
+            Some(ReviewStatus::Approved) => console::style("Accepted").green(),
+            Some(ReviewStatus::Rejected) => {
+                console::style("Changes Requested").red()
             }
+            Some(ReviewStatus::Requested) | None => console::style("Pending"),
         };
         term.write_line(&format!(
             "{} {} {}",
             decision,
             console::style(&pr.title).bold(),
             console::style(&pr.url).dim(),
-        ))
-        .ok()?;
+        ))?;
     }
-    Some(())
+    Ok(())
 }
@@ -5,11 +5,70 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+pub mod adopt;
 pub mod amend;
 pub mod close;
 pub mod diff;
 pub mod format;
+pub mod inbox;
 pub mod init;
 pub mod land;
 pub mod list;
 pub mod patch;
+pub mod rebase;
+pub mod review;
+pub mod sync;
+
+use color_eyre::eyre::Result;
+use git2::Oid;
+
+use crate::output::output;
+
+/// Make sure there are no uncommitted changes: stash them if `autostash` is
+/// set (by the `--autostash` option or `spr.autoStash`), otherwise fail if
+/// there are any. Returns the stash entry to restore when done.
+fn stash_uncommitted_changes(
+    git: &crate::git::Git,
+    autostash: bool,
+) -> Result<Option<Oid>> {
+    if !autostash {
+        git.check_no_uncommitted_changes()?;
+        return Ok(None);
+    }
+
+    let stash = git.stash_changes()?;
+    if stash.is_some() {
+        output("📦", "Stashed uncommitted changes")?;
+    }
+
+    Ok(stash)
+}
+
+/// Re-apply changes stashed by `stash_uncommitted_changes`
+fn restore_stashed_changes(
+    git: &crate::git::Git,
+    stash: Option<Oid>,
+) -> Result<()> {
+    let Some(stash_oid) = stash else {
+        return Ok(());
+    };
+
+    if git.rebase_in_progress() {
+        output(
+            "📦",
+            "Your uncommitted changes are kept in the stash. Once the rebase \
+             is done, apply them with `git stash pop`.",
+        )?;
+    } else if git.pop_stashed_changes(stash_oid)? {
+        output("📦", "Re-applied stashed changes")?;
+    } else {
+        output(
+            "⚠️",
+            "Your stashed changes conflict with the updated worktree, so they \
+             are kept in the stash. Apply them with `git stash pop` and \
+             resolve the conflicts.",
+        )?;
+    }
+
+    Ok(())
+}
@@ -5,25 +5,66 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use color_eyre::eyre::Result;
+use std::{collections::HashSet, path::PathBuf};
+
+use color_eyre::eyre::{Result, WrapErr as _, bail, eyre};
+use git2::Oid;
 
 use crate::{
+    github::{PullRequest, PullRequestState},
     message::{MessageSection, build_commit_message},
     output::output,
 };
 
 #[derive(Debug, clap::Parser)]
 pub struct PatchOptions {
-    /// Pull Request number
-    pull_request: u64,
+    /// Pull Request number. Several Pull Requests are combined on one branch
+    /// (which needs --branch-name), in the order of their dependencies.
+    #[clap(required_unless_present = "prune")]
+    pull_requests: Vec<u64>,
 
     /// Name of the branch to be created. Defaults to `PR-<number>`
     #[clap(long)]
     branch_name: Option<String>,
 
     /// If given, create new branch but do not check out
-    #[clap(long)]
+    #[clap(long, conflicts_with = "worktree")]
     no_checkout: bool,
+
+    /// Check out the new branch in a new worktree at the given path, instead
+    /// of the current one. Defaults to a directory next to the current
+    /// worktree, named after the repository and the branch.
+    #[clap(long, value_name = "PATH", num_args = 0..=1)]
+    worktree: Option<Option<PathBuf>>,
+
+    /// Remove the worktrees created with --worktree whose Pull Requests have
+    /// all been merged or closed, unless they contain changed or untracked
+    /// files, and forget which revision of those Pull Requests was checked
+    /// out
+    #[clap(long, conflicts_with_all = ["pull_requests", "update"])]
+    prune: bool,
+
+    /// Rebuild the branch created by an earlier `spr patch` of this Pull
+    /// Request (`PR-<number>` or the one given with --branch-name) for the
+    /// Pull Request's current revision, instead of creating a new branch.
+    /// The branch stays checked out wherever it is
+    #[clap(long, conflicts_with_all = ["no_checkout", "worktree"])]
+    update: bool,
+}
+
+impl PatchOptions {
+    /// Options for checking out the given Pull Request on a new branch with
+    /// the default name
+    pub fn for_pull_request(pull_request: u64) -> Self {
+        Self {
+            pull_requests: vec![pull_request],
+            branch_name: None,
+            no_checkout: false,
+            worktree: None,
+            prune: false,
+            update: false,
+        }
+    }
 }
 
 pub async fn patch(
@@ -32,7 +73,220 @@ pub async fn patch(
     gh: &mut crate::github::GitHub,
     config: &crate::config::Config,
 ) -> Result<()> {
-    let pr = gh.clone().get_pull_request(opts.pull_request).await?;
+    if opts.prune {
+        return prune(git, gh).await;
+    }
+    if opts.pull_requests.len() > 1 {
+        return patch_several(opts, git, gh, config).await;
+    }
+
+    let pr = gh.clone().get_pull_request(opts.pull_requests[0]).await?;
+    write_pull_request_title(&pr)?;
+
+    if opts.update {
+        return update(opts, &pr, git, gh, config);
+    }
+
+    let branch_name = if let Some(name) = opts.branch_name.clone() {
+        name
+    } else {
+        git.get_pr_patch_branch_name(pr.number)?
+    };
+
+    let patch_branch_oid = create_patch_commits(&pr, git, gh, config)?;
+    if pr.merge_commit.is_none() {
+        // Remember what we checked out, so that `spr review` and `spr patch
+        // --update` can tell whether the Pull Request changed since
+        git.set_patched_head(pr.number, pr.head_oid)?;
+    }
+
+    create_branch(git, &branch_name, patch_branch_oid, &opts)
+}
+
+/// Combine several Pull Requests on one new branch, each as a commit on top
+/// of current master, in the order of their dependencies
+async fn patch_several(
+    opts: PatchOptions,
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    if opts.update {
+        bail!("--update works with one Pull Request only");
+    }
+    let Some(branch_name) = opts.branch_name.clone() else {
+        bail!(
+            "Give the name of the branch to create with --branch-name when \
+             combining several Pull Requests"
+        );
+    };
+
+    let mut pull_requests = Vec::new();
+    for &number in &opts.pull_requests {
+        pull_requests.push(gh.clone().get_pull_request(number).await?);
+    }
+
+    let repo = git.repo();
+    let master_oid =
+        gh.remote().fetch_branch(config.master_ref.branch_name())?;
+
+    // The Pull Request's changes are the ones from the commit it is based on
+    // to its head (see `create_patch_commits`)
+    let base_oids = pull_requests
+        .iter()
+        .map(|pr| Ok(repo.merge_base(pr.head_oid, pr.base_oid)?))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Which Pull Request is based on which: either by name of the branch (if
+    // the base branch is the other's head branch), or by content (spr's base
+    // branches have the tree of the Pull Request below in the stack)
+    let mut based_on =
+        vec![vec![false; pull_requests.len()]; pull_requests.len()];
+    for (i, pr) in pull_requests.iter().enumerate() {
+        if pr.base.is_master_branch() {
+            continue;
+        }
+        let base_tree_oid = git.get_tree_oid_for_commit(base_oids[i])?;
+        for (j, other) in pull_requests.iter().enumerate() {
+            based_on[i][j] = i != j
+                && ((!other.head_repository.is_fork()
+                    && other.head.branch_name() == pr.base.branch_name())
+                    || git.get_tree_oid_for_commit(other.head_oid)?
+                        == base_tree_oid);
+        }
+    }
+
+    let mut tip_oid = master_oid;
+    let mut applied = HashSet::new();
+    let mut conflicting = Vec::new();
+
+    for index in dependency_order(&based_on) {
+        let pr = &pull_requests[index];
+        write_pull_request_title(pr)?;
+        if pr.merge_commit.is_some() {
+            output("❗", "Pull Request has been merged - skipping it")?;
+            continue;
+        }
+
+        let pr_base_oid = base_oids[index];
+        let mut parent_oid = tip_oid;
+
+        // Unless the Pull Request is based on master or on another Pull
+        // Request on this branch, its base branch adds changes of its own,
+        // which go into a commit before the Pull Request's one
+        if !pr.base.is_master_branch()
+            && !applied.iter().any(|&j| based_on[index][j])
+        {
+            let pr_master_oid = repo.merge_base(pr.head_oid, master_oid)?;
+            if git.get_tree_oid_for_commit(pr_base_oid)?
+                != git.get_tree_oid_for_commit(pr_master_oid)?
+            {
+                let Some(tree_oid) =
+                    apply_changes(git, pr_master_oid, pr_base_oid, parent_oid)?
+                else {
+                    output("💥", "The base of this Pull Request conflicts")?;
+                    conflicting.push(pr.number);
+                    continue;
+                };
+                if tree_oid != git.get_tree_oid_for_commit(parent_oid)? {
+                    parent_oid = git.create_derived_commit(
+                        pr_base_oid,
+                        &format!("[𝘀𝗽𝗿] Base of Pull Request #{}", pr.number),
+                        tree_oid,
+                        &[parent_oid],
+                    )?;
+                }
+            }
+        }
+
+        let Some(tree_oid) =
+            apply_changes(git, pr_base_oid, pr.head_oid, parent_oid)?
+        else {
+            output("💥", "This Pull Request conflicts")?;
+            conflicting.push(pr.number);
+            continue;
+        };
+        tip_oid = git.create_derived_commit(
+            pr.head_oid,
+            &build_commit_message(
+                &pr.sections,
+                &config.custom_sections,
+                config.metadata_format,
+            ),
+            tree_oid,
+            &[parent_oid],
+        )?;
+        git.update_metadata_note(config, tip_oid, &pr.sections)?;
+        git.set_patched_head(pr.number, pr.head_oid)?;
+        applied.insert(index);
+        output("🧩", "Added to the branch")?;
+    }
+
+    if tip_oid == master_oid {
+        bail!("None of the Pull Requests could be added to the branch");
+    }
+    create_branch(git, &branch_name, tip_oid, &opts)?;
+
+    if !conflicting.is_empty() {
+        output(
+            "⚠️",
+            &format!(
+                "Left out because of conflicts: {}",
+                conflicting
+                    .iter()
+                    .map(|number| format!("#{}", number))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The order in which to stack Pull Requests, so that each one comes after
+/// the ones it is based on. `based_on[i][j]` says whether the `i`th Pull
+/// Request is based on the `j`th one.
+fn dependency_order(based_on: &[Vec<bool>]) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0..based_on.len()).collect();
+    let mut order = Vec::new();
+
+    while !remaining.is_empty() {
+        // The first Pull Request not based on one still remaining (or just
+        // the first one, should they form a cycle)
+        let position = remaining
+            .iter()
+            .position(|&i| !remaining.iter().any(|&j| based_on[i][j]))
+            .unwrap_or(0);
+        order.push(remaining.remove(position));
+    }
+
+    order
+}
+
+/// The tree we get by applying the changes between two commits on top of a
+/// third one, or `None` if they conflict
+fn apply_changes(
+    git: &crate::git::Git,
+    from_oid: Oid,
+    to_oid: Oid,
+    onto_oid: Oid,
+) -> Result<Option<Oid>> {
+    let repo = git.repo();
+    let index = repo.merge_trees(
+        &repo.find_commit(from_oid)?.tree()?,
+        &repo.find_commit(onto_oid)?.tree()?,
+        &repo.find_commit(to_oid)?.tree()?,
+        None,
+    )?;
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+
+    Ok(Some(git.write_index(index)?))
+}
+
+fn write_pull_request_title(pr: &PullRequest) -> Result<()> {
     output(
         "#️⃣ ",
         &format!(
@@ -43,15 +297,357 @@ pub async fn patch(
                 .map(|s| &s[..])
                 .unwrap_or("(no title)")
         ),
-    )?;
+    )
+}
 
-    let branch_name = if let Some(name) = opts.branch_name {
-        name
+/// Create the branch (replacing any existing one of that name) and check it
+/// out as the options say
+fn create_branch(
+    git: &crate::git::Git,
+    branch_name: &str,
+    oid: Oid,
+    opts: &PatchOptions,
+) -> Result<()> {
+    let repo = git.repo();
+    let commit = repo.find_commit(oid)?;
+
+    // With --worktree, the worktree's directory must not exist yet. Check
+    // this before creating the branch.
+    let worktree_path = match &opts.worktree {
+        Some(Some(path)) => Some(path.clone()),
+        Some(None) => {
+            let workdir = repo
+                .workdir()
+                .ok_or_else(|| eyre!("The repository has no worktree"))?;
+            let repo_name = workdir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Some(workdir.with_file_name(format!(
+                "{}-{}",
+                repo_name,
+                branch_name.replace('/', "-")
+            )))
+        }
+        None => None,
+    };
+    if let Some(path) = &worktree_path
+        && path.exists()
+    {
+        bail!("Cannot create a worktree at {}: it exists", path.display());
+    }
+
+    // Create the new branch, now that we know the commit it shall point to
+    let branch = repo.branch(branch_name, &commit, true)?;
+
+    output("🌱", &format!("Created new branch: {}", branch_name))?;
+
+    if let Some(path) = worktree_path {
+        // The worktree's name (in the Git directory) is the one Git would
+        // choose: that of its directory
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| eyre!("Invalid worktree path {}", path.display()))?;
+        repo.worktree(
+            &name,
+            &path,
+            Some(git2::WorktreeAddOptions::new().reference(Some(branch.get()))),
+        )?;
+        git.set_patch_worktree(&name, &opts.pull_requests)?;
+        output(
+            "✅",
+            &format!("Checked out in new worktree: {}", path.display()),
+        )?;
+    } else if !opts.no_checkout {
+        // Check out the new branch
+        repo.checkout_tree(commit.as_object(), None)?;
+        repo.set_head(&format!("refs/heads/{}", branch_name))?;
+        output("✅", "Checked out")?;
+    }
+
+    Ok(())
+}
+
+/// Rebuild the patch branch of the Pull Request for its current revision,
+/// keeping any commits the user made on top of it
+fn update(
+    opts: PatchOptions,
+    pr: &PullRequest,
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let repo = git.repo();
+    let branch_name = opts
+        .branch_name
+        .unwrap_or_else(|| format!("PR-{}", pr.number));
+    let ref_name = format!("refs/heads/{}", branch_name);
+    let mut reference = repo.find_reference(&ref_name).wrap_err_with(|| {
+        format!(
+            "There is no branch {} to update. Run `spr patch {}` without \
+             --update to create it.",
+            branch_name, pr.number
+        )
+    })?;
+    let old_tip_oid = reference
+        .target()
+        .ok_or_else(|| eyre!("{} is not a regular branch", branch_name))?;
+
+    let Some(patched_oid) = git.get_patched_head(pr.number)? else {
+        bail!(
+            "There is no record of which revision of Pull Request #{} was \
+             checked out with spr patch",
+            pr.number
+        );
+    };
+    if pr.merge_commit.is_none() && patched_oid == pr.head_oid {
+        output("✅", "Branch is up to date with the Pull Request")?;
+        return Ok(());
+    }
+
+    // Find the commits the user made on top of the patch commit, which is
+    // the one with the tree of the Pull Request revision checked out before
+    let master_oid =
+        gh.remote().fetch_branch(config.master_ref.branch_name())?;
+    let patched_tree_oid = git.get_tree_oid_for_commit(patched_oid)?;
+    let mut local_commits = Vec::new();
+    let mut walk = repo.revwalk()?;
+    walk.push(old_tip_oid)?;
+    walk.hide(master_oid)?;
+    walk.simplify_first_parent()?;
+    let mut found = false;
+    for oid in walk {
+        let oid = oid?;
+        if git.get_tree_oid_for_commit(oid)? == patched_tree_oid {
+            found = true;
+            break;
+        }
+        local_commits.push(oid);
+    }
+    if !found {
+        bail!(
+            "Could not find the commit of Pull Request #{} on branch {}",
+            pr.number,
+            branch_name
+        );
+    }
+    local_commits.reverse();
+
+    let checked_out = find_checkout(git, &ref_name)?;
+    if let Some(checkout) = &checked_out {
+        checkout.check_no_uncommitted_changes()?;
+    }
+
+    write_changes(git, patched_oid, pr.head_oid)?;
+
+    let patch_commit_oid = create_patch_commits(pr, git, gh, config)?;
+    let new_tip_oid = if local_commits.is_empty() {
+        patch_commit_oid
     } else {
-        git.get_pr_patch_branch_name(pr.number)?
+        let Some(oid) =
+            git.try_rebase_commits(&local_commits, patch_commit_oid)?
+        else {
+            bail!(
+                "Your commits on top of Pull Request #{} on branch {} do not \
+                 apply cleanly to its new revision. The branch was left \
+                 unchanged.",
+                pr.number,
+                branch_name
+            );
+        };
+        output(
+            "🔁",
+            &format!(
+                "Rebased {} local commit{}",
+                local_commits.len(),
+                if local_commits.len() == 1 { "" } else { "s" }
+            ),
+        )?;
+        oid
     };
 
-    let patch_branch_oid = if let Some(oid) = pr.merge_commit {
+    if let Some(checkout) = &checked_out {
+        let checkout_repo = checkout.repo();
+        checkout_repo.checkout_tree(
+            checkout_repo.find_commit(new_tip_oid)?.as_object(),
+            None,
+        )?;
+    }
+    reference.set_target(new_tip_oid, "spr patch --update")?;
+    if pr.merge_commit.is_none() {
+        git.set_patched_head(pr.number, pr.head_oid)?;
+    }
+    output("🌱", &format!("Updated branch: {}", branch_name))?;
+
+    Ok(())
+}
+
+/// The worktree (this one or a linked one) in which the given branch is
+/// checked out, if any
+fn find_checkout(
+    git: &crate::git::Git,
+    ref_name: &str,
+) -> Result<Option<crate::git::Git>> {
+    let repo = git.repo();
+    if repo.head()?.name() == Some(ref_name) {
+        return Ok(Some(git.clone()));
+    }
+
+    for name in repo.worktrees()?.iter().flatten() {
+        let worktree = repo.find_worktree(name)?;
+        if worktree.validate().is_err() {
+            continue;
+        }
+        let worktree_repo = git2::Repository::open_from_worktree(&worktree)?;
+        if worktree_repo.head().ok().as_ref().and_then(|h| h.name())
+            == Some(ref_name)
+        {
+            return Ok(Some(crate::git::Git::new(worktree_repo)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Print the files that changed between two revisions of a Pull Request
+fn write_changes(
+    git: &crate::git::Git,
+    old_oid: Oid,
+    new_oid: Oid,
+) -> Result<()> {
+    let repo = git.repo();
+    let old_tree = repo.find_commit(old_oid)?.tree()?;
+    let new_tree = repo.find_commit(new_oid)?.tree()?;
+    let diff =
+        repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    let stats = diff.stats()?;
+
+    output(
+        "🔀",
+        &format!(
+            "Changes since the revision checked out before: {} file(s) \
+             changed, {} insertion(s), {} deletion(s)",
+            stats.files_changed(),
+            stats.insertions(),
+            stats.deletions()
+        ),
+    )?;
+    let term = console::Term::stdout();
+    for delta in diff.deltas() {
+        let status = match delta.status() {
+            git2::Delta::Added => 'A',
+            git2::Delta::Deleted => 'D',
+            git2::Delta::Renamed => 'R',
+            _ => 'M',
+        };
+        let path = delta.new_file().path().or(delta.old_file().path());
+        term.write_line(&format!(
+            "      {} {}",
+            status,
+            path.map(|p| p.display().to_string()).unwrap_or_default()
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Remove the linked worktrees created by `spr patch --worktree` whose Pull
+/// Requests are all merged or closed
+async fn prune(
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+) -> Result<()> {
+    let repo = git.repo();
+    let mut pruned = 0;
+
+    for name in repo.worktrees()?.iter().flatten() {
+        // Worktrees spr did not create are none of its business
+        let Some(numbers) = git.get_patch_worktree(name)? else {
+            continue;
+        };
+        let worktree = repo.find_worktree(name)?;
+        if worktree.validate().is_err() {
+            continue;
+        }
+        let path = worktree.path().to_path_buf();
+        let worktree_repo = git2::Repository::open_from_worktree(&worktree)?;
+        if repo.workdir() == worktree_repo.workdir() {
+            // This is where we are
+            continue;
+        }
+
+        let mut all_done = true;
+        for &number in &numbers {
+            if gh.get_pull_request_state(number).await?
+                == PullRequestState::Open
+            {
+                all_done = false;
+                break;
+            }
+        }
+        if !all_done {
+            continue;
+        }
+
+        // Removing the worktree deletes its directory, so anything that is
+        // not committed must be kept. Ignored files, like build output, are
+        // expected to be thrown away.
+        let mut status_options = git2::StatusOptions::new();
+        status_options
+            .include_ignored(false)
+            .include_untracked(true);
+        if !worktree_repo
+            .statuses(Some(&mut status_options))?
+            .is_empty()
+        {
+            output(
+                "⚠️",
+                &format!(
+                    "Keeping worktree {}, which has uncommitted or untracked \
+                     files",
+                    path.display()
+                ),
+            )?;
+            continue;
+        }
+
+        worktree.prune(Some(
+            git2::WorktreePruneOptions::new()
+                .valid(true)
+                .working_tree(true),
+        ))?;
+        git.remove_patch_worktree(name)?;
+        output("🧹", &format!("Removed worktree {}", path.display()))?;
+        pruned += 1;
+    }
+
+    if pruned == 0 {
+        output("👋", "No worktrees to remove")?;
+    }
+
+    // The heads recorded for `spr patch --update` are of no use once the Pull
+    // Request is merged or closed
+    for number in git.get_patched_pull_requests()? {
+        if gh.get_pull_request_state(number).await? != PullRequestState::Open {
+            git.remove_patched_head(number)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Create the commits for the patch branch of the given Pull Request: the
+/// Pull Request commit, based on a master commit or on a commit squashing the
+/// Pull Request's base branch. Returns the Pull Request commit, or the merge
+/// commit if the Pull Request was merged.
+fn create_patch_commits(
+    pr: &PullRequest,
+    git: &crate::git::Git,
+    gh: &crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<Oid> {
+    let oid = if let Some(oid) = pr.merge_commit {
         output("❗", "Pull Request has been merged")?;
 
         oid
@@ -104,28 +700,57 @@ pub async fn patch(
         // Create the main commit for the patch branch. This is based on a
         // master commit, or, if the PR can't be based on master directly, on
         // the commit we created above to prepare the base of this commit.
-        git.create_derived_commit(
+        let oid = git.create_derived_commit(
             pr.head_oid,
-            &build_commit_message(&pr.sections),
+            &build_commit_message(
+                &pr.sections,
+                &config.custom_sections,
+                config.metadata_format,
+            ),
             git.get_tree_oid_for_commit(pr.head_oid)?,
             &[pr_master_oid],
-        )?
+        )?;
+        git.update_metadata_note(config, oid, &pr.sections)?;
+
+        oid
     };
 
-    let repo = git.repo();
-    let patch_branch_commit = repo.find_commit(patch_branch_oid)?;
+    Ok(oid)
+}
 
-    // Create the new branch, now that we know the commit it shall point to
-    repo.branch(&branch_name, &patch_branch_commit, true)?;
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
 
-    output("🌱", &format!("Created new branch: {}", &branch_name))?;
+    #[test]
+    fn test_dependency_order() {
+        assert_eq!(dependency_order(&[]), Vec::<usize>::new());
 
-    if !opts.no_checkout {
-        // Check out the new branch
-        repo.checkout_tree(patch_branch_commit.as_object(), None)?;
-        repo.set_head(&format!("refs/heads/{}", branch_name))?;
-        output("✅", "Checked out")?;
-    }
+        // A stack of two, given top first, and an unrelated fix
+        assert_eq!(
+            dependency_order(&[
+                vec![false, false, true],
+                vec![false, false, false],
+                vec![false, false, false],
+            ]),
+            vec![1, 2, 0]
+        );
 
-    Ok(())
+        // A stack of three, given top first
+        assert_eq!(
+            dependency_order(&[
+                vec![false, true, true],
+                vec![false, false, true],
+                vec![false, false, false],
+            ]),
+            vec![2, 1, 0]
+        );
+
+        // Cycles cannot happen on GitHub, but must not loop forever
+        assert_eq!(
+            dependency_order(&[vec![false, true], vec![true, false]]),
+            vec![0, 1]
+        );
+    }
 }
@@ -0,0 +1,27 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Commands to finish or undo a rebase that spr started (e.g. after landing a
+//! Pull Request) and that stopped because of conflicts
+
+use color_eyre::eyre::Result;
+
+use crate::output::output;
+
+pub async fn continue_rebase(git: &crate::git::Git) -> Result<()> {
+    git.continue_rebase()?;
+    output("✅", "Rebase complete")?;
+
+    Ok(())
+}
+
+pub async fn abort_rebase(git: &crate::git::Git) -> Result<()> {
+    git.abort_rebase()?;
+    output("↩️", "Rebase aborted, back to where it started")?;
+
+    Ok(())
+}
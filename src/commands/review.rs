@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::io::Read as _;
+
+use color_eyre::eyre::{Result, WrapErr as _, bail, eyre};
+
+use crate::{
+    github::{PullRequestState, ReviewEvent},
+    output::output,
+};
+
+#[derive(Debug, clap::Parser)]
+#[clap(group(clap::ArgGroup::new("event").required(true)))]
+pub struct ReviewOptions {
+    /// Pull Request number or URL
+    pull_request: String,
+
+    /// Approve the Pull Request
+    #[clap(long, group = "event")]
+    approve: bool,
+
+    /// Request changes to the Pull Request
+    #[clap(long, group = "event")]
+    request_changes: bool,
+
+    /// Comment on the Pull Request without approving it or requesting
+    /// changes
+    #[clap(long, group = "event")]
+    comment: bool,
+
+    /// Review comment
+    #[clap(long, short = 'm', conflicts_with = "body_file")]
+    message: Option<String>,
+
+    /// Read the review comment from the given file (`-` for standard input)
+    #[clap(long)]
+    body_file: Option<std::path::PathBuf>,
+}
+
+pub async fn review(
+    opts: ReviewOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let number = config
+        .parse_pull_request_field(&opts.pull_request)
+        .ok_or_else(|| {
+            eyre!(
+                "'{}' is neither the number nor the URL of a Pull Request in \
+                 {}/{}",
+                opts.pull_request,
+                config.owner,
+                config.repo
+            )
+        })?;
+
+    let event = if opts.approve {
+        ReviewEvent::Approve
+    } else if opts.request_changes {
+        ReviewEvent::RequestChanges
+    } else {
+        ReviewEvent::Comment
+    };
+
+    let body = match (opts.message, opts.body_file) {
+        (Some(message), _) => message,
+        (None, Some(path)) if path.as_os_str() == "-" => {
+            let mut body = String::new();
+            std::io::stdin().read_to_string(&mut body)?;
+            body
+        }
+        (None, Some(path)) => std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("Could not read {:?}", path))?,
+        (None, None) => String::new(),
+    };
+    let body = body.trim().to_string();
+    if body.is_empty() && event != ReviewEvent::Approve {
+        bail!(
+            "Requesting changes or commenting needs a review comment (use \
+             --message or --body-file)"
+        );
+    }
+
+    let pull_request = gh.clone().get_pull_request(number).await?;
+    if pull_request.state != PullRequestState::Open {
+        bail!("Pull Request #{} is not open", number);
+    }
+
+    // Make sure the review is about the code the user looked at
+    if let Some(patched_oid) = git.get_patched_head(number)?
+        && patched_oid != pull_request.head_oid
+    {
+        if event == ReviewEvent::Approve {
+            bail!(
+                "Pull Request #{} was updated since you checked it out with \
+                 spr patch. Run `spr patch {}` to check out the new revision \
+                 before approving it.",
+                number,
+                number
+            );
+        }
+        output(
+            "⚠️",
+            &format!(
+                "Pull Request #{} was updated since you checked it out with \
+                 spr patch",
+                number
+            ),
+        )?;
+    }
+
+    gh.submit_review(number, event, body, pull_request.head_oid)
+        .await?;
+
+    let (icon, text) = match event {
+        ReviewEvent::Approve => ("✅", "Approved"),
+        ReviewEvent::RequestChanges => ("🚧", "Requested changes to"),
+        ReviewEvent::Comment => ("💬", "Commented on"),
+    };
+    output(icon, &format!("{} Pull Request #{}", text, number))?;
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use color_eyre::eyre::{Result, bail, eyre};
+
+use crate::{
+    git::PreparedCommit,
+    github::PullRequestUpdate,
+    message::{
+        SectionConflict, merge_sections, message_section_label, synced_sections,
+    },
+    output::{output, write_commit_title},
+};
+
+#[derive(Debug, clap::Parser)]
+pub struct SyncOptions {
+    /// Sync all commits in branch, not just HEAD
+    #[clap(long, short = 'a')]
+    all: bool,
+
+    /// Resolve conflicts by keeping the local version of a section
+    #[clap(long, conflicts_with = "prefer_github")]
+    prefer_local: bool,
+
+    /// Resolve conflicts by keeping the version of a section on GitHub
+    #[clap(long)]
+    prefer_github: bool,
+}
+
+pub async fn sync(
+    opts: SyncOptions,
+    git: &crate::git::Git,
+    gh: &mut crate::github::GitHub,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let mut pc = gh.get_prepared_commits()?;
+
+    let len = pc.len();
+    if len == 0 {
+        output("👋", "Branch is empty - nothing to do. Good bye!")?;
+        return Ok(());
+    }
+
+    // The slice of prepared commits we want to operate on.
+    let slice = if opts.all {
+        &mut pc[..]
+    } else {
+        &mut pc[len - 1..]
+    };
+
+    // Request the Pull Request information for each commit (well, those that
+    // declare to have Pull Requests). This list is in reverse order, so that
+    // below we can pop from the vector as we iterate.
+    let mut pull_requests: Vec<_> = slice
+        .iter()
+        .rev()
+        .map(|pc: &PreparedCommit| {
+            pc.pull_request_number.map(|number| {
+                tokio::task::spawn_local(gh.clone().get_pull_request(number))
+            })
+        })
+        .collect();
+
+    let mut result = Ok(());
+
+    for commit in slice.iter_mut() {
+        write_commit_title(commit)?;
+        let Some(pull_request) = pull_requests.pop().flatten() else {
+            output("🤷", "This commit does not have a Pull Request")?;
+            continue;
+        };
+        if result.is_err() {
+            continue;
+        }
+        let pull_request = match pull_request.await? {
+            Ok(pull_request) => pull_request,
+            Err(error) => {
+                result = Err(error);
+                continue;
+            }
+        };
+
+        let base = git.get_synced_sections(config, pull_request.number)?;
+        if base.is_none() {
+            output(
+                "ℹ️",
+                "There is no record of this commit and its Pull Request \
+                 being in sync, so all differences are conflicts",
+            )?;
+        }
+
+        let (mut merged, conflicts) = merge_sections(
+            base.as_ref(),
+            &commit.message,
+            &pull_request.sections,
+            &config.custom_sections,
+        );
+
+        for conflict in conflicts {
+            let text = match resolve_conflict(&opts, &conflict).await {
+                Ok(text) => text,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            };
+            if !text.is_empty() {
+                merged.insert(conflict.section, text);
+            }
+        }
+        if result.is_err() {
+            continue;
+        }
+
+        // Apply the merged sections to the Pull Request...
+        let mut pull_request_updates = PullRequestUpdate::default();
+        pull_request_updates.update_message(
+            &pull_request,
+            &merged,
+            &config.custom_sections,
+        );
+        if !pull_request_updates.is_empty() {
+            if let Err(error) = gh
+                .update_pull_request(pull_request.number, pull_request_updates)
+                .await
+            {
+                result = Err(error);
+                continue;
+            }
+            output("✍", "Updated commit message on GitHub")?;
+        }
+
+        // ...and to the local commit message
+        for section in synced_sections(&config.custom_sections) {
+            match merged.get(&section) {
+                Some(text) => commit.message.insert(section, text.clone()),
+                None => commit.message.remove(&section),
+            };
+        }
+
+        git.set_synced_sections(config, pull_request.number, &merged)?;
+    }
+
+    // The commit messages of the Pull Requests synced so far must be updated
+    // even if a later one failed, as their new state is recorded already
+    git.rewrite_commit_messages(config, slice, None)?;
+
+    result
+}
+
+/// Find out which version of a section to keep, by asking the user unless the
+/// command line options say so already
+async fn resolve_conflict(
+    opts: &SyncOptions,
+    conflict: &SectionConflict,
+) -> Result<String> {
+    let label = message_section_label(&conflict.section).to_string();
+    output(
+        "💥",
+        &format!("{} was changed both locally and on GitHub", label),
+    )?;
+
+    if opts.prefer_local {
+        return Ok(conflict.local.clone());
+    }
+    if opts.prefer_github {
+        return Ok(conflict.remote.clone());
+    }
+
+    let term = console::Term::stdout();
+    for (side, text) in
+        [("Local", &conflict.local), ("GitHub", &conflict.remote)]
+    {
+        term.write_line(&format!("{}", console::style(side).bold()))?;
+        term.write_line(text)?;
+    }
+
+    let (local, remote) = (conflict.local.clone(), conflict.remote.clone());
+    tokio::task::spawn_blocking(move || {
+        let choice = dialoguer::Select::new()
+            .with_prompt(format!("Which {} to keep", label))
+            .items(["Local", "GitHub", "Edit both"])
+            .default(0)
+            .interact()?;
+
+        match choice {
+            0 => Ok(local),
+            1 => Ok(remote),
+            _ => {
+                let text = dialoguer::Editor::new()
+                    .edit(&format!(
+                        "<<<<<<< Local\n{}\n=======\n{}\n>>>>>>> GitHub\n",
+                        local, remote
+                    ))?
+                    .ok_or_else(|| eyre!("Aborted as per user request"))?;
+                if text.contains("<<<<<<<") || text.contains(">>>>>>>") {
+                    bail!("Conflict markers left in {}", label);
+                }
+                Ok(text.trim_end().trim_start_matches('\n').to_string())
+            }
+        }
+    })
+    .await?
+}
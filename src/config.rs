@@ -5,9 +5,371 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, WrapErr as _, bail, eyre};
 
-use crate::github::GitHubBranch;
+use crate::{
+    github::GitHubBranch,
+    message::{
+        CustomSection, MessageSection, MetadataFormat, message_section_by_label,
+    },
+};
+
+/// The code hosting service that the repository lives on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Forge {
+    /// github.com, accessed through its GraphQL and REST APIs
+    GitHub,
+    /// A Forgejo (or Gitea) instance at the given URL, accessed through its
+    /// GitHub-like REST API
+    Forgejo { url: String },
+}
+
+impl Forge {
+    pub fn from_name(name: &str, url: Option<String>) -> Result<Self> {
+        match &name.to_ascii_lowercase()[..] {
+            "github" => Ok(Forge::GitHub),
+            "forgejo" | "gitea" => match url {
+                Some(url) if !url.trim().is_empty() => Ok(Forge::Forgejo {
+                    url: url.trim().trim_end_matches('/').to_string(),
+                }),
+                _ => bail!(
+                    "Forge '{}' requires spr.forgeUrl to be set to the web \
+                     address of the server",
+                    name
+                ),
+            },
+            _ => bail!(
+                "Unknown forge '{}', must be either 'github' or 'forgejo'",
+                name
+            ),
+        }
+    }
+
+    /// The web address of the forge, without trailing slash
+    pub fn web_url(&self) -> &str {
+        match self {
+            Forge::GitHub => "https://github.com",
+            Forge::Forgejo { url } => url,
+        }
+    }
+
+    /// The base address for REST API requests, if it is not GitHub's
+    pub fn api_url(&self) -> Option<String> {
+        match self {
+            Forge::GitHub => None,
+            Forge::Forgejo { url } => Some(format!("{}/api/v1", url)),
+        }
+    }
+
+    /// The path component used in the web address of a pull request
+    fn pull_request_path(&self) -> &'static str {
+        match self {
+            Forge::GitHub => "pull",
+            Forge::Forgejo { .. } => "pulls",
+        }
+    }
+}
+
+/// Name of the file in the root of the repository in which a team can share
+/// spr configuration
+pub const REPOSITORY_CONFIG_FILE: &str = ".spr.toml";
+
+/// Configuration values given on the command line. These take precedence over
+/// values from any configuration file.
+#[derive(Debug, Default)]
+pub struct ConfigOverrides {
+    pub github_repository: Option<String>,
+    pub github_master_branch: Option<String>,
+    pub branch_prefix: Option<String>,
+    pub github_auth_token: Option<String>,
+    pub forge: Option<String>,
+    pub forge_url: Option<String>,
+}
+
+/// The places spr configuration is read from (apart from the command line),
+/// in order of precedence: the user's Git configuration (`spr.*` keys), then
+/// the repository's `.spr.toml` file, as committed in HEAD.
+pub struct ConfigSources {
+    git_config: git2::Config,
+    repository_file: toml::Table,
+}
+
+impl ConfigSources {
+    pub fn new(git_config: git2::Config, repository_file: toml::Table) -> Self {
+        Self {
+            git_config,
+            repository_file,
+        }
+    }
+
+    /// Read the configuration of the given repository. The repository file
+    /// is taken from the tree of HEAD, so that uncommitted changes to it (or
+    /// a version from another worktree) do not change what spr does.
+    pub fn from_repository(repo: &git2::Repository) -> Result<Self> {
+        let head_tree = match repo.head() {
+            Ok(head) => Some(head.peel_to_tree()?),
+            Err(err) if err.code() == git2::ErrorCode::UnbornBranch => None,
+            Err(err) => return Err(err.into()),
+        };
+        let entry = head_tree
+            .as_ref()
+            .and_then(|tree| tree.get_name(REPOSITORY_CONFIG_FILE));
+        let repository_file = match entry {
+            Some(entry) => {
+                let blob = entry.to_object(repo)?.peel_to_blob()?;
+                parse_repository_file(&String::from_utf8_lossy(blob.content()))
+                    .wrap_err_with(|| {
+                        format!(
+                            "Could not read {} in HEAD",
+                            REPOSITORY_CONFIG_FILE
+                        )
+                    })?
+            }
+            None => toml::Table::new(),
+        };
+
+        Ok(Self::new(repo.config()?, repository_file))
+    }
+
+    pub fn get_string(&self, key: &str) -> Result<Option<String>> {
+        match self.git_config.get_string(&format!("spr.{}", key)) {
+            Ok(value) => return Ok(Some(value)),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        match self.repository_file.get(key) {
+            None => Ok(None),
+            Some(toml::Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => {
+                bail!("{} in {} must be a string", key, REPOSITORY_CONFIG_FILE)
+            }
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.git_config.get_bool(&format!("spr.{}", key)) {
+            Ok(value) => return Ok(Some(value)),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        match self.repository_file.get(key) {
+            None => Ok(None),
+            Some(toml::Value::Boolean(value)) => Ok(Some(*value)),
+            Some(_) => {
+                bail!("{} in {} must be a boolean", key, REPOSITORY_CONFIG_FILE)
+            }
+        }
+    }
+
+    /// The commit message sections declared as `[[sections]]` in the
+    /// repository file or as `spr.section.<label>.*` in Git configuration.
+    /// For a section declared in both places, the values from Git
+    /// configuration take precedence.
+    pub fn get_custom_sections(&self) -> Result<Vec<CustomSection>> {
+        let mut definitions = Vec::<SectionDefinition>::new();
+
+        match self.repository_file.get("sections") {
+            None => (),
+            Some(toml::Value::Array(array)) => {
+                for value in array {
+                    definitions.push(parse_section_table(value)?);
+                }
+            }
+            Some(_) => bail!(
+                "sections in {} must be an array of tables",
+                REPOSITORY_CONFIG_FILE
+            ),
+        }
+
+        let mut entries =
+            self.git_config.entries(Some(r"^spr\.section\..+\..+$"))?;
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            let (Some(name), Some(value)) = (entry.name(), entry.value())
+            else {
+                continue;
+            };
+            let Some((label, key)) =
+                name.trim_start_matches("spr.section.").rsplit_once('.')
+            else {
+                continue;
+            };
+
+            let index = match definitions
+                .iter()
+                .position(|d| d.label.eq_ignore_ascii_case(label))
+            {
+                Some(index) => index,
+                None => {
+                    definitions.push(SectionDefinition {
+                        label: label.to_string(),
+                        ..Default::default()
+                    });
+                    definitions.len() - 1
+                }
+            };
+            let definition = &mut definitions[index];
+
+            match key {
+                "alias" => definition.aliases.push(value.to_string()),
+                "required" => {
+                    definition.required = Some(git2::Config::parse_bool(value)?)
+                }
+                "githubbody" => {
+                    definition.github_body =
+                        Some(git2::Config::parse_bool(value)?)
+                }
+                "after" => definition.after = Some(value.to_string()),
+                _ => bail!("Unknown configuration key {}", name),
+            }
+        }
+
+        let mut sections = Vec::<CustomSection>::new();
+        for definition in definitions {
+            for label in
+                std::iter::once(&definition.label).chain(&definition.aliases)
+            {
+                if !lazy_regex::regex_is_match!(r#"^\w[\w\s]*$"#, label) {
+                    bail!(
+                        "Invalid commit message section label '{}', must \
+                         consist of letters, digits and spaces",
+                        label
+                    );
+                }
+                if message_section_by_label(label, &sections).is_some() {
+                    bail!(
+                        "Commit message section label '{}' is already in use",
+                        label
+                    );
+                }
+            }
+
+            let after = match &definition.after {
+                None => MessageSection::TestPlan,
+                Some(after) => message_section_by_label(after, &sections)
+                    .ok_or_else(|| {
+                        eyre!(
+                            "Commit message section '{}' is to be placed \
+                             after unknown section '{}'",
+                            definition.label,
+                            after
+                        )
+                    })?,
+            };
+
+            sections.push(CustomSection {
+                aliases: definition.aliases,
+                required: definition.required.unwrap_or(false),
+                in_github_body: definition.github_body.unwrap_or(true),
+                after,
+                ..CustomSection::new(definition.label)
+            });
+        }
+
+        Ok(sections)
+    }
+}
+
+/// A commit message section as declared in a configuration file, before
+/// validation
+#[derive(Debug, Default)]
+struct SectionDefinition {
+    label: String,
+    aliases: Vec<String>,
+    required: Option<bool>,
+    github_body: Option<bool>,
+    after: Option<String>,
+}
+
+fn parse_section_table(value: &toml::Value) -> Result<SectionDefinition> {
+    let Some(table) = value.as_table() else {
+        bail!(
+            "sections in {} must be an array of tables",
+            REPOSITORY_CONFIG_FILE
+        );
+    };
+    let wrong_type = |key: &str, expected: &str| {
+        eyre!(
+            "{} of a section in {} must be {}",
+            key,
+            REPOSITORY_CONFIG_FILE,
+            expected
+        )
+    };
+
+    let mut definition = SectionDefinition::default();
+    for (key, value) in table {
+        match &key[..] {
+            "label" => {
+                definition.label = value
+                    .as_str()
+                    .ok_or_else(|| wrong_type(key, "a string"))?
+                    .to_string()
+            }
+            "aliases" => {
+                definition.aliases = value
+                    .as_array()
+                    .and_then(|array| {
+                        array
+                            .iter()
+                            .map(|alias| alias.as_str().map(String::from))
+                            .collect()
+                    })
+                    .ok_or_else(|| wrong_type(key, "an array of strings"))?
+            }
+            "required" => {
+                definition.required = Some(
+                    value
+                        .as_bool()
+                        .ok_or_else(|| wrong_type(key, "a boolean"))?,
+                )
+            }
+            "githubBody" => {
+                definition.github_body = Some(
+                    value
+                        .as_bool()
+                        .ok_or_else(|| wrong_type(key, "a boolean"))?,
+                )
+            }
+            "after" => {
+                definition.after = Some(
+                    value
+                        .as_str()
+                        .ok_or_else(|| wrong_type(key, "a string"))?
+                        .to_string(),
+                )
+            }
+            _ => bail!(
+                "Unknown key {} of a section in {}",
+                key,
+                REPOSITORY_CONFIG_FILE
+            ),
+        }
+    }
+
+    if definition.label.is_empty() {
+        bail!("Every section in {} needs a label", REPOSITORY_CONFIG_FILE);
+    }
+
+    Ok(definition)
+}
+
+fn parse_repository_file(text: &str) -> Result<toml::Table> {
+    let table: toml::Table = text.parse()?;
+
+    // The file is committed to the repository, so it must not be used to
+    // store secrets.
+    if table.contains_key("githubAuthToken") {
+        bail!(
+            "githubAuthToken must not be set in {}, use git config instead",
+            REPOSITORY_CONFIG_FILE
+        );
+    }
+
+    Ok(table)
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -18,6 +380,13 @@ pub struct Config {
     pub auth_token: String,
     pub require_approval: bool,
     pub require_test_plan: bool,
+    pub forge: Forge,
+    pub custom_sections: Vec<CustomSection>,
+    pub use_pull_request_template: bool,
+    pub pull_request_template: Option<String>,
+    pub metadata_format: MetadataFormat,
+    pub change_id: bool,
+    pub auto_stash: bool,
 }
 
 impl Config {
@@ -30,6 +399,7 @@ impl Config {
         auth_token: String,
         require_approval: bool,
         require_test_plan: bool,
+        forge: Forge,
     ) -> Self {
         let master_ref =
             GitHubBranch::new_from_branch_name(&master_branch, &master_branch);
@@ -41,14 +411,137 @@ impl Config {
             auth_token,
             require_approval,
             require_test_plan,
+            forge,
+            custom_sections: Vec::new(),
+            use_pull_request_template: true,
+            pull_request_template: None,
+            metadata_format: MetadataFormat::Sections,
+            change_id: false,
+            auto_stash: false,
         }
     }
 
+    /// Determine the configuration from command line values, the user's Git
+    /// configuration, the repository's `.spr.toml` and default values, in
+    /// that order of precedence.
+    pub fn from_sources(
+        overrides: ConfigOverrides,
+        sources: &ConfigSources,
+    ) -> Result<Self> {
+        let github_repository = match overrides.github_repository {
+            Some(v) => v,
+            None => {
+                sources.get_string("githubRepository")?.ok_or_else(|| {
+                    eyre!(
+                        "spr.githubRepository is not configured. Please run \
+                     `spr init`."
+                    )
+                })?
+            }
+        };
+
+        let github_master_branch = match overrides.github_master_branch {
+            Some(v) => v,
+            None => sources
+                .get_string("githubMasterBranch")?
+                .unwrap_or_else(|| "master".to_string()),
+        };
+
+        let branch_prefix = match overrides.branch_prefix {
+            Some(v) => v,
+            None => sources.get_string("branchPrefix")?.ok_or_else(|| {
+                eyre!(
+                    "spr.branchPrefix is not configured. Please run `spr init`."
+                )
+            })?,
+        };
+
+        let (owner, repo) = {
+            let captures = lazy_regex::regex!(r#"^([\w\-\.]+)/([\w\-\.]+)$"#)
+                .captures(&github_repository)
+                .ok_or_else(|| {
+                    eyre!(
+                        "GitHub repository must be given as 'OWNER/REPO', but given value was '{}'",
+                        &github_repository,
+                    )
+                })?;
+            (
+                captures.get(1).unwrap().as_str().to_string(),
+                captures.get(2).unwrap().as_str().to_string(),
+            )
+        };
+
+        let require_approval =
+            sources.get_bool("requireApproval")?.unwrap_or(false);
+        let require_test_plan =
+            sources.get_bool("requireTestPlan")?.unwrap_or(true);
+
+        let auth_token = match overrides.github_auth_token {
+            Some(v) => v,
+            None => {
+                sources.get_string("githubAuthToken")?.ok_or_else(|| {
+                    eyre!(
+                        "spr.githubAuthToken is not configured. Please run \
+                     `spr init`."
+                    )
+                })?
+            }
+        };
+
+        let forge = Forge::from_name(
+            &match overrides.forge {
+                Some(v) => v,
+                None => sources
+                    .get_string("forge")?
+                    .unwrap_or_else(|| "github".to_string()),
+            },
+            match overrides.forge_url {
+                Some(v) => Some(v),
+                None => sources.get_string("forgeUrl")?,
+            },
+        )?;
+
+        Ok(Self {
+            custom_sections: sources.get_custom_sections()?,
+            use_pull_request_template: sources
+                .get_bool("usePullRequestTemplate")?
+                .unwrap_or(true),
+            pull_request_template: sources.get_string("pullRequestTemplate")?,
+            metadata_format: match sources.get_string("metadataFormat")? {
+                Some(name) => MetadataFormat::from_name(&name)?,
+                None => MetadataFormat::Sections,
+            },
+            change_id: sources.get_bool("changeId")?.unwrap_or(false),
+            auto_stash: sources.get_bool("autoStash")?.unwrap_or(false),
+            ..Self::new(
+                owner,
+                repo,
+                github_master_branch,
+                branch_prefix,
+                auth_token,
+                require_approval,
+                require_test_plan,
+                forge,
+            )
+        })
+    }
+
     pub fn pull_request_url(&self, number: u64) -> String {
         format!(
-            "https://github.com/{owner}/{repo}/pull/{number}",
+            "{base}/{owner}/{repo}/{path}/{number}",
+            base = self.forge.web_url(),
             owner = &self.owner,
-            repo = &self.repo
+            repo = &self.repo,
+            path = self.forge.pull_request_path(),
+        )
+    }
+
+    pub fn git_url(&self) -> String {
+        format!(
+            "{base}/{owner}/{repo}.git",
+            base = self.forge.web_url(),
+            owner = &self.owner,
+            repo = &self.repo,
         )
     }
 
@@ -63,15 +556,21 @@ impl Config {
             return Some(caps.get(1).unwrap().as_str().parse().unwrap());
         }
 
+        // The address of the forge may contain a path (e.g. for a Forgejo
+        // instance served under `https://example.com/git`), so we match any
+        // prefix here and compare it (ignoring the scheme) afterwards.
         let regex = lazy_regex::regex!(
-            r#"^\s*https?://github.com/([\w\-\.]+)/([\w\-\.]+)/pull/(\d+)([/?#].*)?\s*$"#
+            r#"^\s*https?://(\S+?)/([\w\-\.]+)/([\w\-\.]+)/(pulls?)/(\d+)([/?#].*)?\s*$"#
         );
         let m = regex.captures(text);
         if let Some(caps) = m
-            && self.owner == caps.get(1).unwrap().as_str()
-            && self.repo == caps.get(2).unwrap().as_str()
+            && strip_scheme(self.forge.web_url())
+                == caps.get(1).unwrap().as_str()
+            && self.owner == caps.get(2).unwrap().as_str()
+            && self.repo == caps.get(3).unwrap().as_str()
+            && self.forge.pull_request_path() == caps.get(4).unwrap().as_str()
         {
-            return Some(caps.get(3).unwrap().as_str().parse().unwrap());
+            return Some(caps.get(5).unwrap().as_str().parse().unwrap());
         }
 
         None
@@ -92,6 +591,10 @@ impl Config {
     }
 }
 
+fn strip_scheme(url: &str) -> &str {
+    url.split_once("://").map(|(_, rest)| rest).unwrap_or(url)
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -106,6 +609,21 @@ mod tests {
             "xyz".into(),
             false,
             true,
+            Forge::GitHub,
+        )
+    }
+
+    fn forgejo_config_factory() -> Config {
+        crate::config::Config::new(
+            "acme".into(),
+            "codez".into(),
+            "main".into(),
+            "spr/foo/".into(),
+            "xyz".into(),
+            false,
+            true,
+            Forge::from_name("forgejo", Some("https://git.acme.org/".into()))
+                .unwrap(),
         )
     }
 
@@ -179,4 +697,266 @@ mod tests {
             Some(123)
         );
     }
+
+    #[test]
+    fn test_parse_pull_request_field_url_other_repo() {
+        let gh = config_factory();
+
+        assert_eq!(
+            gh.parse_pull_request_field(
+                "https://github.com/acme/other/pull/123"
+            ),
+            None
+        );
+        assert_eq!(
+            gh.parse_pull_request_field(
+                "https://github.com/acme/codez/pulls/123"
+            ),
+            None
+        );
+        assert_eq!(
+            gh.parse_pull_request_field(
+                "https://git.acme.org/acme/codez/pull/123"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_forge_from_name() {
+        assert_eq!(Forge::from_name("github", None).unwrap(), Forge::GitHub);
+        assert_eq!(
+            Forge::from_name("Forgejo", Some("https://a.b/c/".into())).unwrap(),
+            Forge::Forgejo {
+                url: "https://a.b/c".into()
+            }
+        );
+        assert!(Forge::from_name("forgejo", None).is_err());
+        assert!(Forge::from_name("gitlab", None).is_err());
+    }
+
+    #[test]
+    fn test_forgejo_pull_request_url() {
+        let gh = forgejo_config_factory();
+
+        assert_eq!(
+            &gh.pull_request_url(123),
+            "https://git.acme.org/acme/codez/pulls/123"
+        );
+        assert_eq!(&gh.git_url(), "https://git.acme.org/acme/codez.git");
+    }
+
+    #[test]
+    fn test_forgejo_parse_pull_request_field_url() {
+        let gh = forgejo_config_factory();
+
+        assert_eq!(gh.parse_pull_request_field("#123"), Some(123));
+        assert_eq!(
+            gh.parse_pull_request_field(
+                "https://git.acme.org/acme/codez/pulls/123"
+            ),
+            Some(123)
+        );
+        assert_eq!(
+            gh.parse_pull_request_field(
+                "http://git.acme.org/acme/codez/pulls/123/files"
+            ),
+            Some(123)
+        );
+        assert_eq!(
+            gh.parse_pull_request_field(
+                "https://github.com/acme/codez/pull/123"
+            ),
+            None
+        );
+    }
+
+    fn sources_factory(
+        git_config: &str,
+        repository_file: &str,
+    ) -> ConfigSources {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        std::fs::write(&path, git_config).unwrap();
+        let mut config = git2::Config::new().unwrap();
+        config
+            .add_file(&path, git2::ConfigLevel::Local, false)
+            .unwrap();
+
+        ConfigSources::new(
+            config.snapshot().unwrap(),
+            parse_repository_file(repository_file).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_from_sources_precedence() {
+        let sources = sources_factory(
+            indoc::indoc! {"
+                [spr]
+                    githubRepository = acme/codez
+                    githubAuthToken = xyz
+                    branchPrefix = spr/me/
+                    requireTestPlan = true
+            "},
+            indoc::indoc! {r#"
+                githubRepository = "acme/ignored"
+                githubMasterBranch = "main"
+                branchPrefix = "spr/team/"
+                requireApproval = true
+                requireTestPlan = false
+            "#},
+        );
+
+        let config = Config::from_sources(
+            ConfigOverrides {
+                branch_prefix: Some("spr/cli/".into()),
+                ..Default::default()
+            },
+            &sources,
+        )
+        .unwrap();
+
+        assert_eq!(config.owner, "acme");
+        assert_eq!(config.repo, "codez");
+        assert_eq!(config.master_ref.branch_name(), "main");
+        assert_eq!(config.branch_prefix, "spr/cli/");
+        assert_eq!(config.auth_token, "xyz");
+        assert!(config.require_approval);
+        assert!(config.require_test_plan);
+        assert_eq!(config.forge, Forge::GitHub);
+    }
+
+    #[test]
+    fn test_from_sources_defaults() {
+        let sources = sources_factory(
+            indoc::indoc! {"
+                [spr]
+                    githubRepository = acme/codez
+                    githubAuthToken = xyz
+                    branchPrefix = spr/me/
+            "},
+            "",
+        );
+
+        let config =
+            Config::from_sources(Default::default(), &sources).unwrap();
+
+        assert_eq!(config.master_ref.branch_name(), "master");
+        assert!(!config.require_approval);
+        assert!(config.require_test_plan);
+    }
+
+    #[test]
+    fn test_repository_file_errors() {
+        assert!(parse_repository_file("githubAuthToken = \"xyz\"").is_err());
+        assert!(parse_repository_file("not toml").is_err());
+
+        let sources = sources_factory("", "requireApproval = \"yes\"");
+        assert!(sources.get_bool("requireApproval").is_err());
+
+        // An invalid value in Git configuration is an error, too, rather
+        // than a reason to look into the repository file
+        let sources = sources_factory(
+            "[spr]\n    requireApproval = maybe\n",
+            "requireApproval = true",
+        );
+        assert!(sources.get_bool("requireApproval").is_err());
+    }
+
+    #[test]
+    fn test_repository_file_from_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let commit_file = |text: &str| {
+            let blob = repo.blob(text.as_bytes()).unwrap();
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert(REPOSITORY_CONFIG_FILE, blob, 0o100644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            let signature = git2::Signature::now("spr", "spr@test").unwrap();
+            repo.commit(Some("HEAD"), &signature, &signature, "", &tree, &[])
+                .unwrap();
+        };
+        let write_file = |text: &str| {
+            std::fs::write(dir.path().join(REPOSITORY_CONFIG_FILE), text)
+                .unwrap();
+        };
+
+        // Without a commit, there is no repository file
+        write_file("branchPrefix = \"spr/uncommitted/\"");
+        let sources = ConfigSources::from_repository(&repo).unwrap();
+        assert_eq!(sources.get_string("branchPrefix").unwrap(), None);
+
+        // Only the committed version counts
+        commit_file("branchPrefix = \"spr/team/\"");
+        let sources = ConfigSources::from_repository(&repo).unwrap();
+        assert_eq!(
+            sources.get_string("branchPrefix").unwrap(),
+            Some("spr/team/".to_string())
+        );
+    }
+
+    #[test]
+    fn test_custom_sections() {
+        let sources = sources_factory(
+            indoc::indoc! {r#"
+                [spr "section.Jira"]
+                    alias = Ticket
+                    required = true
+                [spr "section.Rollout"]
+                    after = risk
+            "#},
+            indoc::indoc! {r#"
+                [[sections]]
+                label = "Jira"
+                githubBody = false
+                after = "Pull Request"
+
+                [[sections]]
+                label = "Risk"
+                aliases = ["Risks"]
+            "#},
+        );
+
+        assert_eq!(
+            sources.get_custom_sections().unwrap(),
+            vec![
+                CustomSection {
+                    aliases: vec!["Ticket".into()],
+                    required: true,
+                    in_github_body: false,
+                    after: MessageSection::PullRequest,
+                    ..CustomSection::new("Jira".into())
+                },
+                CustomSection {
+                    aliases: vec!["Risks".into()],
+                    ..CustomSection::new("Risk".into())
+                },
+                CustomSection {
+                    after: MessageSection::Custom("Risk".into()),
+                    ..CustomSection::new("Rollout".into())
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_custom_sections_errors() {
+        for repository_file in [
+            "sections = \"Risk\"",
+            "[[sections]]\naliases = [\"Risks\"]",
+            "[[sections]]\nlabel = \"Risk\"\nrequired = \"yes\"",
+            "[[sections]]\nlabel = \"Risk\"\ncolour = \"red\"",
+            "[[sections]]\nlabel = \"Risk: high\"",
+            "[[sections]]\nlabel = \"Test Plan\"",
+            "[[sections]]\nlabel = \"Risk\"\nafter = \"Rollout\"",
+        ] {
+            let sources = sources_factory("", repository_file);
+            assert!(
+                sources.get_custom_sections().is_err(),
+                "{}",
+                repository_file
+            );
+        }
+    }
 }
@@ -0,0 +1,587 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Access to Pull Requests on a Forgejo (or Gitea) instance. Forgejo has a
+//! REST API that closely follows GitHub's, but no GraphQL API, so the
+//! queries that we run through GraphQL on GitHub are made with REST calls
+//! here, and translated into the same data structures.
+
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::eyre::{Result, eyre};
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    github::{
+        HeadRepository, ListedPullRequest, OpenPullRequest, PullRequestData,
+        PullRequestMergeability, PullRequestState, ReviewEvent, ReviewRequest,
+        ReviewStatus, Team,
+    },
+};
+
+#[derive(Deserialize, Debug)]
+struct ApiPullRequest {
+    number: u64,
+    state: String,
+    title: String,
+    #[serde(default)]
+    body: Option<String>,
+    base: ApiBranch,
+    head: ApiBranch,
+    #[serde(default)]
+    merged: bool,
+    #[serde(default)]
+    merge_commit_sha: Option<String>,
+    #[serde(default)]
+    mergeable: bool,
+    #[serde(default)]
+    requested_reviewers: Option<Vec<ApiUser>>,
+    #[serde(default)]
+    requested_reviewers_teams: Option<Vec<ApiTeam>>,
+    #[serde(default)]
+    user: Option<ApiUser>,
+    html_url: String,
+    #[serde(default)]
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiBranch {
+    #[serde(rename = "ref")]
+    ref_name: String,
+    sha: String,
+    /// Missing if the repository was deleted
+    #[serde(default)]
+    repo: Option<ApiRepository>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiRepository {
+    full_name: String,
+    clone_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiUser {
+    login: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiTeam {
+    id: u64,
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiReview {
+    #[serde(default)]
+    user: Option<ApiUser>,
+    state: String,
+    #[serde(default)]
+    dismissed: bool,
+    #[serde(default)]
+    commit_id: Option<String>,
+    #[serde(default)]
+    submitted_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+fn repo_path(config: &Config) -> String {
+    format!("/repos/{}/{}", config.owner, config.repo)
+}
+
+async fn get_api_pull_request(
+    config: &Config,
+    number: u64,
+) -> Result<ApiPullRequest> {
+    Ok(octocrab::instance()
+        .get(
+            format!("{}/pulls/{}", repo_path(config), number),
+            None::<&()>,
+        )
+        .await?)
+}
+
+async fn get_reviews(config: &Config, number: u64) -> Result<Vec<ApiReview>> {
+    Ok(octocrab::instance()
+        .get(
+            format!("{}/pulls/{}/reviews", repo_path(config), number),
+            None::<&()>,
+        )
+        .await?)
+}
+
+/// The latest approving or rejecting review of each reviewer. Reviews are
+/// returned by Forgejo in chronological order.
+fn latest_opinionated_reviews(
+    reviews: &[ApiReview],
+) -> HashMap<String, ReviewStatus> {
+    let mut result = HashMap::new();
+
+    for review in reviews.iter().filter(|r| !r.dismissed) {
+        let status = match &review.state[..] {
+            "APPROVED" => ReviewStatus::Approved,
+            "REQUEST_CHANGES" => ReviewStatus::Rejected,
+            _ => continue,
+        };
+        if let Some(user) = &review.user {
+            result.insert(user.login.clone(), status);
+        }
+    }
+
+    result
+}
+
+/// Forgejo does not compute an overall review decision like GitHub does, so
+/// we derive one from the individual reviews.
+fn review_decision(
+    reviewers: &HashMap<String, ReviewStatus>,
+    has_requested_reviewers: bool,
+) -> Option<ReviewStatus> {
+    if reviewers.values().any(|s| s == &ReviewStatus::Rejected) {
+        Some(ReviewStatus::Rejected)
+    } else if reviewers.values().any(|s| s == &ReviewStatus::Approved) {
+        Some(ReviewStatus::Approved)
+    } else if has_requested_reviewers {
+        Some(ReviewStatus::Requested)
+    } else {
+        None
+    }
+}
+
+fn parse_oid(sha: &Option<String>) -> Option<git2::Oid> {
+    sha.as_deref().and_then(|sha| git2::Oid::from_str(sha).ok())
+}
+
+pub(crate) async fn fetch_pull_request(
+    config: &Config,
+    number: u64,
+) -> Result<PullRequestData> {
+    let pr = get_api_pull_request(config, number).await?;
+    let reviewers =
+        latest_opinionated_reviews(&get_reviews(config, number).await?);
+
+    let requested_reviewers: Vec<String> = pr
+        .requested_reviewers
+        .iter()
+        .flatten()
+        .map(|user| user.login.clone())
+        .chain(
+            pr.requested_reviewers_teams
+                .iter()
+                .flatten()
+                .map(|team| format!("#{}", team.name)),
+        )
+        .collect();
+
+    let review_status =
+        review_decision(&reviewers, !requested_reviewers.is_empty());
+
+    Ok(PullRequestData {
+        number: pr.number,
+        state: if pr.state == "open" {
+            PullRequestState::Open
+        } else {
+            PullRequestState::Closed
+        },
+        title: pr.title,
+        body: pr.body.unwrap_or_default(),
+        base_ref_name: pr.base.ref_name,
+        head_ref_name: pr.head.ref_name,
+        head_repository: match (&pr.head.repo, &pr.base.repo) {
+            (Some(head), Some(base)) if head.full_name == base.full_name => {
+                HeadRepository::Same
+            }
+            (Some(head), _) => HeadRepository::Fork(head.clone_url.clone()),
+            // The fork was deleted
+            (None, Some(_)) => HeadRepository::Deleted,
+            (None, None) => HeadRepository::Same,
+        },
+        merge_commit: if pr.merged {
+            parse_oid(&pr.merge_commit_sha)
+        } else {
+            None
+        },
+        reviewers,
+        requested_reviewers,
+        review_status,
+    })
+}
+
+pub(crate) async fn get_pull_request_mergeability(
+    config: &Config,
+    number: u64,
+) -> Result<PullRequestMergeability> {
+    let pr = get_api_pull_request(config, number).await?;
+
+    Ok(PullRequestMergeability {
+        base: config.new_github_branch_from_ref(&pr.base.ref_name)?,
+        head_oid: git2::Oid::from_str(&pr.head.sha)?,
+        mergeable: Some(pr.mergeable),
+        merge_commit: if pr.merged {
+            parse_oid(&pr.merge_commit_sha)
+        } else {
+            None
+        },
+    })
+}
+
+pub(crate) async fn merge_pull_request(
+    config: &Config,
+    number: u64,
+    title: String,
+    message: String,
+    head_oid: git2::Oid,
+) -> Result<Option<git2::Oid>> {
+    #[derive(serde::Serialize)]
+    struct MergePullRequest {
+        #[serde(rename = "Do")]
+        merge_style: &'static str,
+        #[serde(rename = "MergeTitleField")]
+        title: String,
+        #[serde(rename = "MergeMessageField")]
+        message: String,
+        head_commit_id: String,
+    }
+
+    // Forgejo responds with an empty body, so we cannot use one of octocrab's
+    // deserialising methods here.
+    let response = octocrab::instance()
+        ._post(
+            format!("{}/pulls/{}/merge", repo_path(config), number),
+            Some(&MergePullRequest {
+                merge_style: "squash",
+                title,
+                message,
+                head_commit_id: head_oid.to_string(),
+            }),
+        )
+        .await?;
+    octocrab::map_github_error(response).await?;
+
+    let pr = get_api_pull_request(config, number).await?;
+    if !pr.merged {
+        return Err(eyre!("Forgejo Pull Request merge failed"));
+    }
+
+    Ok(parse_oid(&pr.merge_commit_sha))
+}
+
+pub(crate) async fn submit_review(
+    config: &Config,
+    number: u64,
+    event: ReviewEvent,
+    body: String,
+    commit_oid: git2::Oid,
+) -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct CreateReview {
+        commit_id: String,
+        body: String,
+        event: &'static str,
+    }
+    #[derive(Deserialize)]
+    struct Ignore {}
+
+    // Forgejo calls the approving event differently from GitHub
+    let event = match event {
+        ReviewEvent::Approve => "APPROVED",
+        ReviewEvent::RequestChanges => "REQUEST_CHANGES",
+        ReviewEvent::Comment => "COMMENT",
+    };
+    let _: Ignore = octocrab::instance()
+        .post(
+            format!("{}/pulls/{}/reviews", repo_path(config), number),
+            Some(&CreateReview {
+                commit_id: commit_oid.to_string(),
+                body,
+                event,
+            }),
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn get_team(config: &Config, slug: &str) -> Result<Team> {
+    #[derive(Deserialize)]
+    struct TeamSearch {
+        #[serde(default)]
+        data: Vec<ApiTeam>,
+    }
+
+    let search: TeamSearch = octocrab::instance()
+        .get(
+            format!("/orgs/{}/teams/search", config.owner),
+            Some(&[("q", slug)]),
+        )
+        .await?;
+
+    search
+        .data
+        .into_iter()
+        .find(|team| team.name.eq_ignore_ascii_case(slug))
+        .map(|team| Team { slug: team.name })
+        .ok_or_else(|| eyre!("Team '{}' not found", slug))
+}
+
+/// All open Pull Requests in the repository, as Forgejo lists them
+async fn list_open_pull_requests(
+    config: &Config,
+) -> Result<Vec<ApiPullRequest>> {
+    const LIMIT: usize = 50;
+
+    let mut result = Vec::new();
+    for page in 1.. {
+        let pull_requests: Vec<ApiPullRequest> = octocrab::instance()
+            .get(
+                format!("{}/pulls", repo_path(config)),
+                Some(&[
+                    ("state", "open".to_string()),
+                    ("limit", LIMIT.to_string()),
+                    ("page", page.to_string()),
+                ]),
+            )
+            .await?;
+        let last_page = pull_requests.len() < LIMIT;
+        result.extend(pull_requests);
+        if last_page {
+            break;
+        }
+    }
+
+    Ok(result)
+}
+
+pub(crate) async fn get_open_pull_requests(
+    config: &Config,
+) -> Result<Vec<OpenPullRequest>> {
+    list_open_pull_requests(config)
+        .await?
+        .into_iter()
+        .map(|pr| {
+            Ok(OpenPullRequest {
+                number: pr.number,
+                title: pr.title,
+                body: pr.body.unwrap_or_default(),
+                base_ref_name: pr.base.ref_name,
+                head_ref_name: pr.head.ref_name,
+                head_oid: git2::Oid::from_str(&pr.head.sha)?,
+                author: pr
+                    .user
+                    .map(|u| u.login)
+                    .unwrap_or_else(|| "ghost".to_string()),
+            })
+        })
+        .collect()
+}
+
+/// The open Pull Requests that wait for a review by the current user, either
+/// requested from them directly or from one of their teams
+pub(crate) async fn get_review_requests(
+    config: &Config,
+) -> Result<Vec<ReviewRequest>> {
+    // Like the search on GitHub, report at most this many Pull Requests
+    const MAX_REVIEW_REQUESTS: usize = 100;
+
+    let viewer: ApiUser =
+        octocrab::instance().get("/user", None::<&()>).await?;
+    let teams: Vec<ApiTeam> =
+        octocrab::instance().get("/user/teams", None::<&()>).await?;
+    let team_ids: HashSet<u64> = teams.iter().map(|team| team.id).collect();
+
+    // Forgejo cannot filter the list of Pull Requests by review request, so
+    // we do that here, and only fetch the reviews of the ones that match
+    let requested: Vec<_> = list_open_pull_requests(config)
+        .await?
+        .into_iter()
+        .filter(|pr| {
+            pr.requested_reviewers
+                .iter()
+                .flatten()
+                .any(|user| user.login == viewer.login)
+                || pr
+                    .requested_reviewers_teams
+                    .iter()
+                    .flatten()
+                    .any(|team| team_ids.contains(&team.id))
+        })
+        .take(MAX_REVIEW_REQUESTS)
+        .collect();
+    let reviews = futures::future::try_join_all(
+        requested.iter().map(|pr| get_reviews(config, pr.number)),
+    )
+    .await?;
+
+    let mut result = Vec::new();
+    for (pr, reviews) in requested.into_iter().zip(reviews) {
+        // Forgejo records review requests as reviews, too, and returns
+        // reviews in chronological order
+        let requested_at = reviews
+            .iter()
+            .rev()
+            .find(|review| review.state == "REQUEST_REVIEW")
+            .and_then(|review| review.submitted_at)
+            .or(pr.created_at);
+        let reviewed_oid = reviews
+            .iter()
+            .rev()
+            .filter(|review| {
+                !matches!(&review.state[..], "REQUEST_REVIEW" | "PENDING")
+            })
+            .find(|review| {
+                review.user.as_ref().map(|u| &u.login) == Some(&viewer.login)
+            })
+            .and_then(|review| parse_oid(&review.commit_id));
+
+        result.push(ReviewRequest {
+            number: pr.number,
+            title: pr.title,
+            url: pr.html_url,
+            author: pr
+                .user
+                .map(|u| u.login)
+                .unwrap_or_else(|| "ghost".to_string()),
+            requested_at,
+            head_oid: git2::Oid::from_str(&pr.head.sha)?,
+            reviewed_oid,
+        });
+    }
+
+    Ok(result)
+}
+
+/// The open Pull Requests in the repository authored by the current user
+pub(crate) async fn get_open_pull_requests_by_viewer(
+    config: &Config,
+) -> Result<Vec<ListedPullRequest>> {
+    let viewer: ApiUser =
+        octocrab::instance().get("/user", None::<&()>).await?;
+
+    let pull_requests: Vec<_> = list_open_pull_requests(config)
+        .await?
+        .into_iter()
+        .filter(|pr| pr.user.as_ref().map(|u| &u.login) == Some(&viewer.login))
+        .collect();
+    let reviews = futures::future::try_join_all(
+        pull_requests
+            .iter()
+            .map(|pr| get_reviews(config, pr.number)),
+    )
+    .await?;
+
+    Ok(pull_requests
+        .into_iter()
+        .zip(reviews)
+        .map(|(pr, reviews)| {
+            let has_requested_reviewers = pr
+                .requested_reviewers
+                .as_ref()
+                .is_some_and(|r| !r.is_empty());
+
+            ListedPullRequest {
+                title: pr.title,
+                url: pr.html_url,
+                review_status: review_decision(
+                    &latest_opinionated_reviews(&reviews),
+                    has_requested_reviewers,
+                ),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn review(login: &str, state: &str, dismissed: bool) -> ApiReview {
+        ApiReview {
+            user: Some(ApiUser {
+                login: login.into(),
+            }),
+            state: state.into(),
+            dismissed,
+            commit_id: None,
+            submitted_at: None,
+        }
+    }
+
+    #[test]
+    fn test_latest_opinionated_reviews() {
+        let reviews = vec![
+            review("alice", "REQUEST_CHANGES", false),
+            review("bob", "COMMENT", false),
+            review("alice", "APPROVED", false),
+            review("carol", "APPROVED", true),
+            review("alice", "COMMENT", false),
+        ];
+
+        assert_eq!(
+            latest_opinionated_reviews(&reviews),
+            [("alice".to_string(), ReviewStatus::Approved)].into()
+        );
+    }
+
+    #[test]
+    fn test_review_decision() {
+        let approved: HashMap<_, _> =
+            [("alice".to_string(), ReviewStatus::Approved)].into();
+        let mixed: HashMap<_, _> = [
+            ("alice".to_string(), ReviewStatus::Approved),
+            ("bob".to_string(), ReviewStatus::Rejected),
+        ]
+        .into();
+
+        assert_eq!(review_decision(&HashMap::new(), false), None);
+        assert_eq!(
+            review_decision(&HashMap::new(), true),
+            Some(ReviewStatus::Requested)
+        );
+        assert_eq!(
+            review_decision(&approved, true),
+            Some(ReviewStatus::Approved)
+        );
+        assert_eq!(
+            review_decision(&mixed, false),
+            Some(ReviewStatus::Rejected)
+        );
+    }
+
+    #[test]
+    fn test_parse_pull_request() {
+        let pr: ApiPullRequest = serde_json::from_str(
+            r#"{
+                "id": 7,
+                "number": 12,
+                "state": "closed",
+                "title": "Hello",
+                "body": "World",
+                "base": {"ref": "main", "sha": "0000000000000000000000000000000000000001"},
+                "head": {"ref": "spr/foo/hello", "sha": "0000000000000000000000000000000000000002"},
+                "merged": true,
+                "merge_commit_sha": "0000000000000000000000000000000000000003",
+                "mergeable": false,
+                "requested_reviewers": null,
+                "user": {"login": "alice", "full_name": ""},
+                "html_url": "https://git.acme.org/acme/codez/pulls/12"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(pr.number, 12);
+        assert_eq!(pr.head.ref_name, "spr/foo/hello");
+        assert_eq!(
+            parse_oid(&pr.merge_commit_sha),
+            Some(
+                git2::Oid::from_str("0000000000000000000000000000000000000003")
+                    .unwrap()
+            )
+        );
+    }
+}
@@ -11,20 +11,62 @@ use std::collections::{HashSet, VecDeque};
 use crate::{
     config::Config,
     message::{
-        MessageSection, MessageSectionsMap, build_commit_message, parse_message,
+        MessageSection, MessageSectionsMap, MetadataFormat,
+        build_commit_message, build_metadata_note, parse_message,
+        parse_metadata_note, synced_sections,
     },
 };
 use git2::Oid;
 
+/// Prefix of the refs that record the state in which a commit message and its
+/// Pull Request were last in sync. The refs are named by the Pull Request
+/// number (rather than keyed by commit) so that they survive amending the
+/// commit, and point to a blob with the synced sections.
+const SYNC_REF_PREFIX: &str = "refs/spr/sync/";
+
+/// Prefix of the refs that record which head commit of a Pull Request was
+/// last checked out with `spr patch`, named by the Pull Request number
+const PATCH_REF_PREFIX: &str = "refs/spr/patch/";
+
+/// The notes ref that holds the metadata of commits when using
+/// `MetadataFormat::Notes`
+const NOTES_REF: &str = "refs/notes/spr";
+
+/// The file in the Git directory in which spr records a rebase that stopped
+/// because of conflicts
+const REBASE_STATE_FILE: &str = "spr-rebase";
+
 #[derive(Debug)]
 pub struct PreparedCommit {
     pub oid: Oid,
     pub short_id: String,
     pub parent_oid: Oid,
+    /// The tree of the commit in the stack as spr sees it. This differs from
+    /// the tree of commit `oid` if the commit comes before a merge of master
+    /// that `get_prepared_commits` turned into a rebase.
+    pub tree_oid: Oid,
+    /// The tree of the commit before this one in the stack (or of the commit
+    /// on master the stack is based on)
+    pub parent_tree_oid: Oid,
     pub message: MessageSectionsMap,
     pub pull_request_number: Option<u64>,
 }
 
+/// A rebase that stopped because of conflicts
+struct RebaseState {
+    /// The reference that was checked out when the rebase started, either a
+    /// branch or "HEAD"
+    ref_name: String,
+    /// The commit that was checked out when the rebase started
+    orig_head_oid: Oid,
+    /// The commits still to be rebased, starting with the one that conflicted
+    todo: Vec<Oid>,
+    /// The commits rebased so far, with what they were rewritten into. Notes
+    /// are copied and hooks are run for them only when the rebase completes,
+    /// so that aborting it leaves nothing behind.
+    rewritten: Vec<(Oid, Oid)>,
+}
+
 #[derive(Clone)]
 pub struct Git {
     repo: std::sync::Arc<git2::Repository>,
@@ -50,56 +92,224 @@ impl Git {
         self.hooks.as_ref()
     }
 
+    /// Tell the world that spr has rewritten the given commits: run the
+    /// post-rewrite hook, and carry spr's notes over to the new commits.
+    fn rewritten(&self, mapping: &[(Oid, Oid)]) -> Result<()> {
+        self.hooks()
+            .run_post_rewrite_rebase(self.repo.as_ref(), mapping);
+
+        for &(old_oid, new_oid) in mapping {
+            self.copy_metadata_note(old_oid, new_oid)?;
+        }
+
+        Ok(())
+    }
+
+    fn copy_metadata_note(&self, old_oid: Oid, new_oid: Oid) -> Result<()> {
+        if let Some(note) = self.get_metadata_note(old_oid)? {
+            let signature = self.repo.signature()?;
+            self.repo.note(
+                &signature,
+                &signature,
+                Some(NOTES_REF),
+                new_oid,
+                &note,
+                true,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// The commits between master and HEAD, following only the first parent
+    /// of merge commits
     pub fn get_commit_oids(&self, master_oid: Oid) -> Result<Vec<Oid>> {
         let mut walk = self.repo.revwalk()?;
         walk.set_sorting(git2::Sort::TOPOLOGICAL.union(git2::Sort::REVERSE))?;
+        walk.simplify_first_parent()?;
         walk.push_head()?;
         walk.hide(master_oid)?;
 
         Ok(walk.collect::<std::result::Result<Vec<Oid>, _>>()?)
     }
 
+    /// The commits between master and HEAD, as a linear stack.
+    ///
+    /// A merge commit that brings master into the branch is not part of the
+    /// stack. Instead, it moves the stack's base: the commits before it are
+    /// rebased onto the merged master commit, and the commits after it are
+    /// put on top of those. This only computes the trees of the commits in
+    /// the rebased stack, the local branch gets rewritten if and when spr
+    /// updates commit messages. Until then, the prepared commits refer to the
+    /// commits on the local branch. Merges that cannot be turned into a
+    /// rebase like this, because they bring in other branches or contain
+    /// changes of their own, are an error.
     pub fn get_prepared_commits(
         &self,
         config: &Config,
         master_oid: Oid,
     ) -> Result<Vec<PreparedCommit>> {
-        self.get_commit_oids(master_oid)?
-            .into_iter()
-            .map(|oid| self.prepare_commit(config, oid))
-            .collect()
+        let mut commits = Vec::<PreparedCommit>::new();
+
+        // The master commit a merge moved the stack's base to, if that
+        // happened before the first commit of the stack
+        let mut base_oid: Option<Oid> = None;
+
+        for oid in self.get_commit_oids(master_oid)? {
+            let commit = self.repo.find_commit(oid)?;
+
+            if commit.parent_count() > 1 {
+                let merged_oid = self.linearise_merge(
+                    config,
+                    &commit,
+                    master_oid,
+                    &mut commits,
+                )?;
+                if commits.is_empty() {
+                    base_oid = Some(merged_oid);
+                }
+                continue;
+            }
+
+            let mut prepared_commit = self.prepare_commit(config, oid)?;
+            if let Some(previous) = commits.last() {
+                prepared_commit.parent_oid = previous.oid;
+                prepared_commit.parent_tree_oid = previous.tree_oid;
+            } else if let Some(base_oid) = base_oid {
+                prepared_commit.parent_oid = base_oid;
+                prepared_commit.parent_tree_oid =
+                    self.get_tree_oid_for_commit(base_oid)?;
+            }
+            commits.push(prepared_commit);
+        }
+
+        Ok(commits)
     }
 
+    /// Turn a merge of master into a rebase: rebase the given commits (the
+    /// stack before the merge) onto the merged master commit, and check that
+    /// this gives the same tree as the merge commit. Returns the merged master
+    /// commit.
+    fn linearise_merge(
+        &self,
+        config: &Config,
+        merge_commit: &git2::Commit,
+        master_oid: Oid,
+        commits: &mut [PreparedCommit],
+    ) -> Result<Oid> {
+        let short_id = merge_commit.as_object().short_id()?;
+        let short_id = short_id.as_str().unwrap_or_default();
+        let master = config.master_ref.branch_name();
+
+        if merge_commit.parent_count() != 2 {
+            bail!(
+                "Commit {} merges more than two branches. spr can only deal \
+                 with merge commits that bring {} into your branch. Please \
+                 rebase your branch on {} instead.",
+                short_id,
+                master,
+                master
+            );
+        }
+        let merged_oid = merge_commit.parent_id(1)?;
+        if merged_oid != master_oid
+            && !self.repo.graph_descendant_of(master_oid, merged_oid)?
+        {
+            bail!(
+                "Commit {} merges a branch other than {}. spr can only deal \
+                 with merge commits that bring {} into your branch. Please \
+                 rebase your branch instead.",
+                short_id,
+                master,
+                master
+            );
+        }
+
+        let mut new_parent_tree_oid =
+            self.get_tree_oid_for_commit(merged_oid)?;
+        for (index, commit) in commits.iter_mut().enumerate() {
+            // Like cherry-picking the commit onto its new parent
+            let mut merged_index = self.repo.merge_trees(
+                &self.repo.find_tree(commit.parent_tree_oid)?,
+                &self.repo.find_tree(new_parent_tree_oid)?,
+                &self.repo.find_tree(commit.tree_oid)?,
+                None,
+            )?;
+            if merged_index.has_conflicts() {
+                bail!(
+                    "The commits before merge commit {} conflict with the {} \
+                     commit it merges, so spr cannot turn the merge into a \
+                     rebase. Please rebase your branch on {} instead.",
+                    short_id,
+                    master,
+                    master
+                );
+            }
+
+            if index == 0 {
+                commit.parent_oid = merged_oid;
+            }
+            commit.parent_tree_oid = new_parent_tree_oid;
+            commit.tree_oid = merged_index.write_tree_to(self.repo.as_ref())?;
+            new_parent_tree_oid = commit.tree_oid;
+        }
+
+        if new_parent_tree_oid != merge_commit.tree_id() {
+            bail!(
+                "Merge commit {} contains changes of its own (for example \
+                 resolved conflicts), so spr cannot turn it into a rebase. \
+                 Please rebase your branch on {} instead.",
+                short_id,
+                master
+            );
+        }
+
+        Ok(merged_oid)
+    }
+
+    /// Write the messages of the prepared commits (only of the first `limit`
+    /// ones, if given) into the local branch. If any message changes, the
+    /// commits from there on are recreated, and so are the commits of a
+    /// stack that `get_prepared_commits` linearised.
     pub fn rewrite_commit_messages(
         &self,
+        config: &Config,
         commits: &mut [PreparedCommit],
-        mut limit: Option<usize>,
+        limit: Option<usize>,
     ) -> Result<()> {
         if commits.is_empty() {
             return Ok(());
         }
 
-        let mut parent_oid: Option<Oid> = None;
+        let in_scope = limit.unwrap_or(commits.len());
+        let mut messages = Vec::with_capacity(commits.len());
+        let mut changed = false;
+        for (index, prepared_commit) in commits.iter().enumerate() {
+            let commit = self.repo.find_commit(prepared_commit.oid)?;
+            let message = if index < in_scope {
+                build_commit_message(
+                    &prepared_commit.message,
+                    &config.custom_sections,
+                    config.metadata_format,
+                )
+            } else {
+                String::from_utf8_lossy(commit.message_bytes()).into_owned()
+            };
+            changed |= Some(&message[..]) != commit.message();
+            messages.push(message);
+        }
+
+        let mut parent_oid = commits[0].parent_oid;
         let mut updating = false;
-        let mut message: String;
-        let first_parent = commits[0].parent_oid;
-        let hooks = self.hooks();
 
-        for prepared_commit in commits.iter_mut() {
+        for (index, (prepared_commit, message)) in
+            commits.iter_mut().zip(messages).enumerate()
+        {
             let commit = self.repo.find_commit(prepared_commit.oid)?;
-            if limit != Some(0) {
-                message = build_commit_message(&prepared_commit.message);
-                if Some(&message[..]) != commit.message() {
-                    updating = true;
-                }
-            } else {
-                if !updating {
-                    return Ok(());
-                }
-                message = String::from_utf8_lossy(commit.message_bytes())
-                    .into_owned();
-            }
-            limit = limit.map(|n| if n > 0 { n - 1 } else { 0 });
+            updating |= changed
+                && (Some(&message[..]) != commit.message()
+                    || commit.parent_id(0)? != parent_oid
+                    || commit.tree_id() != prepared_commit.tree_oid);
 
             if updating {
                 let new_oid = self.repo.commit(
@@ -107,27 +317,28 @@ impl Git {
                     &commit.author(),
                     &commit.committer(),
                     &message[..],
-                    &commit.tree()?,
-                    &[&self
-                        .repo
-                        .find_commit(parent_oid.unwrap_or(first_parent))?],
+                    &self.repo.find_tree(prepared_commit.tree_oid)?,
+                    &[&self.repo.find_commit(parent_oid)?],
                 )?;
-                hooks.run_post_rewrite_rebase(
-                    self.repo.as_ref(),
-                    &[(prepared_commit.oid, new_oid)],
-                );
+                self.rewritten(&[(prepared_commit.oid, new_oid)])?;
                 prepared_commit.oid = new_oid;
-                parent_oid = Some(new_oid);
-            } else {
-                parent_oid = Some(prepared_commit.oid);
+            }
+            parent_oid = prepared_commit.oid;
+
+            if index < in_scope {
+                self.update_metadata_note(
+                    config,
+                    prepared_commit.oid,
+                    &prepared_commit.message,
+                )?;
             }
         }
 
-        if updating && let Some(oid) = parent_oid {
+        if updating {
             self.repo
                 .find_reference("HEAD")?
                 .resolve()?
-                .set_target(oid, "spr updated commit messages")?;
+                .set_target(parent_oid, "spr updated commit messages")?;
         }
 
         Ok(())
@@ -136,16 +347,121 @@ impl Git {
     pub fn rebase_commits(
         &self,
         commits: &mut [PreparedCommit],
-        mut new_parent_oid: git2::Oid,
+        new_parent_oid: git2::Oid,
     ) -> Result<()> {
         if commits.is_empty() {
             return Ok(());
         }
-        let hooks = self.hooks();
+        if self.read_rebase_state()?.is_some() {
+            bail!(
+                "A rebase by spr is already in progress. Finish it with `spr \
+                 continue`, or undo it with `spr abort`."
+            );
+        }
+
+        // Get and resolve the HEAD reference. This will be either a reference
+        // to a branch ('refs/heads/...') or 'HEAD' if the head is detached.
+        let reference = self.repo.head()?.resolve()?;
+
+        let state = RebaseState {
+            ref_name: reference
+                .name()
+                .ok_or_else(|| eyre!("Reference name is not valid UTF-8"))?
+                .to_string(),
+            orig_head_oid: self.head()?,
+            todo: commits.iter().map(|pc| pc.oid).collect(),
+            rewritten: Vec::new(),
+        };
+
+        self.run_rebase(state, new_parent_oid)
+    }
+
+    /// Finish a rebase that stopped because of conflicts, after the user
+    /// resolved them
+    pub fn continue_rebase(&self) -> Result<()> {
+        let Some(mut state) = self.read_rebase_state()? else {
+            bail!("There is no rebase by spr in progress");
+        };
+
+        let mut index = self.repo.index()?;
+        if index.has_conflicts() {
+            bail!(
+                "There are still unresolved conflicts. Resolve them and mark \
+                 them as resolved with `git add` first."
+            );
+        }
+        let mut opts = git2::StatusOptions::new();
+        opts.include_ignored(false).include_untracked(false);
+        if self.repo.statuses(Some(&mut opts))?.iter().any(|entry| {
+            entry.status().intersects(
+                git2::Status::WT_MODIFIED
+                    | git2::Status::WT_DELETED
+                    | git2::Status::WT_TYPECHANGE
+                    | git2::Status::WT_RENAMED,
+            )
+        }) {
+            bail!(
+                "There are unstaged changes. Add them with `git add` (or \
+                 discard them) first."
+            );
+        }
+
+        let old_oid = state.todo.remove(0);
+        let commit = self.repo.find_commit(old_oid)?;
+        let mut new_parent_oid = self.head()?;
+        let new_parent_commit = self.repo.find_commit(new_parent_oid)?;
+
+        let tree_oid = index.write_tree()?;
+        // Unless the conflicts were resolved by dropping the commit's
+        // changes, there is a new commit
+        if tree_oid != new_parent_commit.tree_id() {
+            new_parent_oid = self.repo.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                String::from_utf8_lossy(commit.message_bytes()).as_ref(),
+                &self.repo.find_tree(tree_oid)?,
+                &[&new_parent_commit],
+            )?;
+        }
+        state.rewritten.push((old_oid, new_parent_oid));
+        self.repo.cleanup_state()?;
 
-        for prepared_commit in commits.iter_mut() {
+        self.run_rebase(state, new_parent_oid)
+    }
+
+    /// Undo a rebase that stopped because of conflicts, going back to the
+    /// commit that was checked out before
+    pub fn abort_rebase(&self) -> Result<()> {
+        let Some(state) = self.read_rebase_state()? else {
+            bail!("There is no rebase by spr in progress");
+        };
+
+        self.repo.cleanup_state()?;
+        if state.ref_name == "HEAD" {
+            self.repo.set_head_detached(state.orig_head_oid)?;
+        } else {
+            self.repo.set_head(&state.ref_name)?;
+        }
+        let orig_head = self.repo.find_object(state.orig_head_oid, None)?;
+        self.repo.reset(&orig_head, git2::ResetType::Hard, None)?;
+
+        std::fs::remove_file(self.rebase_state_path())?;
+
+        Ok(())
+    }
+
+    /// Rebase the commits that are left to do onto the given commit. If one of
+    /// them does not apply cleanly, leave the conflicts in the index and
+    /// worktree for the user to resolve, and record where we are.
+    fn run_rebase(
+        &self,
+        mut state: RebaseState,
+        mut new_parent_oid: Oid,
+    ) -> Result<()> {
+        while let Some(&oid) = state.todo.first() {
             let new_parent_commit = self.repo.find_commit(new_parent_oid)?;
-            let commit = self.repo.find_commit(prepared_commit.oid)?;
+            let commit = self.repo.find_commit(oid)?;
 
             let mut index = self.repo.cherrypick_commit(
                 &commit,
@@ -154,8 +470,17 @@ impl Git {
                 None,
             )?;
             if index.has_conflicts() {
-                bail!("Rebase failed due to merge conflicts");
+                self.stop_rebase(&state, &new_parent_commit, &commit)?;
+                bail!(
+                    "Rebasing commit {} ({}) caused conflicts. Resolve them, \
+                     mark them as resolved with `git add`, and run `spr \
+                     continue`. To go back to where you were before, run `spr \
+                     abort`.",
+                    commit.as_object().short_id()?.as_str().unwrap_or_default(),
+                    commit.summary().unwrap_or_default()
+                );
             }
+            state.todo.remove(0);
 
             let tree_oid = index.write_tree_to(self.repo.as_ref())?;
             if tree_oid == new_parent_commit.tree_id() {
@@ -166,10 +491,7 @@ impl Git {
                 // this behaviour is tuned around a land operation, it's in
                 // general not an unreasoanble thing for a rebase, ala git
                 // rebase --interactive and fixups etc.
-                hooks.run_post_rewrite_rebase(
-                    self.repo.as_ref(),
-                    &[(prepared_commit.oid, new_parent_oid)],
-                );
+                state.rewritten.push((oid, new_parent_oid));
                 continue;
             }
             let tree = self.repo.find_tree(tree_oid)?;
@@ -182,18 +504,13 @@ impl Git {
                 &tree,
                 &[&new_parent_commit],
             )?;
-            hooks.run_post_rewrite_rebase(
-                self.repo.as_ref(),
-                &[(prepared_commit.oid, new_parent_oid)],
-            );
+            state.rewritten.push((oid, new_parent_oid));
         }
 
         let new_oid = new_parent_oid;
         let new_commit = self.repo.find_commit(new_oid)?;
 
-        // Get and resolve the HEAD reference. This will be either a reference
-        // to a branch ('refs/heads/...') or 'HEAD' if the head is detached.
-        let mut reference = self.repo.head()?.resolve()?;
+        let mut reference = self.repo.find_reference(&state.ref_name)?;
 
         // Checkout the tree of the top commit of the rebased branch. This can
         // fail if there are local changes in the worktree that collide with
@@ -214,12 +531,103 @@ impl Git {
 
         // Update the reference. The reference may be a branch or "HEAD", if
         // detached. Either way, whatever we are on gets update to point to the
-        // new commit.
+        // new commit. If the rebase stopped for conflicts on the way, HEAD was
+        // detached in the meantime and needs to go back to the branch.
         reference.set_target(new_oid, "spr rebased")?;
+        if state.ref_name != "HEAD" {
+            self.repo.set_head(&state.ref_name)?;
+        }
+        self.rewritten(&state.rewritten)?;
+
+        let state_path = self.rebase_state_path();
+        if state_path.exists() {
+            std::fs::remove_file(state_path)?;
+        }
 
         Ok(())
     }
 
+    /// Check out the conflicted cherry-pick of `commit` onto `parent_commit`
+    /// (with HEAD detached at the latter, like `git rebase` does) and write
+    /// the state file
+    fn stop_rebase(
+        &self,
+        state: &RebaseState,
+        parent_commit: &git2::Commit,
+        commit: &git2::Commit,
+    ) -> Result<()> {
+        self.repo
+            .checkout_tree(parent_commit.as_object(), None)
+            .map_err(Error::from)
+            .wrap_err(
+                "Could not check out rebased branch - please rebase manually",
+            )?;
+        self.repo.set_head_detached(parent_commit.id())?;
+        self.repo.cherrypick(commit, None)?;
+
+        let mut text =
+            format!("ref {}\nhead {}\n", state.ref_name, state.orig_head_oid);
+        for (old_oid, new_oid) in &state.rewritten {
+            text.push_str(&format!("rewritten {} {}\n", old_oid, new_oid));
+        }
+        for oid in &state.todo {
+            text.push_str(&format!("pick {}\n", oid));
+        }
+        std::fs::write(self.rebase_state_path(), text)?;
+
+        Ok(())
+    }
+
+    fn rebase_state_path(&self) -> std::path::PathBuf {
+        self.repo.path().join(REBASE_STATE_FILE)
+    }
+
+    fn read_rebase_state(&self) -> Result<Option<RebaseState>> {
+        let text = match std::fs::read_to_string(self.rebase_state_path()) {
+            Ok(text) => text,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut ref_name = None;
+        let mut orig_head_oid = None;
+        let mut todo = Vec::new();
+        let mut rewritten = Vec::new();
+        for line in text.lines() {
+            match line.split_once(' ') {
+                Some(("ref", name)) => ref_name = Some(name.to_string()),
+                Some(("head", oid)) => {
+                    orig_head_oid = Some(Oid::from_str(oid)?)
+                }
+                Some(("pick", oid)) => todo.push(Oid::from_str(oid)?),
+                Some(("rewritten", oids)) => {
+                    let Some((old_oid, new_oid)) = oids.split_once(' ') else {
+                        bail!("Invalid line in spr rebase state: {:?}", line);
+                    };
+                    rewritten.push((
+                        Oid::from_str(old_oid)?,
+                        Oid::from_str(new_oid)?,
+                    ));
+                }
+                _ => bail!("Invalid line in spr rebase state: {:?}", line),
+            }
+        }
+
+        match (ref_name, orig_head_oid) {
+            (Some(ref_name), Some(orig_head_oid)) if !todo.is_empty() => {
+                Ok(Some(RebaseState {
+                    ref_name,
+                    orig_head_oid,
+                    todo,
+                    rewritten,
+                }))
+            }
+            _ => bail!("Incomplete spr rebase state"),
+        }
+    }
+
     pub fn head(&self) -> Result<Oid> {
         let oid = self
             .repo
@@ -250,15 +658,14 @@ impl Git {
         }
 
         let parent_oid = commit.parent_id(0)?;
-
-        let message =
-            String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        let tree_oid = commit.tree_id();
+        let parent_tree_oid = commit.parent(0)?.tree_id();
 
         let short_id =
             commit.as_object().short_id()?.as_str().unwrap().to_string();
         drop(commit);
 
-        let mut message = parse_message(&message, MessageSection::Title);
+        let mut message = self.get_commit_message(config, oid)?;
 
         let pull_request_number = message
             .get(&MessageSection::PullRequest)
@@ -277,11 +684,372 @@ impl Git {
             oid,
             short_id,
             parent_oid,
+            tree_oid,
+            parent_tree_oid,
             message,
             pull_request_number,
         })
     }
 
+    /// The parsed message of a commit, including the metadata from its note
+    fn get_commit_message(
+        &self,
+        config: &Config,
+        oid: Oid,
+    ) -> Result<MessageSectionsMap> {
+        let commit = self.repo.find_commit(oid)?;
+        let mut message = parse_message(
+            &String::from_utf8_lossy(commit.message_bytes()),
+            MessageSection::Title,
+            &config.custom_sections,
+        );
+
+        // Metadata in the commit message takes precedence over a note
+        if let Some(note) = self.get_metadata_note(oid)? {
+            for (section, text) in
+                parse_metadata_note(&note, &config.custom_sections)
+            {
+                message.entry(section).or_insert(text);
+            }
+        }
+
+        Ok(message)
+    }
+
+    /// The parsed messages of the commits on all local branches (and a
+    /// detached HEAD) that are not on master
+    pub fn get_local_messages(
+        &self,
+        config: &Config,
+        master_oid: Oid,
+    ) -> Result<Vec<MessageSectionsMap>> {
+        let mut walk = self.repo.revwalk()?;
+        walk.push_glob("refs/heads/*")?;
+        walk.push_head()?;
+        walk.hide(master_oid)?;
+
+        walk.map(|oid| self.get_commit_message(config, oid?))
+            .collect()
+    }
+
+    /// The numbers of the Pull Requests that commits on local branches refer
+    /// to
+    pub fn get_local_pull_request_numbers(
+        &self,
+        config: &Config,
+        master_oid: Oid,
+    ) -> Result<HashSet<u64>> {
+        Ok(self
+            .get_local_messages(config, master_oid)?
+            .iter()
+            .filter_map(|message| message.get(&MessageSection::PullRequest))
+            .filter_map(|text| config.parse_pull_request_field(text))
+            .collect())
+    }
+
+    fn get_metadata_note(&self, oid: Oid) -> Result<Option<String>> {
+        match self.repo.find_note(Some(NOTES_REF), oid) {
+            Ok(note) => Ok(Some(
+                String::from_utf8_lossy(note.message_bytes()).into_owned(),
+            )),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Record the metadata sections of the given message in a note on the
+    /// commit, if configured to do so. Otherwise the metadata is part of the
+    /// commit message, and a note left over from using notes before is
+    /// removed.
+    pub fn update_metadata_note(
+        &self,
+        config: &Config,
+        oid: Oid,
+        message: &MessageSectionsMap,
+    ) -> Result<()> {
+        let note = if config.metadata_format == MetadataFormat::Notes {
+            build_metadata_note(message)
+        } else {
+            String::new()
+        };
+        let current_note = self.get_metadata_note(oid)?;
+        if current_note.as_deref().unwrap_or_default() == note {
+            return Ok(());
+        }
+
+        let signature = self.repo.signature()?;
+        if note.is_empty() {
+            self.repo.note_delete(
+                oid,
+                Some(NOTES_REF),
+                &signature,
+                &signature,
+            )?;
+            return Ok(());
+        }
+
+        self.repo.note(
+            &signature,
+            &signature,
+            Some(NOTES_REF),
+            oid,
+            &note,
+            true,
+        )?;
+
+        // Have Git carry the notes over to the new commits when rebasing or
+        // amending, like spr does itself.
+        let mut git_config = self.repo.config()?;
+        let mut rewrite_refs = git_config
+            .multivar("notes.rewriteRef", Some(&format!("^{}$", NOTES_REF)))?;
+        if rewrite_refs.next().is_none() {
+            drop(rewrite_refs);
+            git_config.set_multivar("notes.rewriteRef", "^$", NOTES_REF)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the repository's Pull Request template from the tree of the given
+    /// commit, looking in the places GitHub looks: `pull_request_template.md`
+    /// in `.github/`, the root directory or `docs/`, or the only template in
+    /// a `PULL_REQUEST_TEMPLATE/` directory there. If `path` is given, the
+    /// template at that path, or with that name in a `PULL_REQUEST_TEMPLATE/`
+    /// directory, is used instead.
+    pub fn get_pull_request_template(
+        &self,
+        oid: Oid,
+        path: Option<&str>,
+    ) -> Result<Option<String>> {
+        const DIRECTORIES: [&str; 3] = [".github", "", "docs"];
+
+        let root = self.repo.find_commit(oid)?.tree()?;
+        let template_directories = || {
+            DIRECTORIES.iter().filter_map(|dir| {
+                subtree(
+                    &self.repo,
+                    &subtree(&self.repo, &root, dir)?,
+                    "pull_request_template",
+                )
+            })
+        };
+
+        let blob_oid = if let Some(path) = path {
+            let blob_oid = match root.get_path(std::path::Path::new(path)) {
+                Ok(entry) => Some(entry.id()),
+                Err(_) => template_directories().find_map(|tree| {
+                    find_tree_entry_ignoring_case(&tree, path)
+                        .map(|entry| entry.id())
+                }),
+            };
+            Some(blob_oid.ok_or_else(|| {
+                eyre!("Pull Request template '{}' not found", path)
+            })?)
+        } else {
+            DIRECTORIES
+                .iter()
+                .find_map(|dir| {
+                    find_tree_entry_ignoring_case(
+                        &subtree(&self.repo, &root, dir)?,
+                        "pull_request_template.md",
+                    )
+                    .map(|entry| entry.id())
+                })
+                .or_else(|| {
+                    template_directories().find_map(|tree| {
+                        let mut templates = tree.iter().filter(|entry| {
+                            entry.kind() == Some(git2::ObjectType::Blob)
+                                && entry.name().is_some_and(|name| {
+                                    name.to_ascii_lowercase().ends_with(".md")
+                                })
+                        });
+                        match (templates.next(), templates.next()) {
+                            (Some(entry), None) => Some(entry.id()),
+                            _ => None,
+                        }
+                    })
+                })
+        };
+
+        let Some(blob_oid) = blob_oid else {
+            return Ok(None);
+        };
+        let blob = self.repo.find_blob(blob_oid)?;
+
+        Ok(Some(String::from_utf8_lossy(blob.content()).into_owned()))
+    }
+
+    /// The synced sections of a Pull Request and its commit message, as
+    /// recorded when they were last in sync
+    pub fn get_synced_sections(
+        &self,
+        config: &Config,
+        number: u64,
+    ) -> Result<Option<MessageSectionsMap>> {
+        let reference = match self
+            .repo
+            .find_reference(&format!("{}{}", SYNC_REF_PREFIX, number))
+        {
+            Ok(reference) => reference,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let blob = reference.peel_to_blob()?;
+
+        Ok(Some(parse_message(
+            &String::from_utf8_lossy(blob.content()),
+            MessageSection::Title,
+            &config.custom_sections,
+        )))
+    }
+
+    /// Record that the commit message and the Pull Request are in sync, with
+    /// the synced sections of the given message
+    pub fn set_synced_sections(
+        &self,
+        config: &Config,
+        number: u64,
+        message: &MessageSectionsMap,
+    ) -> Result<()> {
+        let sections: MessageSectionsMap =
+            synced_sections(&config.custom_sections)
+                .into_iter()
+                .filter_map(|section| {
+                    let text = message.get(&section)?.clone();
+                    Some((section, text))
+                })
+                .collect();
+        let text = build_commit_message(
+            &sections,
+            &config.custom_sections,
+            MetadataFormat::Sections,
+        );
+
+        let blob = self.repo.blob(text.as_bytes())?;
+        self.repo.reference(
+            &format!("{}{}", SYNC_REF_PREFIX, number),
+            blob,
+            true,
+            &format!("spr: synced Pull Request #{}", number),
+        )?;
+
+        Ok(())
+    }
+
+    /// Forget the synced sections of a Pull Request that was closed or landed
+    pub fn remove_synced_sections(&self, number: u64) -> Result<()> {
+        match self
+            .repo
+            .find_reference(&format!("{}{}", SYNC_REF_PREFIX, number))
+        {
+            Ok(mut reference) => Ok(reference.delete()?),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The head commit of the Pull Request when it was last checked out with
+    /// `spr patch`, if it was
+    pub fn get_patched_head(&self, number: u64) -> Result<Option<Oid>> {
+        match self
+            .repo
+            .find_reference(&format!("{}{}", PATCH_REF_PREFIX, number))
+        {
+            Ok(reference) => Ok(reference.target()),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Record that the Pull Request was checked out at the given head commit
+    pub fn set_patched_head(&self, number: u64, oid: Oid) -> Result<()> {
+        self.repo.reference(
+            &format!("{}{}", PATCH_REF_PREFIX, number),
+            oid,
+            true,
+            &format!("spr: patched Pull Request #{}", number),
+        )?;
+
+        Ok(())
+    }
+
+    /// The Pull Requests that were checked out with `spr patch`
+    pub fn get_patched_pull_requests(&self) -> Result<Vec<u64>> {
+        let mut numbers = Vec::new();
+        for name in self
+            .repo
+            .references_glob(&format!("{}*", PATCH_REF_PREFIX))?
+            .names()
+        {
+            if let Some(number) = name?
+                .strip_prefix(PATCH_REF_PREFIX)
+                .and_then(|number| number.parse().ok())
+            {
+                numbers.push(number);
+            }
+        }
+
+        Ok(numbers)
+    }
+
+    /// Forget the head commit a Pull Request was checked out at
+    pub fn remove_patched_head(&self, number: u64) -> Result<()> {
+        match self
+            .repo
+            .find_reference(&format!("{}{}", PATCH_REF_PREFIX, number))
+        {
+            Ok(mut reference) => Ok(reference.delete()?),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The Pull Requests the linked worktree of the given name was created
+    /// for by `spr patch --worktree`, or `None` if it was not created by spr
+    pub fn get_patch_worktree(&self, name: &str) -> Result<Option<Vec<u64>>> {
+        let config = self.repo.config()?;
+        let Ok(text) = config.get_string(&patch_worktree_key(name)) else {
+            return Ok(None);
+        };
+
+        Ok(Some(
+            text.split_whitespace()
+                .filter_map(|number| number.parse().ok())
+                .collect(),
+        ))
+    }
+
+    /// Record that `spr patch` created the linked worktree of the given name
+    /// for the given Pull Requests
+    pub fn set_patch_worktree(
+        &self,
+        name: &str,
+        numbers: &[u64],
+    ) -> Result<()> {
+        let text = numbers
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.repo
+            .config()?
+            .set_str(&patch_worktree_key(name), &text)?;
+
+        Ok(())
+    }
+
+    /// Forget about a linked worktree created by `spr patch`
+    pub fn remove_patch_worktree(&self, name: &str) -> Result<()> {
+        match self.repo.config()?.remove(&patch_worktree_key(name)) {
+            Err(err) if err.code() != git2::ErrorCode::NotFound => {
+                Err(err.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn get_all_ref_names(&self) -> Result<HashSet<String>> {
         let result: std::result::Result<HashSet<_>, _> = self
             .repo
@@ -310,6 +1078,24 @@ impl Git {
         }
     }
 
+    /// Apply the changes between two trees onto the given commit, like
+    /// cherry-picking a commit with the tree `tree_oid` whose parent has the
+    /// tree `parent_tree_oid`. This works for prepared commits in a stack
+    /// that is not written yet (see `get_prepared_commits`).
+    pub fn cherrypick_trees(
+        &self,
+        parent_tree_oid: Oid,
+        tree_oid: Oid,
+        base_oid: Oid,
+    ) -> Result<git2::Index> {
+        Ok(self.repo.merge_trees(
+            &self.repo.find_tree(parent_tree_oid)?,
+            &self.repo.find_commit(base_oid)?.tree()?,
+            &self.repo.find_tree(tree_oid)?,
+            None,
+        )?)
+    }
+
     pub fn cherrypick(&self, oid: Oid, base_oid: Oid) -> Result<git2::Index> {
         let commit = self.repo.find_commit(oid)?;
         let base_commit = self.repo.find_commit(base_oid)?;
@@ -319,6 +1105,40 @@ impl Git {
             .cherrypick_commit(&commit, &base_commit, 0, None)?)
     }
 
+    /// Recreate the given commits (oldest first) on top of `new_parent_oid`,
+    /// keeping their authors and messages, and return the new top commit.
+    /// Nothing is checked out. Returns `None` if any of them does not apply
+    /// cleanly.
+    pub fn try_rebase_commits(
+        &self,
+        oids: &[Oid],
+        mut new_parent_oid: Oid,
+    ) -> Result<Option<Oid>> {
+        let mut rewritten = Vec::new();
+        for &oid in oids {
+            let index = self.cherrypick(oid, new_parent_oid)?;
+            if index.has_conflicts() {
+                return Ok(None);
+            }
+            let tree = self.repo.find_tree(self.write_index(index)?)?;
+            let new_parent_commit = self.repo.find_commit(new_parent_oid)?;
+            let commit = self.repo.find_commit(oid)?;
+
+            new_parent_oid = self.repo.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                String::from_utf8_lossy(commit.message_bytes()).as_ref(),
+                &tree,
+                &[&new_parent_commit],
+            )?;
+            rewritten.push((oid, new_parent_oid));
+        }
+        self.rewritten(&rewritten)?;
+
+        Ok(Some(new_parent_oid))
+    }
+
     pub fn write_index(&self, mut index: git2::Index) -> Result<Oid> {
         Ok(index.write_tree_to(self.repo.as_ref())?)
     }
@@ -428,6 +1248,64 @@ impl Git {
         Ok(oid)
     }
 
+    /// Stash the uncommitted changes to tracked files, like `git stash`.
+    /// Returns the stash commit, or `None` if there was nothing to stash.
+    pub fn stash_changes(&self) -> Result<Option<Oid>> {
+        // libgit2's stash functions need exclusive access to the repository
+        let mut repo = git2::Repository::open(self.repo.path())?;
+        let signature = repo.signature()?;
+
+        match repo.stash_save(&signature, "spr autostash", None) {
+            Ok(oid) => Ok(Some(oid)),
+            Err(error) if error.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Re-apply changes stashed by `stash_changes` and drop the stash entry.
+    /// If they do not apply cleanly, the worktree is left as it is and the
+    /// stash entry is kept, and this returns false.
+    pub fn pop_stashed_changes(&self, stash_oid: Oid) -> Result<bool> {
+        let mut repo = git2::Repository::open(self.repo.path())?;
+
+        let mut stash_index = None;
+        repo.stash_foreach(|index, _, oid| {
+            if *oid == stash_oid {
+                stash_index = Some(index);
+            }
+            stash_index.is_none()
+        })?;
+        let Some(stash_index) = stash_index else {
+            bail!("The stash entry {} has disappeared", stash_oid);
+        };
+
+        // libgit2 writes conflict markers into the worktree when applying a
+        // stash that conflicts, so find out beforehand. The stash commit has
+        // the worktree's tree, and the commit that was HEAD as first parent.
+        let stash_commit = self.repo.find_commit(stash_oid)?;
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        let index = self.repo.merge_trees(
+            &stash_commit.parent(0)?.tree()?,
+            &head_tree,
+            &stash_commit.tree()?,
+            None,
+        )?;
+        if index.has_conflicts() {
+            return Ok(false);
+        }
+
+        repo.stash_apply(stash_index, None)?;
+        repo.stash_drop(stash_index)?;
+
+        Ok(true)
+    }
+
+    /// Whether a rebase by spr stopped because of conflicts and waits for
+    /// `spr continue` or `spr abort`
+    pub fn rebase_in_progress(&self) -> bool {
+        self.rebase_state_path().exists()
+    }
+
     pub fn check_no_uncommitted_changes(&self) -> Result<()> {
         let mut opts = git2::StatusOptions::new();
         opts.include_ignored(false).include_untracked(false);
@@ -440,3 +1318,33 @@ impl Git {
         }
     }
 }
+
+/// The Git configuration key that records the Pull Requests of a worktree
+/// created by `spr patch`
+fn patch_worktree_key(name: &str) -> String {
+    format!("spr.worktree.{}.pullRequests", name)
+}
+
+fn find_tree_entry_ignoring_case<'a>(
+    tree: &'a git2::Tree,
+    name: &str,
+) -> Option<git2::TreeEntry<'a>> {
+    tree.iter().find(|entry| {
+        entry.name().is_some_and(|n| n.eq_ignore_ascii_case(name))
+    })
+}
+
+fn subtree<'r>(
+    repo: &'r git2::Repository,
+    tree: &git2::Tree<'r>,
+    name: &str,
+) -> Option<git2::Tree<'r>> {
+    if name.is_empty() {
+        return Some(tree.clone());
+    }
+    find_tree_entry_ignoring_case(tree, name)?
+        .to_object(repo)
+        .ok()?
+        .into_tree()
+        .ok()
+}
@@ -27,6 +27,15 @@ impl GitRemote {
         }
     }
 
+    /// The same remote repository access, for another URL
+    pub fn with_url(&self, url: String) -> Self {
+        Self {
+            repo: self.repo.clone(),
+            url,
+            auth_token: self.auth_token.clone(),
+        }
+    }
+
     fn with_connection<F, T>(&self, dir: git2::Direction, func: F) -> Result<T>
     where
         F: FnOnce(&mut git2::RemoteConnection) -> Result<T>,
@@ -117,6 +126,31 @@ impl GitRemote {
         })
     }
 
+    /// Fetch a reference by its full name (e.g. `refs/pull/1/head`). Returns
+    /// `None` if the remote does not have it.
+    pub fn fetch_ref(&self, ref_name: &str) -> Result<Option<Oid>> {
+        self.with_connection(git2::Direction::Fetch, move |connection| {
+            let oid = connection
+                .remote()
+                .list()?
+                .iter()
+                .find(|rh| rh.name() == ref_name && !rh.oid().is_zero())
+                .map(|rh| rh.oid());
+            debug!("fetching ref {}: {:?}", ref_name, oid);
+
+            if let Some(oid) = oid {
+                let mut fetch_options = git2::FetchOptions::new();
+                fetch_options.update_fetchhead(false);
+                fetch_options.download_tags(git2::AutotagOption::None);
+                connection
+                    .remote()
+                    .download(&[oid.to_string()], Some(&mut fetch_options))?;
+            }
+
+            Ok(oid)
+        })
+    }
+
     pub fn fetch_branch(&self, branch_name: &str) -> Result<Oid> {
         self.fetch_from_remote(&[branch_name], &[])?
             .first()
@@ -10,10 +10,13 @@ use graphql_client::{GraphQLQuery, Response};
 use serde::Deserialize;
 
 use crate::{
+    config::Forge,
     git::PreparedCommit,
     git_remote::GitRemote,
     message::{
-        MessageSection, MessageSectionsMap, build_github_body, parse_message,
+        CustomSection, MessageSection, MessageSectionsMap, build_github_body,
+        build_github_body_for_update, build_github_body_from_template,
+        parse_github_body,
     },
 };
 use std::collections::{HashMap, HashSet};
@@ -34,6 +37,7 @@ pub struct PullRequest {
     pub sections: MessageSectionsMap,
     pub base: GitHubBranch,
     pub head: GitHubBranch,
+    pub head_repository: HeadRepository,
     pub base_oid: git2::Oid,
     pub head_oid: git2::Oid,
     pub merge_commit: Option<git2::Oid>,
@@ -41,6 +45,24 @@ pub struct PullRequest {
     pub review_status: Option<ReviewStatus>,
 }
 
+/// The repository the head branch of a Pull Request is in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeadRepository {
+    /// This repository
+    Same,
+    /// A fork, with the given URL
+    Fork(String),
+    /// A fork that has been deleted since the Pull Request was opened
+    Deleted,
+}
+
+impl HeadRepository {
+    /// Whether the Pull Request comes from a fork, which spr cannot push to
+    pub fn is_fork(&self) -> bool {
+        *self != HeadRepository::Same
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ReviewStatus {
     Requested,
@@ -72,19 +94,70 @@ impl PullRequestUpdate {
         &mut self,
         pull_request: &PullRequest,
         message: &MessageSectionsMap,
+        custom_sections: &[CustomSection],
     ) {
         let title = message.get(&MessageSection::Title);
         if title.is_some() && title != Some(&pull_request.title) {
             self.title = title.cloned();
         }
 
-        let body = build_github_body(message);
+        let body = match &pull_request.body {
+            Some(body) => {
+                build_github_body_for_update(message, body, custom_sections)
+            }
+            None => build_github_body(message, custom_sections),
+        };
         if pull_request.body.as_ref() != Some(&body) {
             self.body = Some(body);
         }
     }
 }
 
+/// An open Pull Request, as listed by `GitHub::get_open_pull_requests`
+#[derive(Debug, Clone)]
+pub struct OpenPullRequest {
+    pub number: u64,
+    pub title: String,
+    pub body: String,
+    pub base_ref_name: String,
+    pub head_ref_name: String,
+    pub head_oid: git2::Oid,
+    /// The login of the user who opened the Pull Request
+    pub author: String,
+}
+
+/// A Pull Request as listed by `spr list`
+#[derive(Debug)]
+pub struct ListedPullRequest {
+    pub title: String,
+    pub url: String,
+    pub review_status: Option<ReviewStatus>,
+}
+
+/// A Pull Request whose review was requested from the current user (directly
+/// or through one of their teams), as listed by `GitHub::get_review_requests`
+#[derive(Debug, Clone)]
+pub struct ReviewRequest {
+    pub number: u64,
+    pub title: String,
+    pub url: String,
+    pub author: String,
+    /// When the review was requested
+    pub requested_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub head_oid: git2::Oid,
+    /// The commit the current user reviewed last, if they did review this
+    /// Pull Request before
+    pub reviewed_oid: Option<git2::Oid>,
+}
+
+/// The verdict of a review submitted with `GitHub::submit_review`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewEvent {
+    Approve,
+    RequestChanges,
+    Comment,
+}
+
 #[derive(serde::Serialize, Default, Debug)]
 pub struct PullRequestRequestReviewers {
     pub reviewers: Vec<String>,
@@ -98,9 +171,15 @@ pub enum PullRequestState {
     Closed,
 }
 
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Team {
+    pub slug: String,
+}
+
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct UserWithName {
     pub login: String,
+    #[serde(alias = "full_name")]
     pub name: Option<String>,
     #[serde(default)]
     pub is_collaborator: bool,
@@ -131,20 +210,47 @@ type GitObjectID = String;
 )]
 pub struct PullRequestMergeabilityQuery;
 
+#[allow(clippy::upper_case_acronyms)]
+type URI = String;
+type DateTime = chrono::DateTime<chrono::Utc>;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/inbox_query.graphql",
+    response_derives = "Debug"
+)]
+pub struct InboxQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/gql/schema.docs.graphql",
+    query_path = "src/gql/open_reviews.graphql",
+    response_derives = "Debug"
+)]
+pub struct SearchQuery;
+
 impl GitHub {
     pub fn new(
         config: crate::config::Config,
         git: crate::git::Git,
         auth_token: String,
     ) -> Self {
-        let git_remote = GitRemote::new(
-            git.repo().clone(),
-            format!(
-                "https://github.com/{}/{}.git",
-                &config.owner, &config.repo,
-            ),
-            auth_token,
-        );
+        let remote_url = config.git_url();
+        Self::new_with_remote_url(config, git, remote_url, auth_token)
+    }
+
+    /// Like `new`, but fetch from and push to the Git repository at
+    /// `remote_url` instead of the one on the forge. This can be a path to a
+    /// local bare repository, which is what the integration tests use.
+    pub fn new_with_remote_url(
+        config: crate::config::Config,
+        git: crate::git::Git,
+        remote_url: String,
+        auth_token: String,
+    ) -> Self {
+        let git_remote =
+            GitRemote::new(git.repo().clone(), remote_url, auth_token);
         Self {
             config,
             git,
@@ -167,16 +273,41 @@ impl GitHub {
         octocrab::instance()
             .get::<UserWithName, _, _>(format!("/users/{}", login), None::<&()>)
             .await
+            .map(|mut user| {
+                // Forgejo reports users without a full name with an empty
+                // string
+                if user.name.as_deref() == Some("") {
+                    user.name = None;
+                }
+                user
+            })
             .map_err(Error::from)
     }
 
-    pub async fn get_github_team(
-        owner: String,
-        team: String,
-    ) -> Result<octocrab::models::teams::Team> {
+    /// The login of the user spr acts as
+    pub async fn get_viewer_login(&self) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Viewer {
+            login: String,
+        }
+
+        // Forgejo has the same endpoint
+        let viewer: Viewer =
+            octocrab::instance().get("/user", None::<&()>).await?;
+
+        Ok(viewer.login)
+    }
+
+    pub async fn get_github_team(&self, team: String) -> Result<Team> {
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::get_team(&self.config, &team).await;
+        }
+
         octocrab::instance()
-            .teams(owner)
-            .get(team)
+            .get::<Team, _, _>(
+                format!("/orgs/{}/teams/{}", self.config.owner, team),
+                None::<&()>,
+            )
             .await
             .map_err(Error::from)
     }
@@ -186,190 +317,75 @@ impl GitHub {
             config, git_remote, ..
         } = self;
 
-        let variables = pull_request_query::Variables {
-            name: config.repo.clone(),
-            owner: config.owner.clone(),
-            number: number as i64,
-        };
-        let request_body = PullRequestQuery::build_query(variables);
-        let response_body: Response<pull_request_query::ResponseData> =
-            octocrab::instance()
-                .post("/graphql", Some(&request_body))
-                .await?;
-
-        if let Some(errors) = response_body.errors {
-            let error = Err(eyre!("fetching PR #{number} failed"));
-            return errors
-                .into_iter()
-                .fold(error, |err, e| err.context(e.to_string()));
-        }
-
-        let pr = response_body
-            .data
-            .ok_or_else(|| eyre!("failed to fetch PR"))?
-            .repository
-            .ok_or_else(|| eyre!("failed to find repository"))?
-            .pull_request
-            .ok_or_else(|| eyre!("failed to find PR"))?;
-
-        let base = config.new_github_branch_from_ref(&pr.base_ref_name)?;
-        let head = config.new_github_branch_from_ref(&pr.head_ref_name)?;
-
-        let branch_names: Vec<_> =
-            [&base, &head].iter().map(|&b| b.branch_name()).collect();
-
-        let [base_oid, head_oid] =
-            git_remote.fetch_from_remote(&branch_names, &[])?[0..2]
-        else {
-            unreachable!();
-        };
-
-        let base_oid = base_oid.ok_or_else(|| {
-            eyre!("{} not found on GitHub", &base.ref_on_github)
-        })?;
-        let head_oid = head_oid.ok_or_else(|| {
-            eyre!("{} not found on GitHub", &head.ref_on_github)
-        })?;
-
-        let mut sections = parse_message(&pr.body, MessageSection::Summary);
-
-        let title = pr.title.trim().to_string();
-        sections.insert(
-            MessageSection::Title,
-            if title.is_empty() {
-                String::from("(untitled)")
-            } else {
-                title
-            },
-        );
-
-        sections.insert(
-            MessageSection::PullRequest,
-            config.pull_request_url(number),
-        );
-
-        let reviewers: HashMap<String, ReviewStatus> = pr
-            .latest_opinionated_reviews
-            .iter()
-            .flat_map(|all_reviews| &all_reviews.nodes)
-            .flatten()
-            .flatten()
-            .flat_map(|review| {
-                let user_name = review.author.as_ref()?.login.clone();
-                let status = match review.state {
-                    pull_request_query::PullRequestReviewState::APPROVED => ReviewStatus::Approved,
-                    pull_request_query::PullRequestReviewState::CHANGES_REQUESTED => ReviewStatus::Rejected,
-                    _ => ReviewStatus::Requested,
-                };
-                Some((user_name, status))
-            })
-            .collect();
-
-        let review_status = match pr.review_decision {
-            Some(pull_request_query::PullRequestReviewDecision::APPROVED) => Some(ReviewStatus::Approved),
-            Some(pull_request_query::PullRequestReviewDecision::CHANGES_REQUESTED) => Some(ReviewStatus::Rejected),
-            Some(pull_request_query::PullRequestReviewDecision::REVIEW_REQUIRED) => Some(ReviewStatus::Requested),
-            _ => None,
-        };
+        let pr = fetch_pull_request(&config, number).await?;
 
-        let requested_reviewers: Vec<String> = pr.review_requests
-            .iter()
-            .flat_map(|x| &x.nodes)
-            .flatten()
-            .flatten()
-            .flat_map(|x| &x.requested_reviewer)
-            .flat_map(|reviewer| {
-              type UserType = pull_request_query::PullRequestQueryRepositoryPullRequestReviewRequestsNodesRequestedReviewer;
-              match reviewer {
-                UserType::User(user) => Some(user.login.clone()),
-                UserType::Team(team) => Some(format!("#{}", team.slug)),
-                _ => None,
-              }
-            })
-            .chain(reviewers.keys().cloned())
-            .collect::<HashSet<String>>() // de-duplicate
-            .into_iter()
-            .collect();
-
-        sections.insert(
-            MessageSection::Reviewers,
-            requested_reviewers.iter().fold(String::new(), |out, slug| {
-                if out.is_empty() {
-                    slug.to_string()
-                } else {
-                    format!("{}, {}", out, slug)
-                }
-            }),
-        );
+        complete_pull_request(&config, &git_remote, pr)
+    }
 
-        if review_status == Some(ReviewStatus::Approved) {
-            sections.insert(
-                MessageSection::ReviewedBy,
-                reviewers
-                    .iter()
-                    .filter_map(|(k, v)| {
-                        if v == &ReviewStatus::Approved {
-                            Some(k)
-                        } else {
-                            None
-                        }
-                    })
-                    .fold(String::new(), |out, slug| {
-                        if out.is_empty() {
-                            slug.to_string()
-                        } else {
-                            format!("{}, {}", out, slug)
-                        }
-                    }),
-            );
-        }
+    /// Whether the Pull Request is open. Unlike `get_pull_request`, this does
+    /// not need its branches to still exist.
+    pub async fn get_pull_request_state(
+        &self,
+        number: u64,
+    ) -> Result<PullRequestState> {
+        let pr = fetch_pull_request(&self.config, number).await?;
 
-        Ok::<_, Error>(PullRequest {
-            number: pr.number as u64,
-            state: match pr.state {
-                pull_request_query::PullRequestState::OPEN => {
-                    PullRequestState::Open
-                }
-                _ => PullRequestState::Closed,
-            },
-            title: pr.title,
-            body: Some(pr.body),
-            sections,
-            base,
-            head,
-            base_oid,
-            head_oid,
-            reviewers,
-            review_status,
-            merge_commit: pr
-                .merge_commit
-                .and_then(|sha| git2::Oid::from_str(&sha.oid).ok()),
-        })
+        Ok(pr.state)
     }
 
     pub async fn create_pull_request(
         &self,
         message: &MessageSectionsMap,
+        template: Option<&str>,
         base_ref_name: String,
         head_ref_name: String,
         draft: bool,
     ) -> Result<u64> {
-        let number = octocrab::instance()
-            .pulls(self.config.owner.clone(), self.config.repo.clone())
-            .create(
-                message
-                    .get(&MessageSection::Title)
-                    .unwrap_or(&String::new()),
-                head_ref_name,
-                base_ref_name,
+        // This request body is understood by both GitHub and Forgejo (which
+        // ignores the `draft` field)
+        #[derive(serde::Serialize)]
+        struct CreatePullRequest {
+            title: String,
+            head: String,
+            base: String,
+            body: String,
+            draft: bool,
+        }
+        #[derive(Deserialize)]
+        struct Created {
+            number: u64,
+        }
+
+        let created: Created = octocrab::instance()
+            .post(
+                format!(
+                    "/repos/{}/{}/pulls",
+                    self.config.owner, self.config.repo
+                ),
+                Some(&CreatePullRequest {
+                    title: message
+                        .get(&MessageSection::Title)
+                        .cloned()
+                        .unwrap_or_default(),
+                    head: head_ref_name,
+                    base: base_ref_name,
+                    body: match template {
+                        Some(template) => build_github_body_from_template(
+                            message,
+                            template,
+                            &self.config.custom_sections,
+                        ),
+                        None => build_github_body(
+                            message,
+                            &self.config.custom_sections,
+                        ),
+                    },
+                    draft,
+                }),
             )
-            .body(build_github_body(message))
-            .draft(Some(draft))
-            .send()
-            .await?
-            .number;
+            .await?;
 
-        Ok(number)
+        Ok(created.number)
     }
 
     pub async fn update_pull_request(
@@ -377,8 +393,10 @@ impl GitHub {
         number: u64,
         updates: PullRequestUpdate,
     ) -> Result<()> {
-        octocrab::instance()
-            .patch::<octocrab::models::pulls::PullRequest, _, _>(
+        #[derive(Deserialize)]
+        struct Ignore {}
+        let _: Ignore = octocrab::instance()
+            .patch(
                 format!(
                     "/repos/{}/{}/pulls/{}",
                     self.config.owner, self.config.repo, number
@@ -390,6 +408,46 @@ impl GitHub {
         Ok(())
     }
 
+    /// Squash-merge the Pull Request, provided its head is still at
+    /// `head_oid`. Returns the commit that was created on the target branch.
+    pub async fn merge_pull_request(
+        &self,
+        number: u64,
+        title: String,
+        message: String,
+        head_oid: git2::Oid,
+    ) -> Result<Option<git2::Oid>> {
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::merge_pull_request(
+                &self.config,
+                number,
+                title,
+                message,
+                head_oid,
+            )
+            .await;
+        }
+
+        let merge = octocrab::instance()
+            .pulls(&self.config.owner, &self.config.repo)
+            .merge(number)
+            .method(octocrab::params::pulls::MergeMethod::Squash)
+            .title(title)
+            .message(message)
+            .sha(format!("{}", head_oid))
+            .send()
+            .await?;
+
+        if !merge.merged {
+            return Err(eyre!(
+                "GitHub Pull Request merge failed: {}",
+                merge.message.unwrap_or_default()
+            ));
+        }
+
+        Ok(merge.sha.and_then(|sha| git2::Oid::from_str(&sha).ok()))
+    }
+
     pub async fn request_reviewers(
         &self,
         number: u64,
@@ -410,10 +468,274 @@ impl GitHub {
         Ok(())
     }
 
+    /// Submit a review of the Pull Request, for the given head commit
+    pub async fn submit_review(
+        &self,
+        number: u64,
+        event: ReviewEvent,
+        body: String,
+        commit_oid: git2::Oid,
+    ) -> Result<()> {
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::submit_review(
+                &self.config,
+                number,
+                event,
+                body,
+                commit_oid,
+            )
+            .await;
+        }
+
+        #[derive(serde::Serialize)]
+        struct CreateReview {
+            commit_id: String,
+            body: String,
+            event: &'static str,
+        }
+        #[derive(Deserialize)]
+        struct Ignore {}
+
+        let event = match event {
+            ReviewEvent::Approve => "APPROVE",
+            ReviewEvent::RequestChanges => "REQUEST_CHANGES",
+            ReviewEvent::Comment => "COMMENT",
+        };
+        let _: Ignore = octocrab::instance()
+            .post(
+                format!(
+                    "/repos/{}/{}/pulls/{}/reviews",
+                    self.config.owner, self.config.repo, number
+                ),
+                Some(&CreateReview {
+                    commit_id: commit_oid.to_string(),
+                    body,
+                    event,
+                }),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// All open Pull Requests in the repository
+    pub async fn get_open_pull_requests(&self) -> Result<Vec<OpenPullRequest>> {
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::get_open_pull_requests(&self.config).await;
+        }
+
+        #[derive(Deserialize)]
+        struct ApiPullRequest {
+            number: u64,
+            title: String,
+            body: Option<String>,
+            base: ApiBranch,
+            head: ApiBranch,
+            user: Option<ApiUser>,
+        }
+        #[derive(Deserialize)]
+        struct ApiUser {
+            login: String,
+        }
+        #[derive(Deserialize)]
+        struct ApiBranch {
+            #[serde(rename = "ref")]
+            ref_name: String,
+            sha: String,
+        }
+        const PER_PAGE: usize = 100;
+
+        let mut result = Vec::new();
+        for page in 1.. {
+            let pull_requests: Vec<ApiPullRequest> = octocrab::instance()
+                .get(
+                    format!(
+                        "/repos/{}/{}/pulls",
+                        self.config.owner, self.config.repo
+                    ),
+                    Some(&[
+                        ("state", "open".to_string()),
+                        ("per_page", PER_PAGE.to_string()),
+                        ("page", page.to_string()),
+                    ]),
+                )
+                .await?;
+            let last_page = pull_requests.len() < PER_PAGE;
+
+            for pr in pull_requests {
+                result.push(OpenPullRequest {
+                    number: pr.number,
+                    title: pr.title,
+                    body: pr.body.unwrap_or_default(),
+                    base_ref_name: pr.base.ref_name,
+                    head_ref_name: pr.head.ref_name,
+                    head_oid: git2::Oid::from_str(&pr.head.sha)?,
+                    author: pr
+                        .user
+                        .map(|u| u.login)
+                        .unwrap_or_else(|| "ghost".to_string()),
+                });
+            }
+            if last_page {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// The open Pull Requests in the repository opened by the current user
+    pub async fn get_open_pull_requests_by_viewer(
+        &self,
+    ) -> Result<Vec<ListedPullRequest>> {
+        use search_query::{
+            PullRequestReviewDecision as Decision,
+            SearchQuerySearchNodes as Node,
+        };
+
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::get_open_pull_requests_by_viewer(
+                &self.config,
+            )
+            .await;
+        }
+
+        let variables = search_query::Variables {
+            query: format!(
+                "repo:{}/{} is:open is:pr author:@me archived:false",
+                self.config.owner, self.config.repo
+            ),
+        };
+        let request_body = SearchQuery::build_query(variables);
+        let response_body: Response<search_query::ResponseData> =
+            octocrab::instance()
+                .post("/graphql", Some(&request_body))
+                .await
+                .wrap_err("Searching for open PRs".to_string())?;
+
+        let nodes = response_body
+            .data
+            .and_then(|data| data.search.nodes)
+            .ok_or_else(|| eyre!("failed to search for open PRs"))?;
+
+        Ok(nodes
+            .into_iter()
+            .flatten()
+            .filter_map(|node| match node {
+                Node::PullRequest(pr) => Some(ListedPullRequest {
+                    title: pr.title,
+                    url: pr.url,
+                    review_status: match pr.review_decision {
+                        Some(Decision::APPROVED) => {
+                            Some(ReviewStatus::Approved)
+                        }
+                        Some(Decision::CHANGES_REQUESTED) => {
+                            Some(ReviewStatus::Rejected)
+                        }
+                        Some(Decision::REVIEW_REQUIRED) => {
+                            Some(ReviewStatus::Requested)
+                        }
+                        _ => None,
+                    },
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The open Pull Requests that wait for a review by the current user
+    pub async fn get_review_requests(&self) -> Result<Vec<ReviewRequest>> {
+        use inbox_query::InboxQuerySearchNodesOnPullRequestTimelineItemsNodes as TimelineItem;
+
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::get_review_requests(&self.config).await;
+        }
+
+        // review-requested matches requests for the user as well as for any
+        // of their teams
+        let variables = inbox_query::Variables {
+            query: format!(
+                "repo:{}/{} is:open is:pr review-requested:@me archived:false",
+                self.config.owner, self.config.repo
+            ),
+        };
+        let request_body = InboxQuery::build_query(variables);
+        let response_body: Response<inbox_query::ResponseData> =
+            octocrab::instance()
+                .post("/graphql", Some(&request_body))
+                .await?;
+
+        if let Some(errors) = response_body.errors {
+            let error = Err(eyre!("searching for review requests failed"));
+            return errors.into_iter().fold(error, |err, e| err.wrap_err(e));
+        }
+        let data = response_body
+            .data
+            .ok_or_else(|| eyre!("failed to search for review requests"))?;
+        let viewer = data.viewer.login;
+
+        let mut result = Vec::new();
+        for node in data.search.nodes.into_iter().flatten().flatten() {
+            let inbox_query::InboxQuerySearchNodes::PullRequest(pr) = node
+            else {
+                continue;
+            };
+
+            let reviewed_oid = pr
+                .latest_reviews
+                .and_then(|reviews| reviews.nodes)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .find(|review| {
+                    review.author.as_ref().map(|a| &a.login) == Some(&viewer)
+                })
+                .and_then(|review| review.commit)
+                .map(|commit| git2::Oid::from_str(&commit.oid))
+                .transpose()?;
+            let requested_at = pr
+                .timeline_items
+                .nodes
+                .into_iter()
+                .flatten()
+                .flatten()
+                .find_map(|item| match item {
+                    TimelineItem::ReviewRequestedEvent(event) => {
+                        Some(event.created_at)
+                    }
+                    _ => None,
+                })
+                .or(Some(pr.created_at));
+
+            result.push(ReviewRequest {
+                number: pr.number as u64,
+                title: pr.title,
+                url: pr.url,
+                author: pr
+                    .author
+                    .map(|a| a.login)
+                    .unwrap_or_else(|| "ghost".to_string()),
+                requested_at,
+                head_oid: git2::Oid::from_str(&pr.head_ref_oid)?,
+                reviewed_oid,
+            });
+        }
+
+        Ok(result)
+    }
+
     pub async fn get_pull_request_mergeability(
         &self,
         number: u64,
     ) -> Result<PullRequestMergeability> {
+        if let Forge::Forgejo { .. } = self.config.forge {
+            return crate::forgejo::get_pull_request_mergeability(
+                &self.config,
+                number,
+            )
+            .await;
+        }
+
         let variables = pull_request_mergeability_query::Variables {
             name: self.config.repo.clone(),
             owner: self.config.owner.clone(),
@@ -455,6 +777,259 @@ impl GitHub {
     }
 }
 
+/// The forge-independent information about a Pull Request, as returned by
+/// the API, before we fetch its branches and parse its body
+#[derive(Debug)]
+pub(crate) struct PullRequestData {
+    pub number: u64,
+    pub state: PullRequestState,
+    pub title: String,
+    pub body: String,
+    pub base_ref_name: String,
+    pub head_ref_name: String,
+    pub head_repository: HeadRepository,
+    pub merge_commit: Option<git2::Oid>,
+    pub reviewers: HashMap<String, ReviewStatus>,
+    pub requested_reviewers: Vec<String>,
+    pub review_status: Option<ReviewStatus>,
+}
+
+async fn fetch_pull_request(
+    config: &crate::config::Config,
+    number: u64,
+) -> Result<PullRequestData> {
+    if let Forge::Forgejo { .. } = config.forge {
+        return crate::forgejo::fetch_pull_request(config, number).await;
+    }
+
+    let variables = pull_request_query::Variables {
+        name: config.repo.clone(),
+        owner: config.owner.clone(),
+        number: number as i64,
+    };
+    let request_body = PullRequestQuery::build_query(variables);
+    let response_body: Response<pull_request_query::ResponseData> =
+        octocrab::instance()
+            .post("/graphql", Some(&request_body))
+            .await?;
+
+    if let Some(errors) = response_body.errors {
+        let error = Err(eyre!("fetching PR #{number} failed"));
+        return errors
+            .into_iter()
+            .fold(error, |err, e| err.context(e.to_string()));
+    }
+
+    let pr = response_body
+        .data
+        .ok_or_else(|| eyre!("failed to fetch PR"))?
+        .repository
+        .ok_or_else(|| eyre!("failed to find repository"))?
+        .pull_request
+        .ok_or_else(|| eyre!("failed to find PR"))?;
+
+    let reviewers: HashMap<String, ReviewStatus> = pr
+        .latest_opinionated_reviews
+        .iter()
+        .flat_map(|all_reviews| &all_reviews.nodes)
+        .flatten()
+        .flatten()
+        .flat_map(|review| {
+            let user_name = review.author.as_ref()?.login.clone();
+            let status = match review.state {
+                pull_request_query::PullRequestReviewState::APPROVED => ReviewStatus::Approved,
+                pull_request_query::PullRequestReviewState::CHANGES_REQUESTED => ReviewStatus::Rejected,
+                _ => ReviewStatus::Requested,
+            };
+            Some((user_name, status))
+        })
+        .collect();
+
+    let review_status = match pr.review_decision {
+        Some(pull_request_query::PullRequestReviewDecision::APPROVED) => {
+            Some(ReviewStatus::Approved)
+        }
+        Some(
+            pull_request_query::PullRequestReviewDecision::CHANGES_REQUESTED,
+        ) => Some(ReviewStatus::Rejected),
+        Some(
+            pull_request_query::PullRequestReviewDecision::REVIEW_REQUIRED,
+        ) => Some(ReviewStatus::Requested),
+        _ => None,
+    };
+
+    let requested_reviewers: Vec<String> = pr.review_requests
+        .iter()
+        .flat_map(|x| &x.nodes)
+        .flatten()
+        .flatten()
+        .flat_map(|x| &x.requested_reviewer)
+        .flat_map(|reviewer| {
+          type UserType = pull_request_query::PullRequestQueryRepositoryPullRequestReviewRequestsNodesRequestedReviewer;
+          match reviewer {
+            UserType::User(user) => Some(user.login.clone()),
+            UserType::Team(team) => Some(format!("#{}", team.slug)),
+            _ => None,
+          }
+        })
+        .collect();
+
+    Ok(PullRequestData {
+        number: pr.number as u64,
+        state: match pr.state {
+            pull_request_query::PullRequestState::OPEN => {
+                PullRequestState::Open
+            }
+            _ => PullRequestState::Closed,
+        },
+        title: pr.title,
+        body: pr.body,
+        base_ref_name: pr.base_ref_name,
+        head_ref_name: pr.head_ref_name,
+        head_repository: match pr.head_repository {
+            _ if !pr.is_cross_repository => HeadRepository::Same,
+            Some(repository) => HeadRepository::Fork(repository.url),
+            // The head repository is missing if the fork was deleted
+            None => HeadRepository::Deleted,
+        },
+        merge_commit: pr
+            .merge_commit
+            .and_then(|sha| git2::Oid::from_str(&sha.oid).ok()),
+        reviewers,
+        requested_reviewers,
+        review_status,
+    })
+}
+
+fn complete_pull_request(
+    config: &crate::config::Config,
+    git_remote: &GitRemote,
+    pr: PullRequestData,
+) -> Result<PullRequest> {
+    let number = pr.number;
+    let base = config.new_github_branch_from_ref(&pr.base_ref_name)?;
+    let head = config.new_github_branch_from_ref(&pr.head_ref_name)?;
+
+    let [base_oid, head_oid] = match &pr.head_repository {
+        HeadRepository::Same => {
+            let branch_names: Vec<_> =
+                [&base, &head].iter().map(|&b| b.branch_name()).collect();
+
+            let [base_oid, head_oid] =
+                git_remote.fetch_from_remote(&branch_names, &[])?[0..2]
+            else {
+                unreachable!();
+            };
+
+            [base_oid, head_oid]
+        }
+        // The head of a Pull Request from a fork is in the fork, but GitHub
+        // (and Forgejo) keep a copy of it in this repository, which also
+        // survives the fork being deleted
+        head_repository => {
+            let base_oid =
+                git_remote.fetch_from_remote(&[base.branch_name()], &[])?[0];
+            let pull_ref = format!("refs/pull/{}/head", number);
+            let head_oid =
+                match (git_remote.fetch_ref(&pull_ref)?, head_repository) {
+                    (Some(oid), _) => Some(oid),
+                    (None, HeadRepository::Fork(url)) => git_remote
+                        .with_url(url.clone())
+                        .fetch_from_remote(&[head.branch_name()], &[])?[0],
+                    (None, _) => None,
+                };
+
+            [base_oid, head_oid]
+        }
+    };
+
+    let base_oid = base_oid
+        .ok_or_else(|| eyre!("{} not found on GitHub", &base.ref_on_github))?;
+    let head_oid = head_oid.ok_or_else(|| {
+        eyre!(
+            "{} not found on GitHub{}",
+            &head.ref_on_github,
+            match &pr.head_repository {
+                HeadRepository::Same => String::new(),
+                HeadRepository::Fork(url) => format!(" (in {})", url),
+                HeadRepository::Deleted => " (in a deleted fork)".into(),
+            }
+        )
+    })?;
+
+    let mut sections = parse_github_body(&pr.body, &config.custom_sections);
+
+    let title = pr.title.trim().to_string();
+    sections.insert(
+        MessageSection::Title,
+        if title.is_empty() {
+            String::from("(untitled)")
+        } else {
+            title
+        },
+    );
+
+    sections
+        .insert(MessageSection::PullRequest, config.pull_request_url(number));
+
+    let requested_reviewers: Vec<String> = pr
+        .requested_reviewers
+        .into_iter()
+        .chain(pr.reviewers.keys().cloned())
+        .collect::<HashSet<String>>() // de-duplicate
+        .into_iter()
+        .collect();
+
+    sections.insert(
+        MessageSection::Reviewers,
+        requested_reviewers.iter().fold(String::new(), |out, slug| {
+            if out.is_empty() {
+                slug.to_string()
+            } else {
+                format!("{}, {}", out, slug)
+            }
+        }),
+    );
+
+    if pr.review_status == Some(ReviewStatus::Approved) {
+        sections.insert(
+            MessageSection::ReviewedBy,
+            pr.reviewers
+                .iter()
+                .filter_map(|(k, v)| {
+                    if v == &ReviewStatus::Approved {
+                        Some(k)
+                    } else {
+                        None
+                    }
+                })
+                .fold(String::new(), |out, slug| {
+                    if out.is_empty() {
+                        slug.to_string()
+                    } else {
+                        format!("{}, {}", out, slug)
+                    }
+                }),
+        );
+    }
+
+    Ok(PullRequest {
+        number,
+        state: pr.state,
+        title: pr.title,
+        body: Some(pr.body),
+        sections,
+        base,
+        head,
+        head_repository: pr.head_repository,
+        base_oid,
+        head_oid,
+        reviewers: pr.reviewers,
+        review_status: pr.review_status,
+        merge_commit: pr.merge_commit,
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct GitHubBranch {
     ref_on_github: String,
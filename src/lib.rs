@@ -7,6 +7,7 @@
 
 pub mod commands;
 pub mod config;
+pub mod forgejo;
 pub mod git;
 pub mod git_remote;
 pub mod github;
@@ -10,7 +10,7 @@
 //! stacked to allow for a series of code reviews of interdependent code.
 
 use clap::{Parser, Subcommand};
-use color_eyre::eyre::{Error, Result, eyre};
+use color_eyre::eyre::{Error, Result};
 use log::debug;
 use spr::commands;
 
@@ -30,8 +30,8 @@ pub struct Cli {
     #[clap(long)]
     github_auth_token: Option<String>,
 
-    /// GitHub repository ('org/name', if not given taken from config
-    /// spr.githubRepository)
+    /// GitHub repository ('org/name', if not given taken from git config
+    /// spr.githubRepository or from .spr.toml)
     #[clap(long)]
     github_repository: Option<String>,
 
@@ -41,11 +41,22 @@ pub struct Cli {
     github_master_branch: Option<String>,
 
     /// prefix to be used for branches created for pull requests (if not given
-    /// taken from git config spr.branchPrefix, defaulting to
-    /// 'spr/<GITHUB_USERNAME>/')
+    /// taken from git config spr.branchPrefix or from .spr.toml, defaulting
+    /// to 'spr/<GITHUB_USERNAME>/')
     #[clap(long)]
     branch_prefix: Option<String>,
 
+    /// The kind of server hosting the repository: 'github' or 'forgejo' (if
+    /// not given taken from git config spr.forge or from .spr.toml,
+    /// defaulting to 'github')
+    #[clap(long)]
+    forge: Option<String>,
+
+    /// Web address of the Forgejo server, e.g. 'https://codeberg.org' (if
+    /// not given taken from git config spr.forgeUrl or from .spr.toml)
+    #[clap(long)]
+    forge_url: Option<String>,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -72,11 +83,33 @@ enum Commands {
     /// List open Pull Requests on GitHub and their review decision
     List,
 
+    /// List open Pull Requests that wait for your review, and check one out
+    /// or open it in the browser
+    Inbox,
+
     /// Create a new branch with the contents of an existing Pull Request
     Patch(commands::patch::PatchOptions),
 
+    /// Approve, request changes to, or comment on a Pull Request
+    Review(commands::review::ReviewOptions),
+
     /// Close a Pull request
     Close(commands::close::CloseOptions),
+
+    /// Merge changes to the commit message and to the Pull Request on GitHub
+    /// made since they were last in sync
+    Sync(commands::sync::SyncOptions),
+
+    /// Link the HEAD commit to an existing Pull Request, so that `spr diff`
+    /// updates that one
+    Adopt(commands::adopt::AdoptOptions),
+
+    /// Continue a rebase by spr that stopped because of conflicts, after
+    /// resolving them
+    Continue,
+
+    /// Undo a rebase by spr that stopped because of conflicts
+    Abort,
 }
 
 pub async fn spr() -> Result<()> {
@@ -96,77 +129,32 @@ pub async fn spr() -> Result<()> {
 
     let repo = git2::Repository::discover(std::env::current_dir()?)?;
 
-    let git_config = repo.config()?;
-
-    let github_repository = match cli.github_repository {
-        Some(v) => Ok(v),
-        None => git_config.get_string("spr.githubRepository"),
-    }?;
-
-    let github_master_branch = match cli.github_master_branch {
-        Some(v) => Ok::<String, git2::Error>(v),
-        None => git_config
-            .get_string("spr.githubMasterBranch")
-            .or_else(|_| Ok("master".to_string())),
-    }?;
-
-    let branch_prefix = match cli.branch_prefix {
-        Some(v) => Ok(v),
-        None => git_config.get_string("spr.branchPrefix"),
-    }?;
-
-    let (github_owner, github_repo) = {
-        let captures = lazy_regex::regex!(r#"^([\w\-\.]+)/([\w\-\.]+)$"#)
-            .captures(&github_repository)
-            .ok_or_else(|| {
-                eyre!(
-                    "GitHub repository must be given as 'OWNER/REPO', but given value was '{}'",
-                    &github_repository,
-                )
-            })?;
-        (
-            captures.get(1).unwrap().as_str().to_string(),
-            captures.get(2).unwrap().as_str().to_string(),
-        )
-    };
-
-    let require_approval = git_config
-        .get_bool("spr.requireApproval")
-        .ok()
-        .unwrap_or(false);
-    let require_test_plan = git_config
-        .get_bool("spr.requireTestPlan")
-        .ok()
-        .unwrap_or(true);
-
-    let github_auth_token = match cli.github_auth_token {
-        Some(v) => Ok(v),
-        None => git_config.get_string("spr.githubAuthToken"),
-    }?;
-
-    let config = spr::config::Config::new(
-        github_owner,
-        github_repo,
-        github_master_branch,
-        branch_prefix,
-        github_auth_token.clone(),
-        require_approval,
-        require_test_plan,
-    );
+    let config = spr::config::Config::from_sources(
+        spr::config::ConfigOverrides {
+            github_repository: cli.github_repository,
+            github_master_branch: cli.github_master_branch,
+            branch_prefix: cli.branch_prefix,
+            github_auth_token: cli.github_auth_token,
+            forge: cli.forge,
+            forge_url: cli.forge_url,
+        },
+        &spr::config::ConfigSources::from_repository(&repo)?,
+    )?;
     debug!("config: {:?}", config);
 
     let git = spr::git::Git::new(repo);
 
-    octocrab::initialise(
-        octocrab::Octocrab::builder()
-            .personal_token(github_auth_token.clone())
-            .build()?,
-    );
+    let mut octocrab_builder =
+        octocrab::Octocrab::builder().personal_token(config.auth_token.clone());
+    if let Some(api_url) = config.forge.api_url() {
+        octocrab_builder = octocrab_builder.base_uri(api_url)?;
+    }
+    octocrab::initialise(octocrab_builder.build()?);
 
     let mut gh = spr::github::GitHub::new(
         config.clone(),
         git.clone(),
-        github_auth_token,
+        config.auth_token.clone(),
     );
 
     match cli.command {
@@ -179,16 +167,30 @@ pub async fn spr() -> Result<()> {
         Commands::Amend(opts) => {
             commands::amend::amend(opts, &git, &mut gh, &config).await?
         }
-        Commands::List => commands::list::list(&config).await?,
+        Commands::List => commands::list::list(&gh).await?,
+        Commands::Inbox => {
+            commands::inbox::inbox(&git, &mut gh, &config).await?
+        }
         Commands::Patch(opts) => {
             commands::patch::patch(opts, &git, &mut gh, &config).await?
         }
+        Commands::Review(opts) => {
+            commands::review::review(opts, &git, &mut gh, &config).await?
+        }
         Commands::Close(opts) => {
             commands::close::close(opts, &git, &mut gh, &config).await?
         }
         Commands::Format(opts) => {
             commands::format::format(opts, &git, &mut gh, &config).await?
         }
+        Commands::Sync(opts) => {
+            commands::sync::sync(opts, &git, &mut gh, &config).await?
+        }
+        Commands::Adopt(opts) => {
+            commands::adopt::adopt(opts, &git, &mut gh, &config).await?
+        }
+        Commands::Continue => commands::rebase::continue_rebase(&git).await?,
+        Commands::Abort => commands::rebase::abort_rebase(&git).await?,
 
         // The following commands are executed above and return from this
         // function before it reaches this match.
@@ -12,7 +12,7 @@ use crate::output::output;
 pub type MessageSectionsMap =
     std::collections::BTreeMap<MessageSection, String>;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Hash)]
 pub enum MessageSection {
     Title,
     Summary,
@@ -20,9 +20,88 @@ pub enum MessageSection {
     Reviewers,
     ReviewedBy,
     PullRequest,
+    /// A stable identifier of the change, which survives rewriting the commit
+    ChangeId,
+    /// A section declared in the configuration, identified by its label
+    Custom(String),
 }
 
-pub fn message_section_label(section: &MessageSection) -> &'static str {
+/// How spr records the metadata it manages (Reviewers, Reviewed By and Pull
+/// Request) for a commit. All forms are understood when reading a commit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MetadataFormat {
+    /// Labelled sections like the others, e.g. `Pull Request: …`
+    #[default]
+    Sections,
+    /// Git trailers at the end of the message, e.g. `Pull-Request: …`, which
+    /// tools like `git interpret-trailers` understand
+    Trailers,
+    /// Git notes in `refs/notes/spr`, keeping the metadata out of the commit
+    /// message altogether
+    Notes,
+}
+
+impl MetadataFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match &name.to_ascii_lowercase()[..] {
+            "sections" => Ok(MetadataFormat::Sections),
+            "trailers" => Ok(MetadataFormat::Trailers),
+            "notes" => Ok(MetadataFormat::Notes),
+            _ => bail!(
+                "Unknown metadata format '{}', must be one of 'sections', \
+                 'trailers' or 'notes'",
+                name
+            ),
+        }
+    }
+}
+
+/// The sections with metadata managed by spr
+const METADATA_SECTIONS: [MessageSection; 4] = [
+    MessageSection::Reviewers,
+    MessageSection::ReviewedBy,
+    MessageSection::PullRequest,
+    MessageSection::ChangeId,
+];
+
+/// The definition of a commit message section that is not built into spr
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomSection {
+    /// The label the section is written with, e.g. "Risk"
+    pub label: String,
+    /// Further labels that are recognised when parsing a message
+    pub aliases: Vec<String>,
+    /// Whether `validate_commit_message` insists on this section
+    pub required: bool,
+    /// Whether the section is part of the Pull Request description
+    pub in_github_body: bool,
+    /// The section after which this one is placed when building messages
+    pub after: MessageSection,
+}
+
+impl CustomSection {
+    pub fn new(label: String) -> Self {
+        Self {
+            label,
+            aliases: Vec::new(),
+            required: false,
+            in_github_body: true,
+            after: MessageSection::TestPlan,
+        }
+    }
+
+    pub fn section(&self) -> MessageSection {
+        MessageSection::Custom(self.label.clone())
+    }
+
+    fn has_label(&self, label: &str) -> bool {
+        std::iter::once(&self.label)
+            .chain(self.aliases.iter())
+            .any(|l| l.eq_ignore_ascii_case(label))
+    }
+}
+
+pub fn message_section_label(section: &MessageSection) -> &str {
     use MessageSection::*;
 
     match section {
@@ -32,49 +111,135 @@ pub fn message_section_label(section: &MessageSection) -> &'static str {
         Reviewers => "Reviewers",
         ReviewedBy => "Reviewed By",
         PullRequest => "Pull Request",
+        ChangeId => "Change-Id",
+        Custom(label) => label,
     }
 }
 
-pub fn message_section_by_label(label: &str) -> Option<MessageSection> {
+pub fn message_section_by_label(
+    label: &str,
+    custom_sections: &[CustomSection],
+) -> Option<MessageSection> {
     use MessageSection::*;
 
+    // The labels of the metadata sections may also be given in the
+    // hyphenated form of Git trailers, e.g. `Pull-Request`
+    let metadata_label = label.to_ascii_lowercase().replace('-', " ");
+    match &metadata_label[..] {
+        "reviewer" => return Some(Reviewers),
+        "reviewers" => return Some(Reviewers),
+        "reviewed by" => return Some(ReviewedBy),
+        "pull request" => return Some(PullRequest),
+        "change id" => return Some(ChangeId),
+        _ if label.contains('-') => return None,
+        _ => (),
+    }
+
     match &label.to_ascii_lowercase()[..] {
         "title" => Some(Title),
         "summary" => Some(Summary),
         "test plan" => Some(TestPlan),
-        "reviewer" => Some(Reviewers),
-        "reviewers" => Some(Reviewers),
-        "reviewed by" => Some(ReviewedBy),
-        "pull request" => Some(PullRequest),
-        _ => None,
+        _ => custom_sections
+            .iter()
+            .find(|custom| custom.has_label(label))
+            .map(CustomSection::section),
+    }
+}
+
+/// Keeps track of fenced code blocks while going through the lines of a
+/// Markdown text
+#[derive(Default)]
+struct CodeFences {
+    /// The character and length of the fence of the current code block
+    open: Option<(char, usize)>,
+}
+
+impl CodeFences {
+    /// Whether the line, which must be the next one of the text, belongs to a
+    /// fenced code block (including the fences themselves)
+    fn in_code_block(&mut self, line: &str) -> bool {
+        let trimmed = line.trim_start_matches(' ');
+        let fence = match trimmed.chars().next() {
+            Some(c @ ('`' | '~')) if line.len() - trimmed.len() <= 3 => {
+                let len = trimmed.chars().take_while(|&x| x == c).count();
+                (len >= 3).then_some((c, len))
+            }
+            _ => None,
+        };
+
+        match (self.open, fence) {
+            (None, Some(fence)) => {
+                self.open = Some(fence);
+                true
+            }
+            (Some((c, len)), Some((fence_c, fence_len)))
+                if c == fence_c
+                    && fence_len >= len
+                    && trimmed[fence_len..].trim().is_empty() =>
+            {
+                self.open = None;
+                true
+            }
+            (open, _) => open.is_some(),
+        }
+    }
+}
+
+/// Whether the line is part of an indented code block or a block quote, which
+/// can't start a message section
+fn is_indented_code_or_quote(line: &str) -> bool {
+    line.starts_with('\t')
+        || line.starts_with("    ")
+        || line.trim_start().starts_with('>')
+}
+
+/// Remove leading and trailing blank lines, but leave the indentation of the
+/// remaining lines alone
+fn trim_blank_lines(text: &str) -> &str {
+    match text.find(|c: char| !c.is_whitespace()) {
+        Some(first) => {
+            let start = text[..first].rfind('\n').map_or(0, |i| i + 1);
+            text[start..].trim_end()
+        }
+        None => "",
     }
 }
 
 pub fn parse_message(
     msg: &str,
     top_section: MessageSection,
+    custom_sections: &[CustomSection],
 ) -> MessageSectionsMap {
-    let regex = lazy_regex::regex!(r#"^\s*([\w\s]+?)\s*:\s*(.*)$"#);
+    let regex = lazy_regex::regex!(r#"^\s*(\w[\w\s-]*?)\s*:\s*(.*)$"#);
 
-    let mut section = top_section;
+    let mut section = top_section.clone();
     let mut lines_in_section = Vec::<&str>::new();
     let mut sections =
         std::collections::BTreeMap::<MessageSection, String>::new();
 
+    let mut code_fences = CodeFences::default();
+
     for (lineno, line) in msg
         .trim()
         .split('\n')
-        .map(|line| line.trim_end())
+        .map(|line| line.trim_end_matches('\r'))
         .enumerate()
     {
-        if let Some(caps) = regex.captures(line) {
+        // Lines in code blocks and block quotes are taken literally, even if
+        // they look like the start of a section
+        if !code_fences.in_code_block(line)
+            && !is_indented_code_or_quote(line)
+            && let Some(caps) = regex.captures(line)
+        {
             let label = caps.get(1).unwrap().as_str();
-            let payload = caps.get(2).unwrap().as_str();
+            let payload = caps.get(2).unwrap().as_str().trim_end();
 
-            if let Some(new_section) = message_section_by_label(label) {
+            if let Some(new_section) =
+                message_section_by_label(label, custom_sections)
+            {
                 append_to_message_section(
                     sections.entry(section),
-                    lines_in_section.join("\n").trim(),
+                    trim_blank_lines(&lines_in_section.join("\n")),
                 );
                 section = new_section;
                 lines_in_section = vec![payload];
@@ -83,7 +248,7 @@ pub fn parse_message(
         }
 
         if lineno == 0 && top_section == MessageSection::Title {
-            sections.insert(top_section, line.to_string());
+            sections.insert(MessageSection::Title, line.trim_end().to_string());
             section = MessageSection::Summary;
         } else {
             lines_in_section.push(line);
@@ -93,7 +258,7 @@ pub fn parse_message(
     if !lines_in_section.is_empty() {
         append_to_message_section(
             sections.entry(section),
-            lines_in_section.join("\n").trim(),
+            trim_blank_lines(&lines_in_section.join("\n")),
         );
     }
 
@@ -104,13 +269,19 @@ fn append_to_message_section(
     entry: std::collections::btree_map::Entry<MessageSection, String>,
     text: &str,
 ) {
+    // Git trailers give one `Reviewed-by:` line per reviewer
+    let separator = match entry.key() {
+        MessageSection::ReviewedBy => ", ",
+        _ => "\n\n",
+    };
+
     if !text.is_empty() {
         entry
             .and_modify(|value| {
                 if value.is_empty() {
                     *value = text.to_string();
                 } else {
-                    *value = format!("{}\n\n{}", value, text);
+                    *value = format!("{}{}{}", value, separator, text);
                 }
             })
             .or_insert_with(|| text.to_string());
@@ -144,8 +315,13 @@ pub fn build_message(
             if display_label {
                 let label = message_section_label(section);
                 result.push_str(label);
+                // Text that's indented (e.g. a code block) goes on its own
+                // line, so that its indentation survives parsing it again.
                 result.push_str(
-                    if label.len() + text.len() > 76 || text.contains('\n') {
+                    if label.len() + text.len() > 76
+                        || text.contains('\n')
+                        || text.starts_with(char::is_whitespace)
+                    {
                         ":\n"
                     } else {
                         ": "
@@ -161,42 +337,685 @@ pub fn build_message(
     result
 }
 
-pub fn build_commit_message(section_texts: &MessageSectionsMap) -> String {
-    build_message(
+/// Insert the custom sections selected by `include` into the given list of
+/// sections, each one after the section it is configured to follow, or at the
+/// end if that section is not part of the list.
+fn with_custom_sections(
+    sections: &[MessageSection],
+    custom_sections: &[CustomSection],
+    include: impl Fn(&CustomSection) -> bool,
+) -> Vec<MessageSection> {
+    let mut result = sections.to_vec();
+
+    for custom in custom_sections.iter().filter(|c| include(c)) {
+        let index = match result.iter().position(|s| s == &custom.after) {
+            Some(mut index) => {
+                // Skip sections inserted after the same one before, so that
+                // they keep the order in which they were declared.
+                index += 1;
+                while index < result.len()
+                    && custom_sections.iter().any(|c| {
+                        c.after == custom.after && result[index] == c.section()
+                    })
+                {
+                    index += 1;
+                }
+                index
+            }
+            None => result.len(),
+        };
+        result.insert(index, custom.section());
+    }
+
+    result
+}
+
+/// Build a message from the given sections, followed by the metadata
+/// sections in the given format
+fn build_message_with_metadata(
+    section_texts: &MessageSectionsMap,
+    sections: &[MessageSection],
+    custom_sections: &[CustomSection],
+    metadata_format: MetadataFormat,
+) -> String {
+    match metadata_format {
+        MetadataFormat::Sections => build_message(
+            section_texts,
+            &with_custom_sections(
+                &[sections, &METADATA_SECTIONS].concat(),
+                custom_sections,
+                |_| true,
+            ),
+        ),
+        MetadataFormat::Trailers => {
+            let mut result = build_message(
+                section_texts,
+                &with_custom_sections(sections, custom_sections, |_| true),
+            );
+            let trailers = build_trailers(section_texts);
+            if !result.is_empty() && !trailers.is_empty() {
+                result.push('\n');
+            }
+            result.push_str(&trailers);
+            result
+        }
+        MetadataFormat::Notes => build_message(
+            section_texts,
+            &with_custom_sections(sections, custom_sections, |_| true),
+        ),
+    }
+}
+
+/// The text of the Git note that holds the metadata sections of a commit, when
+/// using `MetadataFormat::Notes`
+pub fn build_metadata_note(section_texts: &MessageSectionsMap) -> String {
+    build_message(section_texts, &METADATA_SECTIONS)
+}
+
+/// Parse the text of a Git note written by `build_metadata_note`. Anything but
+/// metadata sections is ignored.
+pub fn parse_metadata_note(
+    text: &str,
+    custom_sections: &[CustomSection],
+) -> MessageSectionsMap {
+    let mut sections =
+        parse_message(text, MessageSection::Summary, custom_sections);
+    sections.retain(|section, _| METADATA_SECTIONS.contains(section));
+    sections
+}
+
+/// The metadata sections as a block of Git trailers, with one `Reviewed-by`
+/// trailer per reviewer, as is customary
+fn build_trailers(section_texts: &MessageSectionsMap) -> String {
+    let mut result = String::new();
+
+    for section in &METADATA_SECTIONS {
+        let Some(text) = section_texts.get(section) else {
+            continue;
+        };
+        // Trailer values must fit on one line
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let (key, values) = match section {
+            MessageSection::Reviewers => ("Reviewers", vec![&text[..]]),
+            MessageSection::ReviewedBy => (
+                "Reviewed-by",
+                text.split(',').map(|name| name.trim()).collect(),
+            ),
+            MessageSection::ChangeId => ("Change-Id", vec![&text[..]]),
+            _ => ("Pull-Request", vec![&text[..]]),
+        };
+
+        for value in values.into_iter().filter(|v| !v.is_empty()) {
+            result.push_str(key);
+            result.push_str(": ");
+            result.push_str(value);
+            result.push('\n');
+        }
+    }
+
+    result
+}
+
+pub fn build_commit_message(
+    section_texts: &MessageSectionsMap,
+    custom_sections: &[CustomSection],
+    metadata_format: MetadataFormat,
+) -> String {
+    build_message_with_metadata(
         section_texts,
         &[
             MessageSection::Title,
             MessageSection::Summary,
             MessageSection::TestPlan,
-            MessageSection::Reviewers,
-            MessageSection::ReviewedBy,
-            MessageSection::PullRequest,
         ],
+        custom_sections,
+        metadata_format,
     )
 }
 
-pub fn build_github_body(section_texts: &MessageSectionsMap) -> String {
-    build_message(
+pub fn build_github_body(
+    section_texts: &MessageSectionsMap,
+    custom_sections: &[CustomSection],
+) -> String {
+    with_change_id_marker(
+        build_message(section_texts, &github_body_sections(custom_sections)),
         section_texts,
-        &[MessageSection::Summary, MessageSection::TestPlan],
     )
 }
 
+/// Matches the hidden marker that records the Change-Id of a commit in the
+/// description of its Pull Request
+fn change_id_marker_regex() -> &'static lazy_regex::Regex {
+    lazy_regex::regex!(r#"^\s*<!--\s*spr-change-id:\s*(\S+)\s*-->\s*$"#)
+}
+
+/// Append the marker with the Change-Id of the message (if it has one) to a
+/// Pull Request description
+fn with_change_id_marker(
+    mut body: String,
+    section_texts: &MessageSectionsMap,
+) -> String {
+    if let Some(change_id) = section_texts.get(&MessageSection::ChangeId) {
+        if !body.is_empty() {
+            body.push('\n');
+        }
+        body.push_str(&format!("<!-- spr-change-id: {} -->\n", change_id));
+    }
+    body
+}
+
+/// Put the marker with the given Change-Id at the end of a Pull Request
+/// description, replacing any marker that is there already
+pub fn set_change_id_marker(body: &str, change_id: &str) -> String {
+    let mut body = body
+        .lines()
+        .filter(|line| !change_id_marker_regex().is_match(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string();
+    if !body.is_empty() {
+        body.push('\n');
+    }
+
+    with_change_id_marker(
+        body,
+        &[(MessageSection::ChangeId, change_id.to_string())].into(),
+    )
+}
+
+/// The Change-Id recorded in a Pull Request description
+pub fn change_id_from_github_body(body: &str) -> Option<String> {
+    body.lines().find_map(|line| {
+        Some(change_id_marker_regex().captures(line)?[1].to_string())
+    })
+}
+
 pub fn build_github_body_for_merging(
     section_texts: &MessageSectionsMap,
+    custom_sections: &[CustomSection],
+    metadata_format: MetadataFormat,
 ) -> String {
-    build_message(
+    build_message_with_metadata(
+        section_texts,
+        &[MessageSection::Summary, MessageSection::TestPlan],
+        custom_sections,
+        metadata_format,
+    )
+}
+
+/// The sections that make up the description of a Pull Request, in order
+fn github_body_sections(
+    custom_sections: &[CustomSection],
+) -> Vec<MessageSection> {
+    with_custom_sections(
+        &[MessageSection::Summary, MessageSection::TestPlan],
+        custom_sections,
+        |custom| custom.in_github_body,
+    )
+}
+
+/// The words of the text of an HTML comment that identify a region of a Pull
+/// Request description, e.g. "readthedocs preview" for both `<!--
+/// readthedocs-preview start -->` and `<!-- readthedocs-preview end -->`.
+/// Returns whether the comment marks the end of a region, and the words.
+fn region_marker(comment: &str) -> (bool, Vec<String>) {
+    let words: Vec<String> = comment
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+    let is_end = words.iter().any(|word| word == "end");
+    let words = words
+        .into_iter()
+        .filter(|word| !["begin", "start", "end", "of"].contains(&&word[..]))
+        .collect();
+
+    (is_end, words)
+}
+
+/// The ranges of lines of a Pull Request description that are not written
+/// by spr, but by bots or GitHub apps, and must be kept as they are: regions
+/// between a pair of start and end comments, like
+///
+/// ```text
+/// <!-- readthedocs-preview start -->
+/// 📚 Documentation preview: https://example.org/
+/// <!-- readthedocs-preview end -->
+/// ```
+///
+/// and HTML comments before or after all other content. Comments in between
+/// are part of the text, e.g. because they were written in the commit message.
+fn preserved_regions(lines: &[&str]) -> Vec<std::ops::Range<usize>> {
+    let single_line_comment = lazy_regex::regex!(r#"^\s*<!--(.*?)-->\s*$"#);
+
+    // Each with whether it is a region between start and end comments
+    let mut regions = Vec::<(std::ops::Range<usize>, bool)>::new();
+    let mut code_fences = CodeFences::default();
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = lines[index];
+        if code_fences.in_code_block(line) {
+            index += 1;
+            continue;
+        }
+
+        let end = if let Some(caps) = single_line_comment.captures(line) {
+            let (is_end, words) = region_marker(caps.get(1).unwrap().as_str());
+            let region_end = lines[index + 1..]
+                .iter()
+                .position(|line| {
+                    single_line_comment.captures(line).is_some_and(|caps| {
+                        let (end, end_words) =
+                            region_marker(caps.get(1).unwrap().as_str());
+                        end && !end_words.is_empty()
+                            && words.ends_with(&end_words)
+                    })
+                })
+                .filter(|_| !is_end);
+            match region_end {
+                Some(offset) => {
+                    regions.push((index..index + offset + 2, true));
+                    index += offset + 2;
+                    continue;
+                }
+                None => index + 1,
+            }
+        } else if line.trim_start().starts_with("<!--") {
+            // A comment spanning several lines
+            lines[index..]
+                .iter()
+                .position(|line| line.contains("-->"))
+                .map_or(lines.len(), |offset| index + offset + 1)
+        } else {
+            index += 1;
+            continue;
+        };
+
+        regions.push((index..end, false));
+        index = end;
+    }
+
+    let content: Vec<usize> = (0..lines.len())
+        .filter(|index| {
+            !lines[*index].trim().is_empty()
+                && !regions.iter().any(|(r, _)| r.contains(index))
+        })
+        .collect();
+
+    regions
+        .into_iter()
+        .filter(|(r, paired)| {
+            *paired
+                || content.first().is_none_or(|&first| r.start < first)
+                || content.last().is_some_and(|&last| r.start > last)
+        })
+        .map(|(r, _)| r)
+        .collect()
+}
+
+/// The text without the regions that `preserved_regions` finds
+fn strip_preserved_regions(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let regions = preserved_regions(&lines);
+
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !regions.iter().any(|r| r.contains(index)))
+        .map(|(_, line)| *line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replace the given lines of a Pull Request description with the given text,
+/// but keep the regions that `preserved_regions` finds in them: the ones
+/// before any other content go before the text, all others after it. Regions
+/// that the text has anyway are not repeated.
+fn replace_keeping_preserved_regions(lines: &[&str], text: &str) -> String {
+    let regions: Vec<_> = preserved_regions(lines)
+        .into_iter()
+        .filter(|r| !text.contains(&lines[r.clone()].join("\n")))
+        .collect();
+    let first_content = (0..lines.len()).find(|index| {
+        !lines[*index].trim().is_empty()
+            && !regions.iter().any(|r| r.contains(index))
+    });
+    let (before, after): (Vec<_>, Vec<_>) = regions
+        .iter()
+        .partition(|r| first_content.is_none_or(|first| r.start < first));
+    let region_text = |r: &std::ops::Range<usize>| lines[r.clone()].join("\n");
+
+    before
+        .into_iter()
+        .map(region_text)
+        .chain(Some(text.to_string()).filter(|text| !text.is_empty()))
+        .chain(after.into_iter().map(region_text))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Build the new description of an existing Pull Request from the given
+/// sections. Content of the current description that was not written by spr,
+/// like a filled-in Pull Request template, comments and regions managed by
+/// bots, is kept.
+pub fn build_github_body_for_update(
+    section_texts: &MessageSectionsMap,
+    current_body: &str,
+    custom_sections: &[CustomSection],
+) -> String {
+    // The Change-Id marker goes to the end of the description. If the
+    // message has no Change-Id, an existing marker is kept like any other
+    // comment.
+    let current_body = if section_texts.contains_key(&MessageSection::ChangeId)
+    {
+        current_body
+            .lines()
+            .filter(|line| !change_id_marker_regex().is_match(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        current_body.to_string()
+    };
+
+    if is_templated_body(&current_body) {
+        return build_github_body_from_template(
+            section_texts,
+            &current_body,
+            custom_sections,
+        );
+    }
+
+    let lines: Vec<&str> = current_body.lines().collect();
+    let body = replace_keeping_preserved_regions(
+        &lines,
+        build_message(section_texts, &github_body_sections(custom_sections))
+            .trim_end(),
+    );
+
+    with_change_id_marker(
+        if body.is_empty() { body } else { body + "\n" },
         section_texts,
-        &[
-            MessageSection::Summary,
-            MessageSection::TestPlan,
-            MessageSection::Reviewers,
-            MessageSection::ReviewedBy,
-            MessageSection::PullRequest,
-        ],
     )
 }
 
+/// A part of a Markdown document: a heading (if it's not the part before the
+/// first heading), and the lines up to the next heading
+struct MarkdownBlock<'a> {
+    heading: Option<&'a str>,
+    level: usize,
+    section: Option<MessageSection>,
+    content: Vec<&'a str>,
+}
+
+/// The message section a heading in a Pull Request template stands for, if
+/// it is one that's part of the Pull Request description
+fn template_heading_section(
+    heading: &str,
+    custom_sections: &[CustomSection],
+) -> Option<MessageSection> {
+    let label = heading.trim().trim_end_matches(':').trim_end();
+    let section = match &label.to_ascii_lowercase()[..] {
+        "description" => Some(MessageSection::Summary),
+        "testing" => Some(MessageSection::TestPlan),
+        _ => message_section_by_label(label, custom_sections),
+    }?;
+
+    github_body_sections(custom_sections)
+        .contains(&section)
+        .then_some(section)
+}
+
+fn split_markdown_blocks<'a>(
+    text: &'a str,
+    custom_sections: &[CustomSection],
+) -> Vec<MarkdownBlock<'a>> {
+    let regex = lazy_regex::regex!(r#"^(#{1,6})\s+(.*?)[\s#]*$"#);
+
+    let mut blocks = vec![MarkdownBlock {
+        heading: None,
+        level: 0,
+        section: None,
+        content: Vec::new(),
+    }];
+    let mut code_fences = CodeFences::default();
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    let regions = preserved_regions(&lines);
+
+    for (index, &line) in lines.iter().enumerate() {
+        if !code_fences.in_code_block(line)
+            && !regions.iter().any(|r| r.contains(&index))
+            && let Some(caps) = regex.captures(line)
+        {
+            blocks.push(MarkdownBlock {
+                heading: Some(line),
+                level: caps.get(1).unwrap().as_str().len(),
+                section: template_heading_section(
+                    caps.get(2).unwrap().as_str(),
+                    custom_sections,
+                ),
+                content: Vec::new(),
+            });
+        } else {
+            blocks.last_mut().unwrap().content.push(line);
+        }
+    }
+
+    blocks
+}
+
+/// Matches the hidden marker spr puts into Pull Request descriptions that it
+/// built from a Pull Request template
+fn template_marker_regex() -> &'static lazy_regex::Regex {
+    lazy_regex::regex!(r#"^\s*<!--\s*spr-template\s*-->\s*$"#)
+}
+
+/// Whether the Pull Request description was built from a Pull Request
+/// template. Only the marker tells: a plain description may well have
+/// Markdown headings that look like those of a template.
+pub fn is_templated_body(text: &str) -> bool {
+    text.lines()
+        .any(|line| template_marker_regex().is_match(line))
+}
+
+/// Build the description of a Pull Request by filling in a template: the
+/// text under each heading that stands for a message section (e.g. "## Test
+/// Plan") is replaced by that section, everything else in the template (like
+/// checklists) is kept as it is. Sections that the template has no heading
+/// for are added at the end, followed by a hidden marker, so that the
+/// description is recognised as built from a template later on.
+pub fn build_github_body_from_template(
+    section_texts: &MessageSectionsMap,
+    template: &str,
+    custom_sections: &[CustomSection],
+) -> String {
+    let template = template
+        .lines()
+        .filter(|line| !template_marker_regex().is_match(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let blocks = split_markdown_blocks(&template, custom_sections);
+    let mut lines = Vec::<String>::new();
+    let mut filled = std::collections::HashSet::<MessageSection>::new();
+
+    for block in &blocks {
+        lines.extend(block.heading.map(String::from));
+
+        match &block.section {
+            Some(section) if filled.insert(section.clone()) => {
+                lines.push(String::new());
+                let text = replace_keeping_preserved_regions(
+                    &block.content,
+                    section_texts.get(section).map_or("", |t| &t[..]),
+                );
+                if !text.is_empty() {
+                    lines.push(text);
+                    lines.push(String::new());
+                }
+            }
+            _ => lines.extend(block.content.iter().map(|l| l.to_string())),
+        }
+    }
+
+    let level = blocks
+        .iter()
+        .find(|block| block.section.is_some())
+        .map(|block| block.level)
+        .unwrap_or(2);
+    let mut result = lines.join("\n").trim_end().to_string();
+
+    for section in github_body_sections(custom_sections) {
+        if filled.contains(&section) {
+            continue;
+        }
+        if let Some(text) =
+            section_texts.get(&section).filter(|t| !t.is_empty())
+        {
+            if !result.is_empty() {
+                result.push_str("\n\n");
+            }
+            result.push_str(&format!(
+                "{} {}\n\n{}",
+                "#".repeat(level),
+                message_section_label(&section),
+                text
+            ));
+        }
+    }
+
+    if !result.is_empty() {
+        result.push_str("\n\n");
+    }
+    result.push_str("<!-- spr-template -->\n");
+    with_change_id_marker(result, section_texts)
+}
+
+/// Parse the description of a Pull Request into message sections. This
+/// understands descriptions spr built from a Pull Request template as well as
+/// the ones it builds by default.
+pub fn parse_github_body(
+    body: &str,
+    custom_sections: &[CustomSection],
+) -> MessageSectionsMap {
+    let change_id = change_id_from_github_body(body);
+    let templated = is_templated_body(body);
+
+    // Whatever bots added to the description does not belong into the
+    // commit message. In a description built from a template, that is
+    // decided for each of its sections, as they are filled in one by one.
+    let mut sections = if !templated {
+        parse_message(
+            &strip_preserved_regions(body),
+            MessageSection::Summary,
+            custom_sections,
+        )
+    } else {
+        let mut sections = MessageSectionsMap::new();
+        for block in split_markdown_blocks(body, custom_sections) {
+            if let Some(section) = block.section {
+                let text = trim_blank_lines(&strip_preserved_regions(
+                    &block.content.join("\n"),
+                ))
+                .to_string();
+                if !text.is_empty() {
+                    sections.entry(section).or_insert(text);
+                }
+            }
+        }
+        sections
+    };
+
+    if let Some(change_id) = change_id {
+        sections.insert(MessageSection::ChangeId, change_id);
+    }
+
+    sections
+}
+
+/// The sections that are kept in sync between a commit message and the title
+/// and description of its Pull Request
+pub fn synced_sections(
+    custom_sections: &[CustomSection],
+) -> Vec<MessageSection> {
+    std::iter::once(MessageSection::Title)
+        .chain(github_body_sections(custom_sections))
+        .collect()
+}
+
+/// A section that was changed differently in the commit message and on
+/// GitHub since they were last in sync
+#[derive(Debug, PartialEq, Eq)]
+pub struct SectionConflict {
+    pub section: MessageSection,
+    pub local: String,
+    pub remote: String,
+}
+
+/// Merge the synced sections of a local commit message and a Pull Request,
+/// given their state when they were last in sync (if known). A section that
+/// was changed on one side only gets that change. Sections that were changed
+/// on both sides in different ways are returned as conflicts, and are missing
+/// from the merged sections.
+pub fn merge_sections(
+    base: Option<&MessageSectionsMap>,
+    local: &MessageSectionsMap,
+    remote: &MessageSectionsMap,
+    custom_sections: &[CustomSection],
+) -> (MessageSectionsMap, Vec<SectionConflict>) {
+    let text = |sections: &MessageSectionsMap, section: &MessageSection| {
+        sections.get(section).cloned().unwrap_or_default()
+    };
+
+    let mut merged = MessageSectionsMap::new();
+    let mut conflicts = Vec::new();
+
+    for section in synced_sections(custom_sections) {
+        let local = text(local, &section);
+        let remote = text(remote, &section);
+        let base = base.map(|base| text(base, &section));
+
+        let result = if local == remote || base.as_ref() == Some(&remote) {
+            local
+        } else if base.as_ref() == Some(&local) {
+            remote
+        } else {
+            conflicts.push(SectionConflict {
+                section,
+                local,
+                remote,
+            });
+            continue;
+        };
+
+        if !result.is_empty() {
+            merged.insert(section, result);
+        }
+    }
+
+    (merged, conflicts)
+}
+
+/// Copy the custom sections that are not part of the Pull Request description
+/// from a local commit message into a message built from a Pull Request, where
+/// they would otherwise be lost.
+pub fn keep_local_sections(
+    message: &mut MessageSectionsMap,
+    local_message: &MessageSectionsMap,
+    custom_sections: &[CustomSection],
+) {
+    for custom in custom_sections.iter().filter(|c| !c.in_github_body) {
+        let section = custom.section();
+        if let Some(text) = local_message.get(&section) {
+            message.insert(section, text.clone());
+        }
+    }
+}
+
 pub fn validate_commit_message(
     message: &MessageSectionsMap,
     config: &crate::config::Config,
@@ -208,6 +1027,20 @@ pub fn validate_commit_message(
         bail!("Commit message does not have a Test Plan!");
     }
 
+    for custom in config.custom_sections.iter().filter(|c| c.required) {
+        let missing_or_empty = match message.get(&custom.section()) {
+            None => true,
+            Some(text) => text.is_empty(),
+        };
+        if missing_or_empty {
+            output(
+                "💔",
+                &format!("Commit message does not have a {}!", custom.label),
+            )?;
+            bail!("Commit message does not have a {}!", custom.label);
+        }
+    }
+
     let title_missing_or_empty = match message.get(&MessageSection::Title) {
         None => true,
         Some(title) => title.is_empty(),
@@ -228,7 +1061,7 @@ mod tests {
     #[test]
     fn test_parse_empty() {
         assert_eq!(
-            parse_message("", MessageSection::Title),
+            parse_message("", MessageSection::Title, &[]),
             [(MessageSection::Title, "".to_string())].into()
         );
     }
@@ -236,15 +1069,15 @@ mod tests {
     #[test]
     fn test_parse_title() {
         assert_eq!(
-            parse_message("Hello", MessageSection::Title),
+            parse_message("Hello", MessageSection::Title, &[]),
             [(MessageSection::Title, "Hello".to_string())].into()
         );
         assert_eq!(
-            parse_message("Hello\n", MessageSection::Title),
+            parse_message("Hello\n", MessageSection::Title, &[]),
             [(MessageSection::Title, "Hello".to_string())].into()
         );
         assert_eq!(
-            parse_message("\n\nHello\n\n", MessageSection::Title),
+            parse_message("\n\nHello\n\n", MessageSection::Title, &[]),
             [(MessageSection::Title, "Hello".to_string())].into()
         );
     }
@@ -252,7 +1085,7 @@ mod tests {
     #[test]
     fn test_parse_title_and_summary() {
         assert_eq!(
-            parse_message("Hello\nFoo Bar", MessageSection::Title),
+            parse_message("Hello\nFoo Bar", MessageSection::Title, &[]),
             [
                 (MessageSection::Title, "Hello".to_string()),
                 (MessageSection::Summary, "Foo Bar".to_string())
@@ -260,7 +1093,7 @@ mod tests {
             .into()
         );
         assert_eq!(
-            parse_message("Hello\n\nFoo Bar", MessageSection::Title),
+            parse_message("Hello\n\nFoo Bar", MessageSection::Title, &[]),
             [
                 (MessageSection::Title, "Hello".to_string()),
                 (MessageSection::Summary, "Foo Bar".to_string())
@@ -268,7 +1101,7 @@ mod tests {
             .into()
         );
         assert_eq!(
-            parse_message("Hello\n\n\nFoo Bar", MessageSection::Title),
+            parse_message("Hello\n\n\nFoo Bar", MessageSection::Title, &[]),
             [
                 (MessageSection::Title, "Hello".to_string()),
                 (MessageSection::Summary, "Foo Bar".to_string())
@@ -276,7 +1109,11 @@ mod tests {
             .into()
         );
         assert_eq!(
-            parse_message("Hello\n\nSummary:\nFoo Bar", MessageSection::Title),
+            parse_message(
+                "Hello\n\nSummary:\nFoo Bar",
+                MessageSection::Title,
+                &[]
+            ),
             [
                 (MessageSection::Title, "Hello".to_string()),
                 (MessageSection::Summary, "Foo Bar".to_string())
@@ -299,7 +1136,8 @@ the
 summary (it's not a "Test plan:"!)
 
 Reviewer:    a, b, c"#,
-                MessageSection::Title
+                MessageSection::Title,
+                &[]
             ),
             [
                 (MessageSection::Title, "Hello".to_string()),
@@ -314,4 +1152,656 @@ Reviewer:    a, b, c"#,
             .into()
         );
     }
+
+    fn custom_sections() -> Vec<CustomSection> {
+        vec![
+            CustomSection {
+                aliases: vec!["Risks".into()],
+                required: true,
+                ..CustomSection::new("Risk".into())
+            },
+            CustomSection {
+                in_github_body: false,
+                after: MessageSection::PullRequest,
+                ..CustomSection::new("Jira".into())
+            },
+            CustomSection::new("Rollout".into()),
+        ]
+    }
+
+    #[test]
+    fn test_parse_custom_sections() {
+        let custom = custom_sections();
+
+        assert_eq!(
+            parse_message(
+                "Hello\n\nFoo\n\nRisks: low\nJIRA: ABC-1\nTest Plan: none",
+                MessageSection::Title,
+                &custom
+            ),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, "Foo".to_string()),
+                (MessageSection::TestPlan, "none".to_string()),
+                (MessageSection::Custom("Risk".into()), "low".to_string()),
+                (MessageSection::Custom("Jira".into()), "ABC-1".to_string()),
+            ]
+            .into()
+        );
+
+        // Without the definitions, the labels are just part of the summary
+        assert_eq!(
+            parse_message("Hello\n\nRisk: low", MessageSection::Title, &[]),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, "Risk: low".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_build_custom_sections() {
+        let custom = custom_sections();
+        let message = parse_message(
+            "Hello\n\nFoo\n\nRollout: anytime\nRisk: low\nJira: ABC-1\n\
+             Test Plan: none\nPull Request: #12",
+            MessageSection::Title,
+            &custom,
+        );
+
+        assert_eq!(
+            build_commit_message(&message, &custom, MetadataFormat::Sections),
+            "Hello\n\nFoo\n\nTest Plan: none\n\nRisk: low\n\n\
+             Rollout: anytime\n\nPull Request: #12\n\nJira: ABC-1\n"
+        );
+        assert_eq!(
+            build_github_body(&message, &custom),
+            "Foo\n\nTest Plan: none\n\nRisk: low\n\nRollout: anytime\n"
+        );
+    }
+
+    #[test]
+    fn test_keep_local_sections() {
+        let custom = custom_sections();
+        let local = parse_message(
+            "Hello\n\nRisk: high\nJira: ABC-1",
+            MessageSection::Title,
+            &custom,
+        );
+        let mut message =
+            parse_message("Hello\n\nRisk: low", MessageSection::Title, &custom);
+        keep_local_sections(&mut message, &local, &custom);
+
+        assert_eq!(
+            message,
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, "".to_string()),
+                (MessageSection::Custom("Risk".into()), "low".to_string()),
+                (MessageSection::Custom("Jira".into()), "ABC-1".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    const TEMPLATE: &str = "<!-- Please fill in the sections below -->
+
+## Description
+
+<!-- What does this change do? -->
+
+## Test Plan
+
+## Checklist
+
+- [ ] Tests added
+- [ ] Docs updated
+
+```
+## Summary (not a heading in a code block)
+```
+";
+
+    #[test]
+    fn test_build_github_body_from_template() {
+        let message = parse_message(
+            "Hello\n\nFoo\nBar\n\nTest Plan: none\nReviewers: a",
+            MessageSection::Title,
+            &[],
+        );
+
+        assert_eq!(
+            build_github_body_from_template(&message, TEMPLATE, &[]),
+            "<!-- Please fill in the sections below -->
+
+## Description
+
+<!-- What does this change do? -->
+
+Foo
+Bar
+
+## Test Plan
+
+none
+
+## Checklist
+
+- [ ] Tests added
+- [ ] Docs updated
+
+```
+## Summary (not a heading in a code block)
+```
+
+<!-- spr-template -->
+"
+        );
+    }
+
+    #[test]
+    fn test_build_github_body_from_template_missing_headings() {
+        let custom = custom_sections();
+        let message = parse_message(
+            "Hello\n\nFoo\n\nTest Plan: none\nRisk: low\nJira: ABC-1",
+            MessageSection::Title,
+            &custom,
+        );
+
+        assert_eq!(
+            build_github_body_from_template(
+                &message,
+                "### Summary\n\n### Checklist\n- [ ] Tests added\n",
+                &custom
+            ),
+            "### Summary\n\nFoo\n\n### Checklist\n- [ ] Tests added\n\n\
+             ### Test Plan\n\nnone\n\n### Risk\n\nlow\n\n\
+             <!-- spr-template -->\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_github_body_from_template() {
+        let message = parse_message(
+            "Hello\n\nFoo\n\nTest Plan: none",
+            MessageSection::Title,
+            &[],
+        );
+        let body = build_github_body_from_template(&message, TEMPLATE, &[]);
+
+        // Somebody ticks a checkbox on GitHub
+        let body = body.replace("- [ ] Tests added", "- [x] Tests added");
+
+        let sections = parse_github_body(&body, &[]);
+        assert_eq!(
+            sections,
+            [
+                (MessageSection::Summary, "Foo".to_string()),
+                (MessageSection::TestPlan, "none".to_string()),
+            ]
+            .into()
+        );
+
+        // Filling the description in again keeps the ticked checkbox
+        assert!(is_templated_body(&body));
+        assert_eq!(build_github_body_from_template(&message, &body, &[]), body);
+    }
+
+    #[test]
+    fn test_parse_github_body_without_template() {
+        assert!(!is_templated_body("Foo\n\nTest Plan: none\n"));
+        assert_eq!(
+            parse_github_body("Foo\n\nTest Plan: none\n", &[]),
+            [
+                (MessageSection::Summary, "Foo".to_string()),
+                (MessageSection::TestPlan, "none".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_github_body_with_headings_in_summary() {
+        let message = parse_message(
+            "Hello\n\nFoo\n\n## Testing\n\nRun it twice\n\nTest Plan: none",
+            MessageSection::Title,
+            &[],
+        );
+        let body = build_github_body(&message, &[]);
+        assert!(!is_templated_body(&body));
+        assert_eq!(
+            parse_github_body(&body, &[]),
+            [
+                (
+                    MessageSection::Summary,
+                    "Foo\n\n## Testing\n\nRun it twice".to_string()
+                ),
+                (MessageSection::TestPlan, "none".to_string()),
+            ]
+            .into()
+        );
+        assert_eq!(build_github_body_for_update(&message, &body, &[]), body);
+    }
+
+    #[test]
+    fn test_parse_trailers() {
+        assert_eq!(
+            parse_message(
+                "Hello\n\nFoo\n\nTest Plan: none\n\nReviewers: a, b\n\
+                 Reviewed-by: a\nreviewed-BY: b\n\
+                 Pull-Request: https://github.com/acme/codez/pull/1",
+                MessageSection::Title,
+                &[]
+            ),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, "Foo".to_string()),
+                (MessageSection::TestPlan, "none".to_string()),
+                (MessageSection::Reviewers, "a, b".to_string()),
+                (MessageSection::ReviewedBy, "a, b".to_string()),
+                (
+                    MessageSection::PullRequest,
+                    "https://github.com/acme/codez/pull/1".to_string()
+                ),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_hyphenated_labels() {
+        // Only the metadata sections have a hyphenated form
+        assert_eq!(
+            parse_message(
+                "Hello\n\nTest-Plan: none\nRisk-Level: low\n\nTest Plan: some",
+                MessageSection::Title,
+                &custom_sections()
+            ),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (
+                    MessageSection::Summary,
+                    "Test-Plan: none\nRisk-Level: low".to_string()
+                ),
+                (MessageSection::TestPlan, "some".to_string()),
+            ]
+            .into()
+        );
+
+        // Repeated sections are joined as paragraphs, but repeated reviewers
+        // as a list
+        assert_eq!(
+            parse_message(
+                "Hello\n\nTest Plan: one\nTest Plan: two\n\n\
+                 Reviewers: a\nReviewers: b\n\n\
+                 Reviewed By: a\nReviewed-by: b",
+                MessageSection::Title,
+                &[]
+            ),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, String::new()),
+                (MessageSection::TestPlan, "one\n\ntwo".to_string()),
+                (MessageSection::Reviewers, "a\n\nb".to_string()),
+                (MessageSection::ReviewedBy, "a, b".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_convert_metadata_format() {
+        let sections = "Hello\n\nFoo\n\nTest Plan: none\n\nReviewers: a, b\n\n\
+                        Reviewed By: a, b\n\n\
+                        Pull Request: https://github.com/acme/codez/pull/1\n";
+        let trailers = "Hello\n\nFoo\n\nTest Plan: none\n\nReviewers: a, b\n\
+                        Reviewed-by: a\nReviewed-by: b\n\
+                        Pull-Request: https://github.com/acme/codez/pull/1\n";
+
+        let message = parse_message(sections, MessageSection::Title, &[]);
+        assert_eq!(
+            build_commit_message(&message, &[], MetadataFormat::Trailers),
+            trailers
+        );
+
+        let message = parse_message(trailers, MessageSection::Title, &[]);
+        assert_eq!(
+            build_commit_message(&message, &[], MetadataFormat::Sections),
+            sections
+        );
+    }
+
+    #[test]
+    fn test_metadata_note() {
+        let message = parse_message(
+            "Hello\n\nFoo\n\nTest Plan: none\n\nReviewers: a, b\n\n\
+             Pull Request: https://github.com/acme/codez/pull/1\n",
+            MessageSection::Title,
+            &[],
+        );
+
+        assert_eq!(
+            build_commit_message(&message, &[], MetadataFormat::Notes),
+            "Hello\n\nFoo\n\nTest Plan: none\n"
+        );
+
+        let note = build_metadata_note(&message);
+        assert_eq!(
+            note,
+            "Reviewers: a, b\n\n\
+             Pull Request: https://github.com/acme/codez/pull/1\n"
+        );
+        assert_eq!(
+            parse_metadata_note(&note, &[]),
+            [
+                (MessageSection::Reviewers, "a, b".to_string()),
+                (
+                    MessageSection::PullRequest,
+                    "https://github.com/acme/codez/pull/1".to_string()
+                ),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_change_id_marker() {
+        let message = parse_message(
+            "Hello\n\nFoo\n\nChange-Id: I0123",
+            MessageSection::Title,
+            &[],
+        );
+        let body = build_github_body(&message, &[]);
+        assert_eq!(body, "Foo\n\n<!-- spr-change-id: I0123 -->\n");
+        assert_eq!(
+            parse_github_body(&body, &[]),
+            [
+                (MessageSection::Summary, "Foo".to_string()),
+                (MessageSection::ChangeId, "I0123".to_string()),
+            ]
+            .into()
+        );
+
+        // The marker stays at the end, after regions added by bots
+        let body =
+            format!("{}\n<!-- bot start -->\nHi\n<!-- bot end -->\n", body);
+        let message = parse_message(
+            "Hello\n\nBar\n\nChange-Id: I0123",
+            MessageSection::Title,
+            &[],
+        );
+        assert_eq!(
+            build_github_body_for_update(&message, &body, &[]),
+            "Bar\n\n<!-- bot start -->\nHi\n<!-- bot end -->\n\n\
+             <!-- spr-change-id: I0123 -->\n"
+        );
+
+        assert_eq!(
+            set_change_id_marker("Foo\n", "I4567"),
+            "Foo\n\n<!-- spr-change-id: I4567 -->\n"
+        );
+        assert_eq!(
+            change_id_from_github_body(&set_change_id_marker(&body, "I4567")),
+            Some("I4567".to_string())
+        );
+    }
+
+    #[test]
+    fn test_trailers_after_custom_sections() {
+        let custom = custom_sections();
+        let message = parse_message(
+            "Hello\n\nFoo\n\nJira: ABC-1\nPull Request: #1",
+            MessageSection::Title,
+            &custom,
+        );
+
+        assert_eq!(
+            build_commit_message(&message, &custom, MetadataFormat::Trailers),
+            "Hello\n\nFoo\n\nJira: ABC-1\n\nPull-Request: #1\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_code_blocks_and_quotes() {
+        let summary = "Configure the thing like this:
+
+```yaml
+summary: foo
+test plan: x
+```
+
+~~~~
+````
+Reviewers: nobody
+~~~~
+
+    Test Plan: indented code
+
+> Pull Request: quoted";
+
+        assert_eq!(
+            parse_message(
+                &format!("Hello\n\n{}\n\nTest Plan: none", summary),
+                MessageSection::Title,
+                &[]
+            ),
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::Summary, summary.to_string()),
+                (MessageSection::TestPlan, "none".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_markdown_round_trip() {
+        let message = "Hello
+
+A line with a hard break  
+and a list:
+
+- one
+  - nested
+
+```rust
+fn main() {}
+```
+
+Test Plan:
+    cargo test
+    cargo clippy
+
+Reviewers: a, b
+";
+
+        let sections = parse_message(message, MessageSection::Title, &[]);
+        assert_eq!(
+            sections.get(&MessageSection::TestPlan).unwrap(),
+            "    cargo test\n    cargo clippy"
+        );
+        assert_eq!(
+            build_commit_message(&sections, &[], MetadataFormat::Sections),
+            message
+        );
+
+        // A single indented line goes on a line of its own, too
+        let sections: MessageSectionsMap = [
+            (MessageSection::Title, "Hello".to_string()),
+            (MessageSection::TestPlan, "    cargo test".to_string()),
+        ]
+        .into();
+        let message =
+            build_commit_message(&sections, &[], MetadataFormat::Sections);
+        assert_eq!(message, "Hello\n\nTest Plan:\n    cargo test\n");
+        assert_eq!(
+            parse_message(&message, MessageSection::Title, &[])
+                .get(&MessageSection::TestPlan)
+                .unwrap(),
+            "    cargo test"
+        );
+    }
+
+    const BOT_BODY: &str = "<!-- dependabot-note -->
+
+Foo
+
+<!-- readthedocs-preview codez start -->
+----
+📚 Documentation preview 📚: https://codez.readthedocs.io/12/
+<!-- readthedocs-preview codez end -->
+
+Test Plan: none
+
+<!-- This is an auto-generated comment: release notes by bot.ai -->
+## Summary by bot
+* New feature
+<!-- end of auto-generated comment: release notes by bot.ai -->
+";
+
+    #[test]
+    fn test_preserved_regions() {
+        let lines: Vec<&str> = BOT_BODY.lines().collect();
+        assert_eq!(preserved_regions(&lines), vec![0..1, 4..8, 11..15]);
+
+        // Comments in code blocks don't count, nor do end markers without a
+        // start
+        let lines = vec!["```", "<!-- x -->", "```", "<!-- x end -->"];
+        assert_eq!(preserved_regions(&lines), vec![3..4]);
+
+        // A comment spanning several lines, after the content
+        let lines = vec!["Foo", "<!--", "bar", "-->"];
+        assert_eq!(preserved_regions(&lines), vec![1..4]);
+
+        // Comments between content are part of it
+        let lines = vec!["Foo", "<!--", "bar", "-->", "baz", "<!-- x -->"];
+        assert_eq!(preserved_regions(&lines), vec![5..6]);
+    }
+
+    #[test]
+    fn test_parse_github_body_with_bot_content() {
+        assert_eq!(
+            parse_github_body(BOT_BODY, &[]),
+            [
+                (MessageSection::Summary, "Foo".to_string()),
+                (MessageSection::TestPlan, "none".to_string()),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_build_github_body_for_update() {
+        let message: MessageSectionsMap = [
+            (MessageSection::Summary, "Bar".to_string()),
+            (MessageSection::TestPlan, "none".to_string()),
+        ]
+        .into();
+
+        // The bot content that came first stays in front, all other goes to
+        // the end
+        let body = build_github_body_for_update(&message, BOT_BODY, &[]);
+        assert_eq!(
+            body,
+            "<!-- dependabot-note -->
+
+Bar
+
+Test Plan: none
+
+<!-- readthedocs-preview codez start -->
+----
+📚 Documentation preview 📚: https://codez.readthedocs.io/12/
+<!-- readthedocs-preview codez end -->
+
+<!-- This is an auto-generated comment: release notes by bot.ai -->
+## Summary by bot
+* New feature
+<!-- end of auto-generated comment: release notes by bot.ai -->
+"
+        );
+
+        // ...and after that, it stays put
+        assert_eq!(build_github_body_for_update(&message, &body, &[]), body);
+
+        // Comments in the commit message are not mistaken for bot content
+        let message: MessageSectionsMap = [
+            (
+                MessageSection::Summary,
+                "Foo\n<!-- keep me -->\nBar".to_string(),
+            ),
+            (MessageSection::TestPlan, "none".to_string()),
+        ]
+        .into();
+        let body = build_github_body(&message, &[]);
+        let updated = build_github_body_for_update(&message, &body, &[]);
+        assert_eq!(updated, body);
+        assert_eq!(build_github_body_for_update(&message, &updated, &[]), body);
+        assert_eq!(parse_github_body(&body, &[]), message);
+
+        // Without bot content, this is just the plain description
+        let message: MessageSectionsMap = [
+            (MessageSection::Summary, "Bar".to_string()),
+            (MessageSection::TestPlan, "none".to_string()),
+        ]
+        .into();
+        assert_eq!(
+            build_github_body_for_update(&message, "Foo\n", &[]),
+            build_github_body(&message, &[])
+        );
+        assert_eq!(
+            build_github_body_for_update(&MessageSectionsMap::new(), "", &[]),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_merge_sections() {
+        let sections = |title: &str, summary: &str, test_plan: &str| {
+            parse_message(
+                &format!(
+                    "{}\n\n{}\n\nTest Plan: {}",
+                    title, summary, test_plan
+                ),
+                MessageSection::Title,
+                &[],
+            )
+        };
+        let base = sections("Hello", "Foo", "none");
+
+        // Title changed on GitHub, summary changed locally, test plan changed
+        // on both sides in the same way
+        let local = sections("Hello", "Foo bar", "some");
+        let remote = sections("Hello!", "Foo", "some");
+        assert_eq!(
+            merge_sections(Some(&base), &local, &remote, &[]),
+            (sections("Hello!", "Foo bar", "some"), vec![])
+        );
+
+        // Summary changed on both sides in different ways
+        let remote = sections("Hello", "Foo baz", "none");
+        let (merged, conflicts) =
+            merge_sections(Some(&base), &local, &remote, &[]);
+        assert_eq!(
+            merged,
+            [
+                (MessageSection::Title, "Hello".to_string()),
+                (MessageSection::TestPlan, "some".to_string()),
+            ]
+            .into()
+        );
+        assert_eq!(
+            conflicts,
+            vec![SectionConflict {
+                section: MessageSection::Summary,
+                local: "Foo bar".into(),
+                remote: "Foo baz".into(),
+            }]
+        );
+
+        // Without a base, every difference is a conflict
+        let (_, conflicts) = merge_sections(None, &local, &base, &[]);
+        assert_eq!(conflicts.len(), 2);
+    }
 }
@@ -42,3 +42,19 @@ pub fn write_commit_title(prepared_commit: &PreparedCommit) -> Result<()> {
     ))?;
     Ok(())
 }
+
+/// Ask the user a yes/no question. If nobody is there to answer (e.g. because
+/// spr runs in a script), the default answer is taken.
+pub async fn confirm(prompt: String, default: bool) -> Result<bool> {
+    if !console::user_attended() {
+        return Ok(default);
+    }
+
+    Ok(tokio::task::spawn_blocking(move || {
+        dialoguer::Confirm::new()
+            .with_prompt(prompt)
+            .default(default)
+            .interact()
+    })
+    .await??)
+}
@@ -40,6 +40,19 @@ pub fn remove_all_parens(text: &str) -> String {
     lazy_regex::regex!(r#"[()]"#).replace_all(text, "").into()
 }
 
+/// A short human-readable form of a duration given in seconds, e.g. "3d" or
+/// "5h"
+pub fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    if seconds >= 2 * 86400 {
+        format!("{}d", seconds / 86400)
+    } else if seconds >= 3600 {
+        format!("{}h", seconds / 3600)
+    } else {
+        format!("{}m", seconds / 60)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -105,4 +118,13 @@ mod tests {
             expected
         );
     }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(-5), "0m");
+        assert_eq!(format_duration(59 * 60), "59m");
+        assert_eq!(format_duration(3 * 3600 + 59), "3h");
+        assert_eq!(format_duration(47 * 3600), "47h");
+        assert_eq!(format_duration(3 * 86400 + 3600), "3d");
+    }
 }
@@ -0,0 +1,1175 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! End-to-end tests that run spr commands against a mock GitHub server and a
+//! local bare repository, and check the resulting branch graphs.
+
+mod harness;
+
+use harness::{MockPullRequestState, MockReview, TestEnv, VIEWER};
+use spr::{github::HeadRepository, message::MetadataFormat};
+
+/// Recreate the given commit on top of HEAD, with the file contents changed
+/// and the commit message (including any Pull Request section) kept.
+fn recommit(
+    env: &TestEnv,
+    files: &[(&str, &str)],
+    old: git2::Oid,
+) -> git2::Oid {
+    let message = env.message(old);
+    env.commit(files, &message)
+}
+
+#[test]
+fn test_diff_amend_rebase_land() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // Create a Pull Request for a single commit
+    let a1 = env.commit(&[("a", "a1\n")], "Add a\n\nTest Plan: none");
+    env.spr_diff(&[]).unwrap();
+
+    let pr = env.mock.pull_request(1);
+    assert_eq!(pr.title, "Add a");
+    assert_eq!(pr.base, "master");
+    assert_eq!(pr.head, "spr/test/add-a");
+    assert!(
+        env.message(env.head())
+            .contains("Pull Request: https://github.com/acme/codez/pull/1")
+    );
+
+    let head1 = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.parents(head1), vec![master0]);
+    assert_eq!(env.tree(head1), env.tree(a1));
+
+    // Amend the commit: the Pull Request branch gets a new commit on top
+    let a1 = env.head();
+    env.reset_hard(master0);
+    let a2 = recommit(&env, &[("a", "a2\n")], a1);
+    env.spr_diff(&["-m", "review comments"]).unwrap();
+
+    let head2 = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.parents(head2), vec![head1]);
+    assert_eq!(env.tree(head2), env.tree(a2));
+
+    // Rebase on a newer master: master gets merged into the Pull Request
+    // branch, and the Pull Request keeps targeting master directly
+    let master1 = env.advance_remote_master("b", "b\n");
+    env.fetch_master();
+    env.reset_hard(master1);
+    let a3 = recommit(&env, &[("a", "a2\n")], a2);
+    env.spr_diff(&["-m", "rebase"]).unwrap();
+
+    let head3 = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.parents(head3), vec![head2, master1]);
+    assert_eq!(env.tree(head3), env.tree(a3));
+    assert_eq!(env.mock.pull_request(1).base, "master");
+
+    // No changes: nothing gets pushed
+    env.spr_diff(&["-m", "nothing"]).unwrap();
+    assert_eq!(env.remote_branch("spr/test/add-a"), Some(head3));
+
+    // Land: master gets one new commit with the tree of the local commit, the
+    // local branch is rebased onto it, and the Pull Request branch is deleted
+    assert!(env.git.repo().find_reference("refs/spr/sync/1").is_ok());
+    env.spr_land(&[]).unwrap();
+    assert!(env.git.repo().find_reference("refs/spr/sync/1").is_err());
+
+    let master2 = env.remote_branch("master").unwrap();
+    assert_eq!(env.parents(master2), vec![master1]);
+    assert_eq!(env.tree(master2), env.tree(a3));
+    assert_eq!(env.head(), master2);
+    assert_eq!(env.remote_branch("spr/test/add-a"), None);
+
+    let pr = env.mock.pull_request(1);
+    assert_eq!(pr.state, MockPullRequestState::Merged);
+    assert_eq!(pr.merge_commit, Some(master2));
+}
+
+#[test]
+fn test_stack_with_base_branch() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // A stack of two commits: the upper one gets a base branch
+    env.commit(&[("a", "a1\n")], "Add a\n\nTest Plan: none");
+    let b1 = env.commit(&[("b", "b1\n")], "Add b\n\nTest Plan: none");
+    env.spr_diff(&["--all"]).unwrap();
+
+    let a1 = env.parents(env.head())[0];
+    let b1_with_pr = env.head();
+    assert_eq!(env.tree(b1_with_pr), env.tree(b1));
+
+    let pr_a = env.mock.pull_request(1);
+    let pr_b = env.mock.pull_request(2);
+    assert_eq!(pr_a.base, "master");
+    assert_eq!(pr_b.base, "spr/test/master.add-b");
+    assert_eq!(pr_b.head, "spr/test/add-b");
+
+    let base1 = env.remote_branch("spr/test/master.add-b").unwrap();
+    assert_eq!(env.parents(base1), vec![master0]);
+    assert_eq!(env.tree(base1), env.tree(a1));
+
+    let head_b1 = env.remote_branch("spr/test/add-b").unwrap();
+    assert_eq!(env.parents(head_b1), vec![master0, base1]);
+    assert_eq!(env.tree(head_b1), env.tree(b1));
+
+    // Amend the bottom commit and restack: the base branch gets a new commit
+    // with the new parent tree, which is merged into the upper Pull Request
+    env.reset_hard(master0);
+    let a2 = recommit(&env, &[("a", "a2\n")], a1);
+    let b2 = recommit(&env, &[("b", "b1\n")], b1_with_pr);
+    env.spr_diff(&["--all", "-m", "update"]).unwrap();
+
+    let base2 = env.remote_branch("spr/test/master.add-b").unwrap();
+    assert_eq!(env.parents(base2), vec![base1]);
+    assert_eq!(env.tree(base2), env.tree(a2));
+
+    let head_b2 = env.remote_branch("spr/test/add-b").unwrap();
+    assert_eq!(env.parents(head_b2), vec![head_b1, base2]);
+    assert_eq!(env.tree(head_b2), env.tree(b2));
+
+    // Rebase the stack onto a newer master: the base branch also merges in
+    // the new master commit
+    let master1 = env.advance_remote_master("c", "c\n");
+    env.fetch_master();
+    env.reset_hard(master1);
+    let a3 = recommit(&env, &[("a", "a2\n")], a2);
+    let b3 = recommit(&env, &[("b", "b1\n")], b2);
+    env.spr_diff(&["--all", "-m", "rebase"]).unwrap();
+
+    let head_a3 = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.parents(head_a3)[1], master1);
+    assert_eq!(env.tree(head_a3), env.tree(a3));
+
+    let base3 = env.remote_branch("spr/test/master.add-b").unwrap();
+    assert_eq!(env.parents(base3), vec![base2, master1]);
+    assert_eq!(env.tree(base3), env.tree(a3));
+
+    let head_b3 = env.remote_branch("spr/test/add-b").unwrap();
+    assert_eq!(env.parents(head_b3), vec![head_b2, base3]);
+    assert_eq!(env.tree(head_b3), env.tree(b3));
+}
+
+#[test]
+fn test_pull_request_template() {
+    let mut env = TestEnv::new();
+
+    env.commit(
+        &[(
+            ".github/pull_request_template.md",
+            "## Summary\n\n## Test Plan\n\n## Checklist\n\n- [ ] Docs\n",
+        )],
+        "Add template\n\nAdd a template.\n\nTest Plan: none",
+    );
+    env.spr_diff(&[]).unwrap();
+
+    assert_eq!(
+        env.mock.pull_request(1).body,
+        "## Summary\n\nAdd a template.\n\n## Test Plan\n\nnone\n\n\
+         ## Checklist\n\n- [ ] Docs\n\n<!-- spr-template -->\n"
+    );
+
+    // The description is edited and the checklist ticked on GitHub
+    env.mock.update_pull_request(1, |pr| {
+        pr.body = pr
+            .body
+            .replace("Add a template.", "Add the template.")
+            .replace("- [ ] Docs", "- [x] Docs");
+    });
+
+    // spr amend brings the sections (but not the checklist) into the local
+    // commit message
+    env.spr_amend(&[]).unwrap();
+    let message = env.message(env.head());
+    assert!(message.contains("\n\nAdd the template.\n\nTest Plan: none\n"));
+    assert!(!message.contains("Docs"));
+
+    // Updating the Pull Request keeps the ticked checklist
+    env.spr_diff(&["--update-message"]).unwrap();
+    assert!(env.mock.pull_request(1).body.contains("- [x] Docs"));
+    assert!(
+        env.mock
+            .pull_request(1)
+            .body
+            .contains("## Test Plan\n\nnone\n")
+    );
+}
+
+#[test]
+fn test_sync() {
+    let mut env = TestEnv::new();
+
+    env.commit(&[("a", "a\n")], "Add a\n\nAdd a file.\n\nTest Plan: none");
+    env.spr_diff(&[]).unwrap();
+
+    // The title is edited on GitHub, and the summary locally
+    env.mock.update_pull_request(1, |pr| {
+        pr.title = "Add file a".into();
+    });
+    let message = env.message(env.head()).replace("Add a file.", "Add one.");
+    env.git
+        .repo()
+        .find_commit(env.head())
+        .unwrap()
+        .amend(Some("HEAD"), None, None, None, Some(&message), None)
+        .unwrap();
+
+    // Both changes end up on both sides
+    env.spr_sync(&[]).unwrap();
+    let message = env.message(env.head());
+    assert!(message.starts_with("Add file a\n\nAdd one.\n\n"));
+    let pr = env.mock.pull_request(1);
+    assert_eq!(pr.title, "Add file a");
+    assert!(pr.body.contains("Add one."));
+    assert!(!pr.body.contains("Add a file."));
+
+    // The test plan is changed on both sides: a conflict
+    env.mock.update_pull_request(1, |pr| {
+        pr.body = pr.body.replace("none", "remote");
+    });
+    let message = message.replace("Test Plan: none", "Test Plan: local");
+    env.git
+        .repo()
+        .find_commit(env.head())
+        .unwrap()
+        .amend(Some("HEAD"), None, None, None, Some(&message), None)
+        .unwrap();
+
+    env.spr_sync(&["--prefer-local"]).unwrap();
+    assert!(env.message(env.head()).contains("Test Plan: local"));
+    assert!(env.mock.pull_request(1).body.contains("local"));
+    assert!(!env.mock.pull_request(1).body.contains("remote"));
+}
+
+#[test]
+fn test_metadata_in_notes() {
+    let mut env = TestEnv::new();
+    env.config.metadata_format = MetadataFormat::Notes;
+    let master0 = env.head();
+
+    // Creating the Pull Request does not rewrite the local commit, but adds a
+    // note to it
+    let a1 =
+        env.commit(&[("a", "a1\n")], "Add a\n\nAdd a file.\n\nTest Plan: none");
+    env.spr_diff(&[]).unwrap();
+    assert_eq!(env.head(), a1);
+    assert_eq!(
+        env.note(a1).as_deref(),
+        Some("Pull Request: https://github.com/acme/codez/pull/1\n")
+    );
+
+    // Rewriting the commit message carries the note over
+    env.mock
+        .update_pull_request(1, |pr| pr.title = "Add file a".into());
+    env.spr_amend(&[]).unwrap();
+    let a2 = env.head();
+    assert_ne!(a2, a1);
+    assert_eq!(
+        env.message(a2),
+        "Add file a\n\nAdd a file.\n\nTest Plan: none\n"
+    );
+    assert!(env.note(a2).is_some());
+
+    // The note links the commit to its Pull Request
+    env.advance_remote_master("b", "b\n");
+    env.spr_diff(&["-m", "update"]).unwrap();
+    assert_eq!(env.head(), a2);
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 1);
+
+    // Nor does the landed commit contain any metadata
+    env.spr_land(&[]).unwrap();
+    let master = env.remote_branch("master").unwrap();
+    assert_eq!(env.parents(env.parents(master)[0]), vec![master0]);
+    assert_eq!(
+        env.message(master),
+        "Add file a (#1)\n\nAdd a file.\n\nTest Plan: none\n"
+    );
+}
+
+#[test]
+fn test_change_id() {
+    let mut env = TestEnv::new();
+    env.config.change_id = true;
+    let master0 = env.head();
+    let change_id = |message: &str| {
+        message
+            .lines()
+            .find_map(|line| line.strip_prefix("Change-Id: "))
+            .unwrap()
+            .to_string()
+    };
+
+    // The first spr diff gives the commit a Change-Id, which is recorded in
+    // the Pull Request
+    env.commit(&[("a", "a\n")], "Add a\n\nAdd a file.\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+    let a = env.head();
+    let change_id_a = change_id(&env.message(a));
+    assert!(
+        env.mock
+            .pull_request(1)
+            .body
+            .ends_with(&format!("<!-- spr-change-id: {} -->\n", change_id_a))
+    );
+
+    // Dropping the Pull Request section does not lead to a second Pull
+    // Request
+    env.reset_hard(master0);
+    let a = env.commit(
+        &[("a", "a\n")],
+        &format!(
+            "Add a\n\nAdd a file.\n\nTest Plan: none\n\nChange-Id: {}\n",
+            change_id_a
+        ),
+    );
+    env.spr_diff(&[]).unwrap();
+    assert_ne!(env.head(), a);
+    assert!(
+        env.message(env.head())
+            .contains("Pull Request: https://github.com/acme/codez/pull/1")
+    );
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 1);
+
+    // A second commit, which is then squashed into the first one
+    env.commit(&[("b", "b\n")], "Add b\n\nAdd b file.\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+    let change_id_b = change_id(&env.message(env.head()));
+
+    env.reset_hard(master0);
+    env.commit(
+        &[("a", "a\n"), ("b", "b\n")],
+        &format!(
+            "Add a and b\n\nAdd two files.\n\nTest Plan: none\n\n\
+             Change-Id: {}\n\nChange-Id: {}\n",
+            change_id_a, change_id_b
+        ),
+    );
+    let list_requests =
+        env.mock.request_count("GET", "/repos/acme/codez/pulls");
+    env.spr_diff(&["--all", "-m", "squash"]).unwrap();
+
+    // The open Pull Requests are listed once, for all that spr diff looks for
+    assert_eq!(
+        env.mock.request_count("GET", "/repos/acme/codez/pulls"),
+        list_requests + 1
+    );
+
+    // The squashed commit keeps the first Change-Id and its Pull Request
+    let message = env.message(env.head());
+    assert!(message.contains(&format!("Change-Id: {}\n", change_id_a)));
+    assert!(!message.contains(&change_id_b));
+    assert!(
+        message.contains("Pull Request: https://github.com/acme/codez/pull/1")
+    );
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 2);
+}
+
+#[test]
+fn test_close_orphans() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // A Pull Request for a commit on another local branch
+    env.commit(&[("c", "c\n")], "Add c\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+    let c = env.git.repo().find_commit(env.head()).unwrap();
+    env.git.repo().branch("other", &c, false).unwrap();
+    drop(c);
+
+    // A stack of two commits, of which the first one gets dropped
+    env.reset_hard(master0);
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none\n");
+    env.spr_diff(&["--all"]).unwrap();
+    let b = env.head();
+
+    env.reset_hard(master0);
+    recommit(&env, &[("b", "b\n")], b);
+
+    // Without asking to close orphans, they are only listed
+    env.spr_diff(&["--all", "-m", "drop a"]).unwrap();
+    assert_eq!(env.mock.pull_request(2).state, MockPullRequestState::Open);
+
+    // Somebody else's Pull Request on a branch with the same prefix is not
+    // ours to close
+    env.push_branch(env.head(), "spr/test/theirs");
+    let theirs =
+        env.mock
+            .open_pull_request("Theirs", "master", "spr/test/theirs");
+
+    env.spr_diff(&["--all", "--close-orphans"]).unwrap();
+    assert_eq!(env.mock.pull_request(1).state, MockPullRequestState::Open);
+    assert_eq!(env.mock.pull_request(2).state, MockPullRequestState::Closed);
+    assert_eq!(env.mock.pull_request(3).state, MockPullRequestState::Open);
+    assert_eq!(env.remote_branch("spr/test/add-a"), None);
+    assert!(env.git.repo().find_reference("refs/spr/sync/2").is_err());
+    assert!(env.git.repo().find_reference("refs/spr/sync/3").is_ok());
+    assert!(env.remote_branch("spr/test/add-b").is_some());
+    assert_eq!(
+        env.mock.pull_request(theirs).state,
+        MockPullRequestState::Open
+    );
+    assert!(env.remote_branch("spr/test/theirs").is_some());
+}
+
+#[test]
+fn test_adopt() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // A Pull Request opened on GitHub, for a branch that was pushed there
+    let a1 = env.commit(&[("a", "a1\n")], "Add a");
+    env.push_branch(a1, "feature-a");
+    let number = env.mock.open_pull_request("Add a", "master", "feature-a");
+
+    // Adopt it for a local commit
+    env.reset_hard(master0);
+    let a2 = env.commit(&[("a", "a2\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_adopt(&[&number.to_string()]).unwrap();
+    assert!(
+        env.message(env.head())
+            .contains("Pull Request: https://github.com/acme/codez/pull/1")
+    );
+
+    // spr diff updates the Pull Request branch with a new commit on top
+    env.spr_diff(&["-m", "update"]).unwrap();
+    let head = env.remote_branch("feature-a").unwrap();
+    assert_eq!(env.parents(head), vec![a1]);
+    assert_eq!(env.tree(head), env.tree(a2));
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 1);
+
+    // Pull Requests against other branches cannot be adopted
+    env.reset_hard(master0);
+    env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none\n");
+    env.push_branch(env.head(), "feature-b");
+    let number = env
+        .mock
+        .open_pull_request("Add b", "feature-a", "feature-b");
+    assert!(env.spr_adopt(&[&number.to_string()]).is_err());
+}
+
+#[test]
+fn test_relink_pull_request() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+
+    // The same commit, but without the Pull Request section, as if spr had
+    // died before writing it
+    env.reset_hard(master0);
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_diff(&["--relink"]).unwrap();
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 1);
+    assert!(
+        env.message(env.head())
+            .contains("Pull Request: https://github.com/acme/codez/pull/1")
+    );
+
+    // A commit with different content gets a Pull Request of its own
+    env.reset_hard(master0);
+    env.commit(&[("a", "other a\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_diff(&["--relink"]).unwrap();
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 2);
+    assert!(
+        env.message(env.head())
+            .contains("Pull Request: https://github.com/acme/codez/pull/2")
+    );
+
+    // So does one with the same content, but a title that spr would not have
+    // named the branch of Pull Request #1 after
+    env.reset_hard(master0);
+    env.commit(&[("a", "a\n")], "Add file a\n\nTest Plan: none\n");
+    env.spr_diff(&["--relink"]).unwrap();
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 3);
+
+    // Without --relink, nobody is there to agree to linking the commit
+    env.reset_hard(master0);
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+    assert_eq!(env.mock.state.lock().unwrap().pull_requests.len(), 4);
+}
+
+#[test]
+fn test_merge_master_into_stack() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+
+    // Somebody lands a change, and we merge master instead of rebasing
+    env.advance_remote_master("m", "m\n");
+    let master1 = env.fetch_master();
+    env.merge(master1, "Merge master");
+    env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none\n");
+    let head_tree = env.tree(env.head());
+
+    // The merge moves the stack's base to the new master commit
+    env.spr_diff(&["--all", "-m", "merge master"]).unwrap();
+    let head = env.head();
+    assert_eq!(env.tree(head), head_tree);
+    let a = env.parents(head)[0];
+    assert_eq!(env.parents(a), vec![master1]);
+    assert!(env.message(a).contains("pull/1"));
+    assert!(env.message(head).contains("pull/2"));
+
+    let pr_a = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.tree(pr_a), env.tree(a));
+    let pr_b = env.remote_branch("spr/test/add-b").unwrap();
+    assert_eq!(env.tree(pr_b), head_tree);
+
+    // Merging anything else is an error
+    env.reset_hard(master0);
+    let other = env.commit(&[("o", "o\n")], "Other branch");
+    env.reset_hard(head);
+    env.merge(other, "Merge other branch");
+    let error = env.spr_diff(&["--all"]).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("merges a branch other than master")
+    );
+}
+
+#[test]
+fn test_merge_master_into_stack_with_notes() {
+    let mut env = TestEnv::new();
+    env.config.metadata_format = MetadataFormat::Notes;
+
+    let a = env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.spr_diff(&[]).unwrap();
+
+    env.advance_remote_master("m", "m\n");
+    let master1 = env.fetch_master();
+    env.merge(master1, "Merge master");
+    let b = env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none\n");
+
+    // Looking at the stack rebases it in memory only, so the local branch
+    // stays as it is, and the notes go on its commits
+    env.spr_diff(&["--all", "-m", "merge master"]).unwrap();
+    assert_eq!(env.head(), b);
+    assert!(env.note(a).unwrap().contains("pull/1"));
+    assert!(env.note(b).unwrap().contains("pull/2"));
+
+    let pr_a = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.parents(pr_a)[1], master1);
+    let pr_b = env.remote_branch("spr/test/add-b").unwrap();
+    assert_eq!(env.tree(pr_b), env.tree(b));
+
+    // No commit other than those on the local branch has a note
+    let repo = env.git.repo();
+    for note in repo.notes(Some("refs/notes/spr")).unwrap() {
+        let (_, annotated) = note.unwrap();
+        assert!(annotated == a || annotated == b);
+    }
+}
+
+/// A stack of two commits, where landing the second one leaves the first one
+/// conflicting with master. Returns the HEAD commit before landing.
+fn land_with_conflict(env: &mut TestEnv) -> git2::Oid {
+    env.commit(&[("x", "a\n")], "Add x\n\nTest Plan: none\n");
+    env.commit(&[("y", "b\n")], "Add y\n\nTest Plan: none\n");
+    env.advance_remote_master("x", "master\n");
+    env.spr_diff(&["--cherry-pick"]).unwrap();
+    let head = env.head();
+
+    let error = env.spr_land(&["--cherry-pick"]).unwrap_err();
+    assert!(error.to_string().contains("spr continue"));
+    head
+}
+
+#[test]
+fn test_land_conflict_continue() {
+    let mut env = TestEnv::new();
+    land_with_conflict(&mut env);
+    let landed = env.remote_branch("master").unwrap();
+    assert_eq!(env.head(), landed);
+    assert_eq!(env.remote_branch("spr/test/add-y"), None);
+
+    // Nothing happens until the conflict is resolved
+    assert!(env.spr_continue().is_err());
+
+    env.stage(&[("x", "resolved\n")]);
+    env.spr_continue().unwrap();
+
+    // The landed commit is gone, the other one is rebased onto master
+    let head = env.head();
+    assert_eq!(env.parents(head), vec![landed]);
+    assert!(env.message(head).starts_with("Add x"));
+    let repo = env.git.repo();
+    let blob = repo
+        .find_commit(head)
+        .unwrap()
+        .tree()
+        .unwrap()
+        .get_path(std::path::Path::new("x"))
+        .unwrap()
+        .id();
+    assert_eq!(repo.find_blob(blob).unwrap().content(), b"resolved\n");
+    assert_eq!(repo.head().unwrap().name(), Some("refs/heads/master"));
+    assert!(env.spr_continue().is_err());
+}
+
+#[test]
+fn test_land_conflict_abort() {
+    let mut env = TestEnv::new();
+    env.config.metadata_format = MetadataFormat::Notes;
+    // The commit below the conflict is rebased before spr stops, and its
+    // note must not be copied when the rebase is aborted
+    env.commit(&[("w", "w\n")], "Add w\n\nTest Plan: none\n");
+    env.commit(&[("x", "a\n")], "Add x\n\nTest Plan: none\n");
+    env.commit(&[("y", "b\n")], "Add y\n\nTest Plan: none\n");
+    env.advance_remote_master("x", "master\n");
+    env.spr_diff(&["--all", "--cherry-pick"]).unwrap();
+    let head = env.head();
+    let notes = env.git.repo().refname_to_id("refs/notes/spr").unwrap();
+
+    let error = env.spr_land(&["--cherry-pick"]).unwrap_err();
+    assert!(error.to_string().contains("spr continue"));
+
+    env.spr_abort().unwrap();
+    assert_eq!(env.head(), head);
+    let repo = env.git.repo();
+    assert_eq!(repo.head().unwrap().name(), Some("refs/heads/master"));
+    assert_eq!(repo.refname_to_id("refs/notes/spr").unwrap(), notes);
+    assert!(env.git.check_no_uncommitted_changes().is_ok());
+    assert!(env.spr_abort().is_err());
+}
+
+#[test]
+fn test_autostash() {
+    let mut env = TestEnv::new();
+
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.write_files(&[("README", "Debugging\n")]);
+
+    // Without stashing, uncommitted changes are in the way
+    assert!(env.spr_diff(&[]).is_err());
+
+    env.spr_diff(&["--autostash"]).unwrap();
+    assert!(env.message(env.head()).contains("pull/1"));
+    assert_eq!(env.read_file("README"), "Debugging\n");
+    assert_eq!(env.stash_count(), 0);
+
+    // The changes come back when spr diff fails, too, and its error is kept
+    let error = env
+        .spr_diff(&["--autostash", "--all", "--refs", "HEAD"])
+        .unwrap_err();
+    assert!(error.to_string().contains("Do not use --refs with --all"));
+    assert_eq!(env.read_file("README"), "Debugging\n");
+    assert_eq!(env.stash_count(), 0);
+
+    // Landing rebases onto master, which changed README meanwhile, so the
+    // changes stay in the stash
+    env.advance_remote_master("README", "Hello world\n");
+    env.config.auto_stash = true;
+    env.spr_land(&[]).unwrap();
+    assert_eq!(env.head(), env.remote_branch("master").unwrap());
+    assert_eq!(env.read_file("README"), "Hello world\n");
+    assert_eq!(env.stash_count(), 1);
+}
+
+#[test]
+fn test_land_refs() {
+    let mut env = TestEnv::new();
+
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none\n");
+    env.commit(&[("c", "c\n")], "Add c\n\nTest Plan: none\n");
+    env.spr_diff(&["--all"]).unwrap();
+    let a = env.parents(env.parents(env.head())[0])[0];
+
+    assert!(env.spr_land(&["--refs", "master~5"]).is_err());
+
+    // Land the bottom commit of the stack, without checking it out
+    env.spr_land(&["--refs", "HEAD~2"]).unwrap();
+    let master = env.remote_branch("master").unwrap();
+    assert_eq!(env.tree(master), env.tree(a));
+    assert_eq!(env.mock.pull_request(1).state, MockPullRequestState::Merged);
+
+    // The rest of the stack is rebased onto it
+    let head = env.head();
+    assert!(env.message(head).starts_with("Add c"));
+    let b = env.parents(head)[0];
+    assert!(env.message(b).starts_with("Add b"));
+    assert_eq!(env.parents(b), vec![master]);
+
+    // Landing a commit from the middle of the stack needs --cherry-pick
+    env.spr_diff(&["--all", "-m", "rebase"]).unwrap();
+    assert!(env.spr_land(&["--refs", "HEAD"]).is_err());
+}
+
+#[test]
+fn test_land_update_stack() {
+    let mut env = TestEnv::new();
+
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none\n");
+    env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none\n");
+    env.commit(&[("c", "c\n")], "Add c\n\nTest Plan: none\n");
+    env.spr_diff(&["--all"]).unwrap();
+    assert_eq!(env.mock.pull_request(2).base, "spr/test/master.add-b");
+    assert_eq!(env.mock.pull_request(3).base, "spr/test/master.add-c");
+
+    env.spr_land(&["--refs", "HEAD~2", "--update-stack"])
+        .unwrap();
+    let master = env.remote_branch("master").unwrap();
+
+    // The Pull Request of the commit now directly on master targets master,
+    // and its base branch is gone
+    let head = env.head();
+    let b = env.parents(head)[0];
+    let pr = env.mock.pull_request(2);
+    assert_eq!(pr.base, "master");
+    assert_eq!(pr.state, MockPullRequestState::Open);
+    assert_eq!(env.remote_branch("spr/test/master.add-b"), None);
+    let pr_head = env.remote_branch("spr/test/add-b").unwrap();
+    assert_eq!(env.tree(pr_head), env.tree(b));
+    assert!(env.parents(pr_head).contains(&master));
+    assert!(env.message(pr_head).starts_with("rebase after landing #1"));
+
+    // The one above still needs its base branch, which was updated
+    assert_eq!(env.mock.pull_request(3).base, "spr/test/master.add-c");
+    let base = env.remote_branch("spr/test/master.add-c").unwrap();
+    assert_eq!(env.tree(base), env.tree(b));
+}
+
+#[test]
+fn test_inbox() {
+    let env = TestEnv::new();
+    let master0 = env.head();
+
+    // Three Pull Requests by somebody else, two of which await our review
+    let a1 = env.commit(&[("a", "a1\n")], "Add a");
+    env.push_branch(a1, "feature-a");
+    let pr_a = env.mock.open_pull_request("Add a", "master", "feature-a");
+    env.reset_hard(master0);
+    let b = env.commit(&[("b", "b\n")], "Add b");
+    env.push_branch(b, "feature-b");
+    let pr_b = env.mock.open_pull_request("Add b", "master", "feature-b");
+    env.mock.open_pull_request("Add c", "master", "feature-b");
+
+    // We reviewed a, which was updated since, and b, which was not
+    env.reset_hard(a1);
+    let a2 = env.commit(&[("a", "a2\n")], "Update a");
+    env.push_branch(a2, "feature-a");
+    for (number, reviewed) in [(pr_a, a1), (pr_b, b)] {
+        env.mock.update_pull_request(number, |pr| {
+            pr.requested_reviewers.push(VIEWER.to_string());
+            pr.reviews.push(MockReview {
+                login: VIEWER.to_string(),
+                commit: reviewed,
+                event: "COMMENT".to_string(),
+                body: "Looks good so far".to_string(),
+            });
+        });
+    }
+
+    let requests = env.run(env.gh.get_review_requests()).unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].number, pr_a);
+    assert_eq!(requests[0].author, "octocat");
+    assert_eq!(requests[0].head_oid, a2);
+    assert_eq!(requests[0].reviewed_oid, Some(a1));
+    assert_eq!(
+        requests[0].requested_at,
+        "2024-03-02T12:00:00Z".parse().ok()
+    );
+    assert_eq!(requests[1].number, pr_b);
+    assert_eq!(requests[1].reviewed_oid, Some(b));
+    assert_eq!(requests[1].head_oid, b);
+}
+
+#[test]
+fn test_review() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // Somebody else's Pull Request, which we check out
+    let a1 = env.commit(&[("a", "a1\n")], "Add a");
+    env.push_branch(a1, "feature-a");
+    let pr = env.mock.open_pull_request("Add a", "master", "feature-a");
+    env.reset_hard(master0);
+    env.spr_patch(&[&pr.to_string()]).unwrap();
+
+    // Requesting changes and commenting need a comment
+    assert!(env.spr_review(&[&pr.to_string(), "--comment"]).is_err());
+    assert!(
+        env.spr_review(&[&pr.to_string(), "--approve", "--comment"])
+            .is_err()
+    );
+
+    let body_file = env.dir.path().join("review.md");
+    std::fs::write(&body_file, "Please rename a\n").unwrap();
+    env.spr_review(&[
+        &pr.to_string(),
+        "--request-changes",
+        "--body-file",
+        body_file.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    // The author pushes an update: we may comment on it, but not approve it
+    // before checking it out again
+    env.reset_hard(a1);
+    let a2 = env.commit(&[("a", "a2\n")], "Rename a");
+    env.push_branch(a2, "feature-a");
+    env.reset_hard(master0);
+    assert!(env.spr_review(&[&pr.to_string(), "--approve"]).is_err());
+    env.spr_review(&[&pr.to_string(), "--comment", "-m", "Thanks!"])
+        .unwrap();
+    env.spr_patch(&[&pr.to_string()]).unwrap();
+    env.spr_review(&[&pr.to_string(), "--approve"]).unwrap();
+
+    let reviews: Vec<_> = env
+        .mock
+        .pull_request(pr)
+        .reviews
+        .into_iter()
+        .map(|review| (review.event, review.commit, review.body))
+        .collect();
+    assert_eq!(
+        reviews,
+        vec![
+            (
+                "REQUEST_CHANGES".to_string(),
+                a1,
+                "Please rename a".to_string()
+            ),
+            ("COMMENT".to_string(), a2, "Thanks!".to_string()),
+            ("APPROVE".to_string(), a2, String::new()),
+        ]
+    );
+}
+
+#[test]
+fn test_patch_update() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // Three revisions of somebody else's Pull Request, of which the first is
+    // on GitHub for now
+    let a1 = env.commit(&[("a", "a1\n")], "Add a");
+    let a2 = env.commit(&[("a", "a2\n")], "Change a");
+    let a3 = env.commit(&[("a", "a3\n")], "Change a again");
+    env.reset_hard(master0);
+    env.push_branch(a1, "feature-a");
+    let pr = env.mock.open_pull_request("Add a", "master", "feature-a");
+
+    env.spr_patch(&[&pr.to_string()]).unwrap();
+    let notes = env.commit(&[("notes", "Check a\n")], "Add notes");
+
+    // The author pushes a new revision: the branch gets rebuilt, with our
+    // commit on top
+    env.push_branch(a2, "feature-a");
+    env.spr_patch(&[&pr.to_string(), "--update"]).unwrap();
+    let head = env.head();
+    assert_ne!(head, notes);
+    assert_eq!(env.message(head), "Add notes");
+    assert_eq!(env.read_file("a"), "a2\n");
+    assert_eq!(env.read_file("notes"), "Check a\n");
+    let patch_commit = env.parents(head)[0];
+    assert_eq!(env.tree(patch_commit), env.tree(a2));
+    assert_eq!(env.parents(patch_commit), vec![master0]);
+    assert_eq!(
+        env.git.repo().head().unwrap().name(),
+        Some("refs/heads/PR-1")
+    );
+    assert!(
+        env.git
+            .repo()
+            .find_branch("PR-1-1", git2::BranchType::Local)
+            .is_err()
+    );
+
+    // Nothing to do without a new revision
+    env.spr_patch(&[&pr.to_string(), "--update"]).unwrap();
+    assert_eq!(env.head(), head);
+
+    // A local commit that conflicts with the next revision stops the update
+    let mine = env.commit(&[("a", "mine\n")], "Change a my way");
+    env.push_branch(a3, "feature-a");
+    assert!(env.spr_patch(&[&pr.to_string(), "--update"]).is_err());
+    assert_eq!(env.head(), mine);
+}
+
+#[test]
+fn test_patch_fork() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // A Pull Request from a fork, whose head is only in the fork...
+    let fork_path = env.dir.path().join("fork.git");
+    git2::Repository::init_bare(&fork_path).unwrap();
+    let fork_url = fork_path.to_str().unwrap().to_string();
+    let f1 = env.commit(&[("f", "f1\n")], "Fix f");
+    env.git
+        .repo()
+        .remote_anonymous(&fork_url)
+        .unwrap()
+        .push(&[format!("{}:refs/heads/fix", f1)], None)
+        .unwrap();
+    let pr1 = env.mock.open_pull_request("Fix f", "master", "fix");
+    env.mock.update_pull_request(pr1, |pr| {
+        pr.head_repository = HeadRepository::Fork(fork_url.clone());
+    });
+
+    // ...and one from a deleted fork, whose head is only in
+    // refs/pull/<number>/head of this repository
+    env.reset_hard(master0);
+    let g1 = env.commit(&[("g", "g1\n")], "Fix g");
+    let pr2 = env.mock.open_pull_request("Fix g", "master", "fix");
+    env.mock.update_pull_request(pr2, |pr| {
+        pr.head_repository = HeadRepository::Deleted;
+    });
+    env.git
+        .repo()
+        .remote_anonymous(env.remote_path.to_str().unwrap())
+        .unwrap()
+        .push(&[format!("{}:refs/pull/{}/head", g1, pr2)], None)
+        .unwrap();
+    env.reset_hard(master0);
+
+    env.spr_patch(&[&pr1.to_string()]).unwrap();
+    assert_eq!(env.tree(env.head()), env.tree(f1));
+    assert_eq!(env.parents(env.head()), vec![master0]);
+
+    // spr does not push to forks
+    let error = env.spr_diff(&[]).unwrap_err();
+    assert!(error.to_string().contains("fork"));
+    assert!(env.remote_branch("fix").is_none());
+
+    env.spr_patch(&[&pr2.to_string()]).unwrap();
+    assert_eq!(env.tree(env.head()), env.tree(g1));
+}
+
+#[test]
+fn test_patch_several() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    // A stack of two Pull Requests created by spr, the upper one with a base
+    // branch...
+    env.commit(&[("a", "a\n")], "Add a\n\nTest Plan: none");
+    env.commit(&[("b", "b\n")], "Add b\n\nTest Plan: none");
+    env.spr_diff(&["--all"]).unwrap();
+
+    // ...an unrelated fix, and a Pull Request that conflicts with it
+    env.reset_hard(master0);
+    let fix = env.commit(&[("README", "Fixed\n")], "Fix README");
+    env.push_branch(fix, "fix");
+    let pr_fix = env.mock.open_pull_request("Fix README", "master", "fix");
+    env.reset_hard(master0);
+    let other = env.commit(&[("README", "Changed\n")], "Change README");
+    env.push_branch(other, "other");
+    let pr_other =
+        env.mock
+            .open_pull_request("Change README", "master", "other");
+    env.reset_hard(master0);
+
+    // The upper Pull Request comes after the lower one, whatever the order
+    // on the command line, and the conflicting one is left out
+    env.spr_patch(&[
+        "2",
+        &pr_fix.to_string(),
+        "1",
+        &pr_other.to_string(),
+        "--branch-name",
+        "integ",
+    ])
+    .unwrap();
+    assert_eq!(
+        env.git.repo().head().unwrap().name(),
+        Some("refs/heads/integ")
+    );
+    assert_eq!(env.read_file("README"), "Fixed\n");
+    assert_eq!(env.read_file("a"), "a\n");
+    assert_eq!(env.read_file("b"), "b\n");
+
+    let b = env.head();
+    let a = env.parents(b)[0];
+    let fixed = env.parents(a)[0];
+    assert_eq!(env.parents(fixed), vec![master0]);
+    for (oid, number) in [(b, 2), (a, 1), (fixed, pr_fix)] {
+        assert!(
+            env.message(oid).contains(&format!(
+                "Pull Request: {}",
+                env.config.pull_request_url(number)
+            )),
+            "{}",
+            env.message(oid)
+        );
+    }
+
+    // Several Pull Requests need a branch name
+    assert!(env.spr_patch(&["1", "2"]).is_err());
+}
+
+#[test]
+fn test_patch_worktree() {
+    let mut env = TestEnv::new();
+    let master0 = env.head();
+
+    let a1 = env.commit(&[("a", "a1\n")], "Add a");
+    let a2 = env.commit(&[("a", "a2\n")], "Change a");
+    env.reset_hard(master0);
+    let own = env.commit(
+        &[("a", "a1\n")],
+        "Add a\n\nPull Request: https://github.com/acme/codez/pull/1",
+    );
+    env.reset_hard(master0);
+    env.push_branch(a1, "feature-a");
+    let pr = env.mock.open_pull_request("Add a", "master", "feature-a");
+
+    // Local changes in the current worktree are no obstacle
+    env.write_files(&[("README", "Work in progress\n")]);
+    env.spr_patch(&[&pr.to_string(), "--worktree"]).unwrap();
+    let worktree = env.dir.path().join("local-PR-1");
+    assert_eq!(std::fs::read_to_string(worktree.join("a")).unwrap(), "a1\n");
+    assert_eq!(env.head(), master0);
+    assert_eq!(env.read_file("README"), "Work in progress\n");
+
+    // A second one at a given path, which has local changes, too
+    let other_worktree = env.dir.path().join("review");
+    env.spr_patch(&[
+        &pr.to_string(),
+        "--branch-name",
+        "review",
+        "--worktree",
+        other_worktree.to_str().unwrap(),
+    ])
+    .unwrap();
+    std::fs::write(other_worktree.join("a"), "Looks odd\n").unwrap();
+
+    // Updating the branch updates the worktree it is checked out in, so
+    // where to check it out cannot be chosen
+    env.push_branch(a2, "feature-a");
+    assert!(
+        env.spr_patch(&[&pr.to_string(), "--update", "--worktree"])
+            .is_err()
+    );
+    assert!(
+        env.spr_patch(&[&pr.to_string(), "--update", "--no-checkout"])
+            .is_err()
+    );
+    env.spr_patch(&[&pr.to_string(), "--update"]).unwrap();
+    assert_eq!(std::fs::read_to_string(worktree.join("a")).unwrap(), "a2\n");
+
+    // A worktree spr did not create, although it only has a commit of the
+    // Pull Request
+    let own_worktree = env.dir.path().join("own");
+    {
+        let repo = env.git.repo();
+        let branch = repo
+            .branch("own", &repo.find_commit(own).unwrap(), false)
+            .unwrap();
+        repo.worktree(
+            "own",
+            &own_worktree,
+            Some(git2::WorktreeAddOptions::new().reference(Some(branch.get()))),
+        )
+        .unwrap();
+    }
+
+    // Ignored files do not keep a worktree
+    let exclude = env.git.repo().path().join("info").join("exclude");
+    std::fs::create_dir_all(exclude.parent().unwrap()).unwrap();
+    std::fs::write(&exclude, "*.log\n").unwrap();
+    std::fs::write(worktree.join("build.log"), "Built\n").unwrap();
+
+    // Nothing to prune while the Pull Request is open, then only the
+    // worktree spr created without local changes
+    env.spr_patch(&["--prune"]).unwrap();
+    assert!(worktree.exists());
+    assert!(env.git.repo().find_reference("refs/spr/patch/1").is_ok());
+    env.mock.update_pull_request(pr, |pr| {
+        pr.state = MockPullRequestState::Closed;
+    });
+    env.spr_patch(&["--prune"]).unwrap();
+    assert!(!worktree.exists());
+    assert!(other_worktree.exists());
+    assert!(own_worktree.exists());
+    assert_eq!(env.read_file("README"), "Work in progress\n");
+    assert!(env.git.repo().find_reference("refs/spr/patch/1").is_err());
+}
+
+#[test]
+fn test_forgejo() {
+    let mut env = TestEnv::new_forgejo();
+    let master0 = env.head();
+
+    let a1 = env.commit(&[("a", "a1\n")], "Add a\n\nTest Plan: none");
+    env.spr_diff(&[]).unwrap();
+    assert!(
+        env.message(env.head())
+            .contains("Pull Request: https://git.acme.org/acme/codez/pulls/1")
+    );
+    let head1 = env.remote_branch("spr/test/add-a").unwrap();
+    assert_eq!(env.tree(head1), env.tree(a1));
+
+    // Approving uses Forgejo's name of the event, and counts as approval
+    env.spr_review(&["1", "--approve"]).unwrap();
+    assert_eq!(env.mock.pull_request(1).reviews[0].event, "APPROVED");
+    let listed = env.run(env.gh.get_open_pull_requests_by_viewer()).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(
+        listed[0].review_status,
+        Some(spr::github::ReviewStatus::Approved)
+    );
+    env.spr_list().unwrap();
+
+    env.spr_land(&[]).unwrap();
+    assert_eq!(env.mock.pull_request(1).state, MockPullRequestState::Merged);
+    let master1 = env.remote_branch("master").unwrap();
+    assert_eq!(env.parents(master1), vec![master0]);
+    assert_eq!(env.tree(master1), env.tree(a1));
+    assert_eq!(env.head(), master1);
+
+    // A Pull Request from a fork is recognised as such
+    let fork_path = env.dir.path().join("fork.git");
+    git2::Repository::init_bare(&fork_path).unwrap();
+    let fork_url = fork_path.to_str().unwrap().to_string();
+    let f1 = env.commit(&[("f", "f1\n")], "Fix f");
+    env.git
+        .repo()
+        .remote_anonymous(&fork_url)
+        .unwrap()
+        .push(&[format!("{}:refs/heads/fix", f1)], None)
+        .unwrap();
+    let pr = env.mock.open_pull_request("Fix f", "master", "fix");
+    env.mock.update_pull_request(pr, |pr| {
+        pr.head_repository = HeadRepository::Fork(fork_url.clone());
+    });
+    env.reset_hard(master1);
+
+    env.spr_patch(&[&pr.to_string()]).unwrap();
+    assert_eq!(env.tree(env.head()), env.tree(f1));
+    let error = env.spr_diff(&[]).unwrap_err();
+    assert!(error.to_string().contains("fork"));
+
+    // Review requests for one of our teams count, but not those for a team
+    // of the same name in another organisation
+    env.push_branch(f1, "feature-f");
+    let pr_ours = env.mock.open_pull_request("Fix f", "master", "feature-f");
+    let pr_theirs = env.mock.open_pull_request("Fix f", "master", "feature-f");
+    env.mock.update_pull_request(pr_ours, |pr| {
+        pr.requested_teams.push((1, "reviewers".to_string()));
+    });
+    env.mock.update_pull_request(pr_theirs, |pr| {
+        pr.requested_teams.push((2, "reviewers".to_string()));
+    });
+    let requests = env.run(env.gh.get_review_requests()).unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].number, pr_ours);
+    assert_eq!(requests[0].head_oid, f1);
+    assert_eq!(
+        requests[0].requested_at,
+        "2024-03-01T12:00:00Z".parse().ok()
+    );
+}
@@ -0,0 +1,830 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A minimal HTTP server that emulates the parts of GitHub's REST and GraphQL
+//! APIs that spr uses, or those of Forgejo's REST API. Pull Requests are kept
+//! in memory, while their branches live in a local bare repository, which spr
+//! pushes to and fetches from.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{Value, json};
+use spr::github::HeadRepository;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockPullRequestState {
+    Open,
+    Closed,
+    Merged,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockPullRequest {
+    pub number: u64,
+    pub title: String,
+    pub body: String,
+    pub base: String,
+    pub head: String,
+    pub state: MockPullRequestState,
+    pub merge_commit: Option<git2::Oid>,
+    pub requested_reviewers: Vec<String>,
+    /// The id and name of the teams whose review was requested
+    pub requested_teams: Vec<(u64, String)>,
+    pub reviews: Vec<MockReview>,
+    pub author: String,
+    pub head_repository: HeadRepository,
+}
+
+/// A review submitted on a Pull Request
+#[derive(Debug, Clone)]
+pub struct MockReview {
+    pub login: String,
+    /// The head commit of the Pull Request that was reviewed
+    pub commit: git2::Oid,
+    /// `APPROVE` (`APPROVED` on Forgejo), `REQUEST_CHANGES` or `COMMENT`
+    pub event: String,
+    pub body: String,
+}
+
+/// The login of the user spr authenticates as
+pub const VIEWER: &str = "spr-tester";
+
+#[derive(Debug, Default)]
+pub struct MockState {
+    pub pull_requests: BTreeMap<u64, MockPullRequest>,
+    /// Whether to emulate Forgejo's API instead of GitHub's
+    pub forgejo: bool,
+    /// The method and path of every request received, in order
+    pub requests: Vec<(String, String)>,
+}
+
+pub struct MockGitHub {
+    pub url: String,
+    pub state: Arc<Mutex<MockState>>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Value,
+}
+
+impl Request {
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| &value[..])
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "message": message }),
+        }
+    }
+}
+
+impl MockGitHub {
+    /// Start the server on a random local port. `remote_path` is the bare
+    /// repository that holds the branches of the emulated GitHub (or
+    /// Forgejo) repository.
+    pub fn start(remote_path: PathBuf, forgejo: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState {
+            forgejo,
+            ..Default::default()
+        }));
+
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                handle_connection(stream, &server_state, &remote_path);
+            }
+        });
+
+        Self { url, state }
+    }
+
+    pub fn pull_request(&self, number: u64) -> MockPullRequest {
+        self.state.lock().unwrap().pull_requests[&number].clone()
+    }
+
+    /// Open a Pull Request, as if somebody had done so on GitHub
+    pub fn open_pull_request(
+        &self,
+        title: &str,
+        base: &str,
+        head: &str,
+    ) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let number = state.pull_requests.len() as u64 + 1;
+        state.pull_requests.insert(
+            number,
+            MockPullRequest {
+                number,
+                title: title.into(),
+                body: String::new(),
+                base: base.into(),
+                head: head.into(),
+                state: MockPullRequestState::Open,
+                merge_commit: None,
+                requested_reviewers: Vec::new(),
+                requested_teams: Vec::new(),
+                reviews: Vec::new(),
+                author: "octocat".into(),
+                head_repository: HeadRepository::Same,
+            },
+        );
+        number
+    }
+
+    /// How many requests with the given method and path were received
+    pub fn request_count(&self, method: &str, path: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|(m, p)| m == method && p == path)
+            .count()
+    }
+
+    /// Change a Pull Request, as if somebody had edited it on GitHub
+    pub fn update_pull_request(
+        &self,
+        number: u64,
+        f: impl FnOnce(&mut MockPullRequest),
+    ) {
+        f(self
+            .state
+            .lock()
+            .unwrap()
+            .pull_requests
+            .get_mut(&number)
+            .unwrap());
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    state: &Mutex<MockState>,
+    remote_path: &PathBuf,
+) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let Some(request) = read_request(&mut reader) else {
+        return;
+    };
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        state
+            .requests
+            .push((request.method.clone(), request.path.clone()));
+        let remote = git2::Repository::open_bare(remote_path).unwrap();
+        route(&request, &mut state, &remote)
+    };
+
+    let body = response.body.to_string();
+    let mut stream = stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        body.len(),
+        body
+    );
+    let _ = stream.flush();
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let path = path.to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().ok()?;
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).ok()?
+    };
+
+    Some(Request {
+        method,
+        path,
+        query,
+        body,
+    })
+}
+
+fn route(
+    request: &Request,
+    state: &mut MockState,
+    remote: &git2::Repository,
+) -> Response {
+    let segments: Vec<&str> =
+        request.path.trim_matches('/').split('/').collect();
+
+    if state.forgejo
+        && let Some(response) = forgejo_route(request, &segments, state, remote)
+    {
+        return response;
+    }
+
+    match (&request.method[..], &segments[..]) {
+        ("POST", ["graphql"]) => graphql(request, state, remote),
+        ("GET", ["repos", _, _, "pulls"]) => list_pull_requests(state, remote),
+        ("POST", ["repos", _, _, "pulls"]) => {
+            create_pull_request(request, state)
+        }
+        ("PATCH", ["repos", _, _, "pulls", number]) => {
+            update_pull_request(request, state, number)
+        }
+        ("POST", ["repos", _, _, "pulls", number, "requested_reviewers"]) => {
+            request_reviewers(request, state, number)
+        }
+        ("POST", ["repos", _, _, "pulls", number, "reviews"]) => {
+            submit_review(request, state, number)
+        }
+        ("PUT", ["repos", _, _, "pulls", number, "merge"]) => {
+            merge_pull_request(request, state, remote, number)
+        }
+        ("GET", ["user"]) => Response::ok(json!({ "login": VIEWER })),
+        ("GET", ["users", login]) => {
+            Response::ok(json!({ "login": login, "name": null }))
+        }
+        ("GET", ["orgs", _, "teams", slug]) => {
+            Response::ok(json!({ "slug": slug }))
+        }
+        _ => Response::error(404, "Not Found"),
+    }
+}
+
+/// The routes of Forgejo's API that differ from GitHub's, mostly where spr
+/// uses GraphQL on GitHub (Forgejo has no GraphQL API at all). Returns `None`
+/// for the routes that are the same on both.
+fn forgejo_route(
+    request: &Request,
+    segments: &[&str],
+    state: &mut MockState,
+    remote: &git2::Repository,
+) -> Option<Response> {
+    let response = match (&request.method[..], segments) {
+        ("GET", ["repos", _, _, "pulls"]) => {
+            // Forgejo pages are numbered from 1, and we put everything on
+            // the first one
+            if request.query_param("page").is_some_and(|page| page != "1") {
+                return Some(Response::ok(json!([])));
+            }
+            Response::ok(
+                state
+                    .pull_requests
+                    .values()
+                    .filter(|pr| pr.state == MockPullRequestState::Open)
+                    .map(|pr| forgejo_pull_request(pr, remote))
+                    .collect(),
+            )
+        }
+        ("GET", ["repos", _, _, "pulls", number]) => {
+            match find_pull_request(state, number) {
+                Some(pr) => Response::ok(forgejo_pull_request(pr, remote)),
+                None => Response::error(404, "Not Found"),
+            }
+        }
+        ("GET", ["repos", _, _, "pulls", number, "reviews"]) => {
+            let Some(pr) = find_pull_request(state, number) else {
+                return Some(Response::error(404, "Not Found"));
+            };
+            Response::ok(
+                pr.reviews
+                    .iter()
+                    .map(|review| {
+                        json!({
+                            "user": { "login": review.login },
+                            "state": review.event,
+                            "dismissed": false,
+                            "commit_id": review.commit.to_string(),
+                            "submitted_at": "2024-03-02T12:00:00Z",
+                        })
+                    })
+                    .collect(),
+            )
+        }
+        ("POST", ["repos", _, _, "pulls", number, "reviews"]) => {
+            // Forgejo calls the approving event differently
+            if request.body["event"].as_str() == Some("APPROVE") {
+                return Some(Response::error(422, "Unknown review event"));
+            }
+            submit_review(request, state, number)
+        }
+        ("POST", ["repos", _, _, "pulls", number, "merge"]) => {
+            forgejo_merge_pull_request(request, state, remote, number)
+        }
+        ("GET", ["user"]) => Response::ok(json!({ "login": VIEWER })),
+        ("GET", ["user", "teams"]) => {
+            Response::ok(json!([{ "id": 1, "name": "reviewers" }]))
+        }
+        ("GET", ["users", login]) => {
+            Response::ok(json!({ "login": login, "full_name": "" }))
+        }
+        ("GET", ["orgs", _, "teams", "search"]) => Response::ok(json!({
+            "data": [{
+                "id": 1,
+                "name": request.query_param("q").unwrap_or_default(),
+            }],
+        })),
+        ("POST", ["graphql"])
+        | ("PUT", ["repos", _, _, "pulls", _, "merge"])
+        | ("GET", ["orgs", _, "teams", _]) => Response::error(404, "Not Found"),
+        _ => return None,
+    };
+
+    Some(response)
+}
+
+/// A Pull Request the way Forgejo's REST API describes it
+fn forgejo_pull_request(
+    pr: &MockPullRequest,
+    remote: &git2::Repository,
+) -> Value {
+    let repo = json!({
+        "full_name": "acme/codez",
+        "clone_url": remote.path().to_str().unwrap(),
+    });
+    let branch = |name: &str, repo: Option<Value>| {
+        json!({
+            "ref": name,
+            "sha": branch_oid(remote, name)
+                .map(|oid| oid.to_string())
+                .unwrap_or_default(),
+            "repo": repo,
+        })
+    };
+    let head_repo = match &pr.head_repository {
+        HeadRepository::Same => Some(repo.clone()),
+        HeadRepository::Fork(url) => Some(json!({
+            "full_name": "contributor/codez",
+            "clone_url": url,
+        })),
+        HeadRepository::Deleted => None,
+    };
+
+    json!({
+        "number": pr.number,
+        "state": if pr.state == MockPullRequestState::Open {
+            "open"
+        } else {
+            "closed"
+        },
+        "title": pr.title,
+        "body": pr.body,
+        "base": branch(&pr.base, Some(repo)),
+        "head": branch(&pr.head, head_repo),
+        "merged": pr.state == MockPullRequestState::Merged,
+        "merge_commit_sha": pr.merge_commit.map(|oid| oid.to_string()),
+        "mergeable": true,
+        "requested_reviewers": pr
+            .requested_reviewers
+            .iter()
+            .map(|login| json!({ "login": login }))
+            .collect::<Vec<_>>(),
+        "requested_reviewers_teams": pr
+            .requested_teams
+            .iter()
+            .map(|(id, name)| json!({ "id": id, "name": name }))
+            .collect::<Vec<_>>(),
+        "user": { "login": pr.author },
+        "html_url": format!(
+            "https://git.acme.org/acme/codez/pulls/{}",
+            pr.number
+        ),
+        "created_at": "2024-03-01T12:00:00Z",
+    })
+}
+
+fn branch_oid(remote: &git2::Repository, branch: &str) -> Option<git2::Oid> {
+    remote
+        .find_reference(&format!("refs/heads/{}", branch))
+        .ok()
+        .and_then(|r| r.target())
+}
+
+fn find_pull_request<'a>(
+    state: &'a mut MockState,
+    number: &str,
+) -> Option<&'a mut MockPullRequest> {
+    state.pull_requests.get_mut(&number.parse().ok()?)
+}
+
+fn graphql(
+    request: &Request,
+    state: &mut MockState,
+    remote: &git2::Repository,
+) -> Response {
+    if request.body["operationName"].as_str() == Some("InboxQuery") {
+        return inbox_query(state, remote);
+    }
+
+    let number = request.body["variables"]["number"].as_u64().unwrap_or(0);
+    let Some(pr) = state.pull_requests.get(&number) else {
+        return Response::ok(json!({
+            "data": null,
+            "errors": [{ "message": "Could not resolve to a PullRequest" }],
+        }));
+    };
+    let merge_commit =
+        pr.merge_commit.map(|oid| json!({ "oid": oid.to_string() }));
+
+    let pull_request = match request.body["operationName"].as_str() {
+        Some("PullRequestQuery") => json!({
+            "number": pr.number,
+            "state": match pr.state {
+                MockPullRequestState::Open => "OPEN",
+                MockPullRequestState::Closed => "CLOSED",
+                MockPullRequestState::Merged => "MERGED",
+            },
+            "reviewDecision": null,
+            "title": pr.title,
+            "body": pr.body,
+            "baseRefName": pr.base,
+            "headRefName": pr.head,
+            "isCrossRepository": pr.head_repository.is_fork(),
+            "headRepository": match &pr.head_repository {
+                HeadRepository::Fork(url) => json!({ "url": url }),
+                _ => Value::Null,
+            },
+            "mergeCommit": merge_commit,
+            "latestOpinionatedReviews": { "nodes": [] },
+            "reviewRequests": {
+                "nodes": pr.requested_reviewers.iter().map(|login| json!({
+                    "requestedReviewer": { "__typename": "User", "login": login },
+                })).collect::<Vec<_>>(),
+            },
+        }),
+        Some("PullRequestMergeabilityQuery") => json!({
+            "baseRefName": pr.base,
+            "headRefOid": branch_oid(remote, &pr.head)
+                .map(|oid| oid.to_string())
+                .unwrap_or_default(),
+            "mergeable": "MERGEABLE",
+            "mergeCommit": merge_commit,
+        }),
+        _ => return Response::error(400, "Unsupported GraphQL operation"),
+    };
+
+    Response::ok(json!({
+        "data": { "repository": { "pullRequest": pull_request } }
+    }))
+}
+
+/// The open Pull Requests whose review was requested from `VIEWER`
+fn inbox_query(state: &MockState, remote: &git2::Repository) -> Response {
+    let nodes: Vec<Value> = state
+        .pull_requests
+        .values()
+        .filter(|pr| {
+            pr.state == MockPullRequestState::Open
+                && pr.requested_reviewers.iter().any(|r| r == VIEWER)
+        })
+        .map(|pr| {
+            let reviews: Vec<Value> = pr
+                .reviews
+                .iter()
+                .map(|review| {
+                    json!({
+                        "author": {
+                            "__typename": "User",
+                            "login": review.login,
+                        },
+                        "commit": { "oid": review.commit.to_string() },
+                    })
+                })
+                .collect();
+            json!({
+                "__typename": "PullRequest",
+                "number": pr.number,
+                "title": pr.title,
+                "url": format!("https://github.com/acme/codez/pull/{}", pr.number),
+                "createdAt": "2024-03-01T12:00:00Z",
+                "headRefOid": branch_oid(remote, &pr.head)
+                    .map(|oid| oid.to_string())
+                    .unwrap_or_default(),
+                "author": { "__typename": "User", "login": pr.author },
+                "latestReviews": { "nodes": reviews },
+                "timelineItems": {
+                    "nodes": [{
+                        "__typename": "ReviewRequestedEvent",
+                        "createdAt": "2024-03-02T12:00:00Z",
+                    }],
+                },
+            })
+        })
+        .collect();
+
+    Response::ok(json!({
+        "data": {
+            "viewer": { "login": VIEWER },
+            "search": { "nodes": nodes },
+        }
+    }))
+}
+
+/// The open Pull Requests, all on one page
+fn list_pull_requests(
+    state: &MockState,
+    remote: &git2::Repository,
+) -> Response {
+    let branch = |name: &str| {
+        json!({
+            "ref": name,
+            "sha": branch_oid(remote, name)
+                .map(|oid| oid.to_string())
+                .unwrap_or_default(),
+        })
+    };
+
+    Response::ok(
+        state
+            .pull_requests
+            .values()
+            .filter(|pr| pr.state == MockPullRequestState::Open)
+            .map(|pr| {
+                json!({
+                    "number": pr.number,
+                    "title": pr.title,
+                    "body": pr.body,
+                    "base": branch(&pr.base),
+                    "head": branch(&pr.head),
+                    "user": { "login": pr.author },
+                })
+            })
+            .collect(),
+    )
+}
+
+fn create_pull_request(request: &Request, state: &mut MockState) -> Response {
+    let number = state.pull_requests.len() as u64 + 1;
+    let field = |name: &str| {
+        request.body[name].as_str().unwrap_or_default().to_string()
+    };
+
+    state.pull_requests.insert(
+        number,
+        MockPullRequest {
+            number,
+            title: field("title"),
+            body: field("body"),
+            base: field("base"),
+            head: field("head"),
+            state: MockPullRequestState::Open,
+            merge_commit: None,
+            requested_reviewers: Vec::new(),
+            requested_teams: Vec::new(),
+            reviews: Vec::new(),
+            author: VIEWER.into(),
+            head_repository: HeadRepository::Same,
+        },
+    );
+
+    Response {
+        status: 201,
+        body: json!({ "number": number }),
+    }
+}
+
+fn update_pull_request(
+    request: &Request,
+    state: &mut MockState,
+    number: &str,
+) -> Response {
+    let Some(pr) = find_pull_request(state, number) else {
+        return Response::error(404, "Not Found");
+    };
+
+    if let Some(title) = request.body["title"].as_str() {
+        pr.title = title.to_string();
+    }
+    if let Some(body) = request.body["body"].as_str() {
+        pr.body = body.to_string();
+    }
+    if let Some(base) = request.body["base"].as_str() {
+        pr.base = base.to_string();
+    }
+    if request.body["state"].as_str() == Some("closed") {
+        pr.state = MockPullRequestState::Closed;
+    }
+
+    Response::ok(json!({ "number": pr.number }))
+}
+
+fn request_reviewers(
+    request: &Request,
+    state: &mut MockState,
+    number: &str,
+) -> Response {
+    let Some(pr) = find_pull_request(state, number) else {
+        return Response::error(404, "Not Found");
+    };
+
+    pr.requested_reviewers.extend(
+        request.body["reviewers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r.as_str().map(String::from)),
+    );
+
+    Response::ok(json!({}))
+}
+
+fn submit_review(
+    request: &Request,
+    state: &mut MockState,
+    number: &str,
+) -> Response {
+    let Some(pr) = find_pull_request(state, number) else {
+        return Response::error(404, "Not Found");
+    };
+    let Some(commit) = request.body["commit_id"]
+        .as_str()
+        .and_then(|oid| git2::Oid::from_str(oid).ok())
+    else {
+        return Response::error(422, "commit_id is not a commit");
+    };
+
+    pr.reviews.push(MockReview {
+        login: VIEWER.to_string(),
+        commit,
+        event: request.body["event"].as_str().unwrap_or_default().into(),
+        body: request.body["body"].as_str().unwrap_or_default().into(),
+    });
+
+    Response::ok(json!({ "id": pr.reviews.len() }))
+}
+
+/// Squash-merge the Pull Request into its base branch in the bare repository,
+/// the way GitHub does it: one new commit on the base branch whose tree is
+/// the result of merging the Pull Request branch into the base branch.
+fn merge_pull_request(
+    request: &Request,
+    state: &mut MockState,
+    remote: &git2::Repository,
+    number: &str,
+) -> Response {
+    let Some(pr) = find_pull_request(state, number) else {
+        return Response::error(404, "Not Found");
+    };
+
+    let message = format!(
+        "{} (#{})\n\n{}",
+        request.body["commit_title"].as_str().unwrap_or(&pr.title),
+        pr.number,
+        request.body["commit_message"].as_str().unwrap_or_default(),
+    );
+    match squash_merge(pr, remote, request.body["sha"].as_str(), &message) {
+        Ok(merge_oid) => Response::ok(json!({
+            "sha": merge_oid.to_string(),
+            "merged": true,
+            "message": "Pull Request successfully merged",
+        })),
+        Err(response) => response,
+    }
+}
+
+/// Squash-merge the Pull Request the way Forgejo does it, which responds
+/// with an empty body
+fn forgejo_merge_pull_request(
+    request: &Request,
+    state: &mut MockState,
+    remote: &git2::Repository,
+    number: &str,
+) -> Response {
+    let Some(pr) = find_pull_request(state, number) else {
+        return Response::error(404, "Not Found");
+    };
+    if request.body["Do"].as_str() != Some("squash") {
+        return Response::error(422, "Unsupported merge style");
+    }
+
+    let message = format!(
+        "{}\n\n{}",
+        request.body["MergeTitleField"]
+            .as_str()
+            .unwrap_or(&pr.title),
+        request.body["MergeMessageField"]
+            .as_str()
+            .unwrap_or_default(),
+    );
+    match squash_merge(
+        pr,
+        remote,
+        request.body["head_commit_id"].as_str(),
+        &message,
+    ) {
+        Ok(_) => Response::ok(Value::Null),
+        Err(response) => response,
+    }
+}
+
+/// Create one new commit on the base branch whose tree is the result of
+/// merging the Pull Request branch into the base branch, provided the head
+/// of the Pull Request is `expected_head`
+fn squash_merge(
+    pr: &mut MockPullRequest,
+    remote: &git2::Repository,
+    expected_head: Option<&str>,
+    message: &str,
+) -> Result<git2::Oid, Response> {
+    if pr.state != MockPullRequestState::Open {
+        return Err(Response::error(405, "Pull Request is not open"));
+    }
+
+    let (Some(base_oid), Some(head_oid)) =
+        (branch_oid(remote, &pr.base), branch_oid(remote, &pr.head))
+    else {
+        return Err(Response::error(422, "Branch not found"));
+    };
+    if expected_head != Some(&head_oid.to_string()[..]) {
+        return Err(Response::error(409, "Head branch was modified"));
+    }
+
+    let base_commit = remote.find_commit(base_oid).unwrap();
+    let head_commit = remote.find_commit(head_oid).unwrap();
+    let mut index = remote
+        .merge_commits(&base_commit, &head_commit, None)
+        .unwrap();
+    if index.has_conflicts() {
+        return Err(Response::error(405, "Pull Request is not mergeable"));
+    }
+    let tree = remote
+        .find_tree(index.write_tree_to(remote).unwrap())
+        .unwrap();
+
+    let signature =
+        git2::Signature::now("GitHub", "noreply@github.com").unwrap();
+    let merge_oid = remote
+        .commit(
+            Some(&format!("refs/heads/{}", pr.base)),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &[&base_commit],
+        )
+        .unwrap();
+
+    pr.state = MockPullRequestState::Merged;
+    pr.merge_commit = Some(merge_oid);
+
+    Ok(merge_oid)
+}
@@ -0,0 +1,397 @@
+/*
+ * Copyright (c) Radical HQ Limited
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Test environment for end-to-end tests: a local working repository, a bare
+//! repository standing in for the repository on GitHub, and a mock server for
+//! the GitHub API. spr commands run against these exactly like they would
+//! against the real thing.
+
+#![allow(dead_code)]
+
+pub mod mock_github;
+
+use std::{
+    future::Future,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use clap::Parser as _;
+use color_eyre::eyre::Result;
+use git2::Oid;
+use spr::{
+    commands,
+    config::{Config, Forge},
+    git::Git,
+    github::GitHub,
+};
+
+pub use mock_github::{MockGitHub, MockPullRequestState, MockReview, VIEWER};
+
+/// octocrab's global instance is bound to the Tokio runtime it was created
+/// in, so end-to-end tests must not run concurrently.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+pub struct TestEnv {
+    pub dir: tempfile::TempDir,
+    pub remote_path: PathBuf,
+    pub git: Git,
+    pub gh: GitHub,
+    pub config: Config,
+    pub mock: MockGitHub,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl TestEnv {
+    /// Create a local repository with one commit on master, and a remote
+    /// (bare) repository with the same master branch.
+    pub fn new() -> Self {
+        Self::with_forge(Forge::GitHub)
+    }
+
+    /// Like `new`, but with the remote repository on a Forgejo instance
+    pub fn new_forgejo() -> Self {
+        Self::with_forge(Forge::Forgejo {
+            url: "https://git.acme.org".into(),
+        })
+    }
+
+    fn with_forge(forge: Forge) -> Self {
+        let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+
+        let dir = tempfile::tempdir().unwrap();
+        let remote_path = dir.path().join("remote.git");
+        let local_path = dir.path().join("local");
+
+        git2::Repository::init_bare(&remote_path).unwrap();
+        let repo = git2::Repository::init(&local_path).unwrap();
+        {
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Spr Tester").unwrap();
+            config.set_str("user.email", "spr@example.com").unwrap();
+        }
+
+        let config = Config::new(
+            "acme".into(),
+            "codez".into(),
+            "master".into(),
+            "spr/test/".into(),
+            "token".into(),
+            false,
+            true,
+            forge,
+        );
+        let git = Git::new(repo);
+        let gh = GitHub::new_with_remote_url(
+            config.clone(),
+            git.clone(),
+            remote_path.to_str().unwrap().to_string(),
+            "token".into(),
+        );
+        let mock = MockGitHub::start(
+            remote_path.clone(),
+            matches!(config.forge, Forge::Forgejo { .. }),
+        );
+
+        let env = Self {
+            dir,
+            remote_path,
+            git,
+            gh,
+            config,
+            mock,
+            _serial: serial,
+        };
+
+        env.commit(&[("README", "Hello\n")], "Initial commit");
+        env.git
+            .repo()
+            .remote_anonymous(env.remote_path.to_str().unwrap())
+            .unwrap()
+            .push(&["refs/heads/master:refs/heads/master"], None)
+            .unwrap();
+
+        env
+    }
+
+    /// Run the given future in a fresh Tokio runtime, with octocrab pointed
+    /// at the mock server.
+    pub fn run<F: Future>(&self, future: F) -> F::Output {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local_set = tokio::task::LocalSet::new();
+
+        local_set.block_on(&runtime, async {
+            octocrab::initialise(
+                octocrab::Octocrab::builder()
+                    .base_uri(&self.mock.url[..])
+                    .unwrap()
+                    .personal_token("token".to_string())
+                    .build()
+                    .unwrap(),
+            );
+            future.await
+        })
+    }
+
+    pub fn spr_diff(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::diff::DiffOptions::try_parse_from(
+            std::iter::once("diff").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::diff::diff(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_land(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::land::LandOptions::try_parse_from(
+            std::iter::once("land").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::land::land(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_amend(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::amend::AmendOptions::try_parse_from(
+            std::iter::once("amend").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::amend::amend(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_sync(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::sync::SyncOptions::try_parse_from(
+            std::iter::once("sync").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::sync::sync(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_adopt(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::adopt::AdoptOptions::try_parse_from(
+            std::iter::once("adopt").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::adopt::adopt(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_patch(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::patch::PatchOptions::try_parse_from(
+            std::iter::once("patch").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::patch::patch(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_review(&mut self, args: &[&str]) -> Result<()> {
+        let opts = commands::review::ReviewOptions::try_parse_from(
+            std::iter::once("review").chain(args.iter().copied()),
+        )?;
+        let (git, config) = (self.git.clone(), self.config.clone());
+        let mut gh = self.gh.clone();
+        self.run(commands::review::review(opts, &git, &mut gh, &config))
+    }
+
+    pub fn spr_list(&mut self) -> Result<()> {
+        let gh = self.gh.clone();
+        self.run(commands::list::list(&gh))
+    }
+
+    pub fn spr_continue(&mut self) -> Result<()> {
+        let git = self.git.clone();
+        self.run(commands::rebase::continue_rebase(&git))
+    }
+
+    pub fn spr_abort(&mut self) -> Result<()> {
+        let git = self.git.clone();
+        self.run(commands::rebase::abort_rebase(&git))
+    }
+
+    /// Write the given files into the worktree
+    pub fn write_files(&self, files: &[(&str, &str)]) {
+        let workdir = self.git.repo().workdir().unwrap().to_path_buf();
+        for (path, content) in files {
+            let path_in_workdir = workdir.join(path);
+            std::fs::create_dir_all(path_in_workdir.parent().unwrap()).unwrap();
+            std::fs::write(path_in_workdir, content).unwrap();
+        }
+    }
+
+    /// Read a file from the worktree
+    pub fn read_file(&self, path: &str) -> String {
+        let workdir = self.git.repo().workdir().unwrap().to_path_buf();
+        std::fs::read_to_string(workdir.join(path)).unwrap()
+    }
+
+    /// Write the given files into the worktree and add them to the index
+    pub fn stage(&self, files: &[(&str, &str)]) {
+        self.write_files(files);
+        let mut index = self.git.repo().index().unwrap();
+        for (path, _) in files {
+            index.add_path(std::path::Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+    }
+
+    /// The number of entries in the stash
+    pub fn stash_count(&self) -> usize {
+        let mut repo = git2::Repository::open(self.git.repo().path()).unwrap();
+        let mut count = 0;
+        repo.stash_foreach(|_, _, _| {
+            count += 1;
+            true
+        })
+        .unwrap();
+        count
+    }
+
+    /// Write the given files into the worktree and commit them on top of
+    /// HEAD.
+    pub fn commit(&self, files: &[(&str, &str)], message: &str) -> Oid {
+        self.stage(files);
+        let repo = self.git.repo();
+        let mut index = repo.index().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let signature = repo.signature().unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    /// Merge the given commit into HEAD, like `git merge` would (without
+    /// conflicts)
+    pub fn merge(&self, oid: Oid, message: &str) -> Oid {
+        let repo = self.git.repo();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let other = repo.find_commit(oid).unwrap();
+        let mut index = repo.merge_commits(&head, &other, None).unwrap();
+        assert!(!index.has_conflicts());
+        let tree = repo.find_tree(index.write_tree_to(repo).unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        let merge = repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &[&head, &other],
+            )
+            .unwrap();
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+        merge
+    }
+
+    /// Move HEAD (and the worktree) to the given commit
+    pub fn reset_hard(&self, oid: Oid) {
+        let repo = self.git.repo();
+        let object = repo.find_object(oid, None).unwrap();
+        repo.reset(&object, git2::ResetType::Hard, None).unwrap();
+    }
+
+    pub fn head(&self) -> Oid {
+        self.git.head().unwrap()
+    }
+
+    pub fn message(&self, oid: Oid) -> String {
+        self.git
+            .repo()
+            .find_commit(oid)
+            .unwrap()
+            .message()
+            .unwrap()
+            .to_string()
+    }
+
+    /// The note spr keeps for the given commit, if any
+    pub fn note(&self, oid: Oid) -> Option<String> {
+        let note = self.git.repo().find_note(Some("refs/notes/spr"), oid);
+        note.ok().and_then(|note| note.message().map(String::from))
+    }
+
+    pub fn tree(&self, oid: Oid) -> Oid {
+        self.git.get_tree_oid_for_commit(oid).unwrap()
+    }
+
+    pub fn parents(&self, oid: Oid) -> Vec<Oid> {
+        self.git
+            .repo()
+            .find_commit(oid)
+            .unwrap()
+            .parent_ids()
+            .collect()
+    }
+
+    /// The commit a branch points to in the remote repository
+    pub fn remote_branch(&self, name: &str) -> Option<Oid> {
+        git2::Repository::open_bare(&self.remote_path)
+            .unwrap()
+            .find_reference(&format!("refs/heads/{}", name))
+            .ok()
+            .and_then(|r| r.target())
+    }
+
+    /// Push a local commit to a branch in the remote repository
+    pub fn push_branch(&self, oid: Oid, name: &str) {
+        self.git
+            .repo()
+            .remote_anonymous(self.remote_path.to_str().unwrap())
+            .unwrap()
+            .push(&[format!("{}:refs/heads/{}", oid, name)], None)
+            .unwrap();
+    }
+
+    /// Fetch the remote's master branch into the local object database
+    pub fn fetch_master(&self) -> Oid {
+        self.gh.remote().fetch_branch("master").unwrap()
+    }
+
+    /// Add a commit to master in the remote repository, as if somebody else
+    /// had landed a change.
+    pub fn advance_remote_master(&self, file: &str, content: &str) -> Oid {
+        let remote = git2::Repository::open_bare(&self.remote_path).unwrap();
+        let master = remote
+            .find_reference("refs/heads/master")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        let blob = remote.blob(content.as_bytes()).unwrap();
+        let mut builder =
+            remote.treebuilder(Some(&master.tree().unwrap())).unwrap();
+        builder.insert(file, blob, 0o100644).unwrap();
+        let tree = remote.find_tree(builder.write().unwrap()).unwrap();
+        let signature =
+            git2::Signature::now("Other", "other@example.com").unwrap();
+
+        remote
+            .commit(
+                Some("refs/heads/master"),
+                &signature,
+                &signature,
+                &format!("Change {}", file),
+                &tree,
+                &[&master],
+            )
+            .unwrap()
+    }
+}